@@ -0,0 +1,129 @@
+#[macro_use]
+extern crate criterion;
+extern crate glock;
+
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::thread::{ self, JoinHandle };
+
+use criterion::Criterion;
+use glock::*;
+
+const BACKGROUND_THREADS: usize = 4;
+
+fn spawn_background<F: Fn() + Send + Sync + 'static>(f: F) -> (Arc<AtomicBool>, Vec<JoinHandle<()>>) {
+    let stop = Arc::new(AtomicBool::new(false));
+    let f = Arc::new(f);
+
+    let threads = (0..BACKGROUND_THREADS).map(|_| {
+        let stop = stop.clone();
+        let f = f.clone();
+        thread::spawn(move || { while !stop.load(Ordering::Relaxed) { f(); } })
+    }).collect();
+
+    (stop, threads)
+}
+
+fn stop_background((stop, threads): (Arc<AtomicBool>, Vec<JoinHandle<()>>)) {
+    stop.store(true, Ordering::Relaxed);
+    for t in threads { t.join().unwrap(); }
+}
+
+fn uncontended(c: &mut Criterion) {
+    let lock = GLock::new_root(0u64).unwrap();
+
+    c.bench_function("uncontended_shared", |b| b.iter(|| {
+        drop(lock.lock_shared().unwrap());
+    }));
+
+    c.bench_function("uncontended_exclusive", |b| b.iter(|| {
+        *lock.lock_exclusive().unwrap() += 1;
+    }));
+
+    c.bench_function("uncontended_upgrade", |b| b.iter(|| {
+        let g = lock.lock_shared().unwrap();
+        *g.upgrade_to_exclusive().unwrap() += 1;
+    }));
+}
+
+fn reader_contention(c: &mut Criterion) {
+    let lock = Arc::new(GLock::new_root(0u64).unwrap());
+
+    let background = {
+        let lock = lock.clone();
+        spawn_background(move || { drop(lock.lock_shared().unwrap()); })
+    };
+
+    c.bench_function("reader_contention_shared", |b| b.iter(|| {
+        drop(lock.lock_shared().unwrap());
+    }));
+
+    stop_background(background);
+}
+
+fn reader_writer_contention(c: &mut Criterion) {
+    let lock = Arc::new(GLock::new_root(0u64).unwrap());
+
+    let background = {
+        let lock = lock.clone();
+        spawn_background(move || { drop(lock.lock_shared().unwrap()); })
+    };
+
+    c.bench_function("reader_writer_contention_exclusive", |b| b.iter(|| {
+        *lock.lock_exclusive().unwrap() += 1;
+    }));
+
+    stop_background(background);
+}
+
+fn mixed_contention(c: &mut Criterion) {
+    let lock = Arc::new(GLock::new_root(0u64).unwrap());
+
+    let readers = {
+        let lock = lock.clone();
+        spawn_background(move || { drop(lock.lock_shared().unwrap()); })
+    };
+
+    let intention_writers = {
+        let lock = lock.clone();
+        spawn_background(move || { drop(lock.lock(LockType::IntentionExclusive).unwrap()); })
+    };
+
+    c.bench_function("mixed_contention_intention_shared", |b| b.iter(|| {
+        drop(lock.lock(LockType::IntentionShared).unwrap());
+    }));
+
+    c.bench_function("mixed_contention_exclusive", |b| b.iter(|| {
+        *lock.lock_exclusive().unwrap() += 1;
+    }));
+
+    stop_background(intention_writers);
+    stop_background(readers);
+}
+
+fn deep_tree(c: &mut Criterion) {
+    let root = GLock::new_root(0u64).unwrap();
+    let mut locks = vec![root.new_child(0u64).unwrap()];
+
+    for _ in 0..7 {
+        let child = locks.last().unwrap().new_child(0u64).unwrap();
+        locks.push(child);
+    }
+
+    let leaf = locks.last().unwrap();
+
+    c.bench_function("deep_tree_implicit_parents", |b| b.iter(|| {
+        *leaf.lock_exclusive().unwrap() += 1;
+    }));
+
+    c.bench_function("deep_tree_explicit_parent", |b| {
+        let parent = locks[locks.len() - 2].lock(LockType::IntentionExclusive).unwrap();
+
+        b.iter(|| {
+            *leaf.lock_exclusive_using_parent(&parent).unwrap() += 1;
+        })
+    });
+}
+
+criterion_group!(benches, uncontended, reader_contention, reader_writer_contention, mixed_contention, deep_tree);
+criterion_main!(benches);
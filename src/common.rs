@@ -1,12 +1,14 @@
 use std::fmt::{ Display, Formatter, Error as FmtError };
 use std::error::Error;
+use std::time::Duration;
 
 use self::super::locktype::LockType;
 
 /// Error enum for `glock` crate.
 #[derive(Debug, PartialEq, Eq)]
 pub enum LockError {
-    /// This error indicates an unhandled error, usually an internal `Mutex` poison error.
+    /// This error indicates an unhandled error, such as a violated invariant reported by
+    /// `GLock::verify_invariants()`.
     UnknownError {
         /// The error message from the original `Error`.
         message: String
@@ -15,6 +17,38 @@ pub enum LockError {
     /// This error is returned when calling any of the `try_lock` variants, if the target lock is busy.
     LockBusy,
 
+    /// This error is returned when a lock could not be acquired or upgraded before its timeout
+    /// expired, either the timeout passed to one of the `timeout` variants, or the default timeout
+    /// of the `GLock` (see `GLockBuilder::default_timeout()`).
+    Timeout,
+
+    /// This error is returned when upgrading a lock would block forever, because the only locks
+    /// preventing the upgrade are held by the current thread itself (e.g. upgrading one of two
+    /// `Shared` locks held by the same thread to `Exclusive`).
+    WouldSelfDeadlock,
+
+    /// This error is returned when upgrading a lock would block forever, because another thread is
+    /// already waiting to upgrade a lock on the same `GLock`, and each upgrade waits for the lock
+    /// the other one is upgrading from to be released (e.g. two threads upgrading their `Shared`
+    /// locks to `Exclusive`). The lock keeps its original type, and releasing it lets the other
+    /// upgrade proceed.
+    ConflictingUpgrade,
+
+    /// This error is returned when acquiring a lock on a `GLock` that has already been dropped (or
+    /// consumed using `into_inner()`), but whose lock kernel is still alive, e.g. when locking a
+    /// child `GLock` after its parent `GLock` was dropped, which would implicitly lock the parent.
+    /// It is also returned when acquiring a lock on a `GLock` that was closed (see `GLock::close()`),
+    /// and when upgrading a lock that was released by `GLock::force_reset()`.
+    LockReleased,
+
+    /// This error is returned by `GLock::assume_locked()` in debug builds, when the current thread
+    /// does not hold a lock of at least the specified type on the `GLock`.
+    LockNotHeld,
+
+    /// This error is returned when locking a child `GLock` that requires an explicit parent lock
+    /// (see `GLockBuilder::require_explicit_parent()`) without passing a parent `GLockGuard`.
+    ParentNotHeld,
+
     /// This error is returned when calling any of the `lock_using_parent` variants and passing an
     /// incorrect parent `GLockGuard` (i.e. a `GLockGuard` that does not belong to the parent `GLock`)
     InvalidParentLock,
@@ -38,6 +72,81 @@ pub enum LockError {
         /// The target lock type of the upgrade.
         requested: LockType
     },
+
+    /// This error occurs when trying to upgrade a `GLockGuard` to a type that is less restrictive
+    /// than its current type (e.g. upgrading an `Exclusive` lock to `Shared`). Such locks can be
+    /// downgraded using `GLockGuard::downgrade()` instead.
+    UpgradeToLowerType {
+        /// The original lock type.
+        original: LockType,
+
+        /// The target lock type of the upgrade.
+        requested: LockType
+    },
+
+    /// This error occurs when trying to downgrade a `GLockGuard` to a type to which it is not
+    /// downgradable, or to a type that does not support the child locks still held through it.
+    InvalidDowngrade {
+        /// The original lock type.
+        original: LockType,
+
+        /// The target lock type of the downgrade.
+        requested: LockType
+    },
+
+    /// This error is returned when the lock types passed to a function do not make sense together,
+    /// e.g. when the fallback type passed to `GLock::lock_preferred()` is not less restrictive than
+    /// the preferred type.
+    InvalidLockType,
+
+    /// This error is returned when acquiring or upgrading a lock on a poisoned `GLock`. A `GLock`
+    /// is poisoned when one of its `GLockGuard`s is dropped while panicking, if it was built using
+    /// `PoisonPolicy::FailStop`.
+    Poisoned,
+
+    /// This error is returned when the admission hook of a `GLock` (see `GLockBuilder::admit()`)
+    /// rejects the acquisition of a lock on it.
+    Rejected,
+
+    /// This error is returned when the same `GLock` is passed more than once to a function that
+    /// locks several `GLock`s at once, such as `lock_all_exclusive()`.
+    DuplicateLock,
+
+    /// This error is returned when converting a `LockToken` back to a `GLockGuard` using a
+    /// `GLock` other than the one the `LockToken` was created from.
+    InvalidToken,
+
+    /// This error is returned when a child `GLock` built with an intended lock type (see
+    /// `GLockBuilder::intended_lock_type()`) is first locked using a parent `GLockGuard` whose
+    /// type does not support child locks of the intended type.
+    UnsupportedChildLockType {
+        /// The type of the parent lock.
+        parent: LockType,
+
+        /// The intended type of the child lock.
+        child: LockType
+    },
+
+    /// This error is returned when creating a child `GLock` using `new_child_with_id()` with an id
+    /// that is already used by another live child of the same parent `GLock`.
+    DuplicateId,
+
+    /// This error is returned when creating a child `GLock` with an automatically assigned id,
+    /// after all ids have been handed out by its parent `GLock`. Automatically assigned ids are never
+    /// reused, but `new_child_with_id()` can still be used.
+    IdExhausted,
+
+    /// This error is returned by `GLockGuard::unlocked()` when child locks or `ParentGuard`s still
+    /// use the `GLockGuard` as their parent lock, so its lock cannot be released.
+    ChildLocksHeld,
+
+    /// This error is returned by `GLock::reparent()` and `GLock::adopt_child()` when the new parent
+    /// `GLock` is the child being moved or one of its descendants, which would create a cycle.
+    InvalidReparent,
+
+    /// This error is returned by `GLock::adopt_child()` when the child `GLock` already has a
+    /// parent `GLock`.
+    HasParent,
 }
 
 impl Display for LockError {
@@ -45,9 +154,28 @@ impl Display for LockError {
         match self {
             LockError::UnknownError { message }                     => write!(f, "Unknown error: {}", message),
             LockError::LockBusy                                     => write!(f, "Failed to acquire/upgrade lock; lock is busy"),
+            LockError::Timeout                                      => write!(f, "Failed to acquire/upgrade lock; timed out"),
+            LockError::WouldSelfDeadlock                            => write!(f, "Failed to upgrade lock; it would deadlock on locks held by the current thread"),
+            LockError::ConflictingUpgrade                           => write!(f, "Failed to upgrade lock; it would deadlock with another pending upgrade"),
+            LockError::LockReleased                                 => write!(f, "Lock has already been dropped"),
+            LockError::LockNotHeld                                  => write!(f, "Lock is not held by the current thread"),
+            LockError::ParentNotHeld                                => write!(f, "Parent lock must be acquired explicitly"),
             LockError::InvalidParentLock                            => write!(f, "Invalid parent lock"),
             LockError::InvalidParentLockType { required, actual }   => write!(f, "Invalid parent lock type; required: {}, actual: {}", required, actual),
             LockError::InvalidUpgrade { original, requested }       => write!(f, "Lock of type {} is not upgradable to type {}", original, requested),
+            LockError::UpgradeToLowerType { original, requested }   => write!(f, "Lock of type {} cannot be upgraded to less restrictive type {}; use downgrade() instead", original, requested),
+            LockError::InvalidDowngrade { original, requested }     => write!(f, "Lock of type {} is not downgradable to type {}", original, requested),
+            LockError::InvalidLockType                              => write!(f, "Invalid combination of lock types"),
+            LockError::Poisoned                                     => write!(f, "Lock is poisoned"),
+            LockError::Rejected                                     => write!(f, "Lock acquisition was rejected by the admission hook"),
+            LockError::DuplicateLock                                => write!(f, "The same lock was specified more than once"),
+            LockError::InvalidToken                                 => write!(f, "Lock token does not belong to this lock"),
+            LockError::UnsupportedChildLockType { parent, child }   => write!(f, "Parent lock of type {} does not support child lock of type {}", parent, child),
+            LockError::DuplicateId                                  => write!(f, "Child lock id is already in use"),
+            LockError::IdExhausted                                  => write!(f, "No more child lock ids are available"),
+            LockError::ChildLocksHeld                               => write!(f, "Lock is still used by child locks"),
+            LockError::InvalidReparent                              => write!(f, "New parent lock is a descendant of the child lock"),
+            LockError::HasParent                                    => write!(f, "Child lock already has a parent lock"),
         }
     }
 }
@@ -55,7 +183,32 @@ impl Display for LockError {
 impl Error for LockError {}
 
 
-pub fn map_unknown_err<T: Error>(error: T) -> LockError { LockError::UnknownError { message: format!("{}", error) } }
+/// Determines what happens when a `GLockGuard` is dropped while its thread is panicking.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum PoisonPolicy {
+    /// The lock is released on a best-effort basis, and the `GLock` remains usable. This is the
+    /// default policy.
+    #[default]
+    BestEffort,
+
+    /// The lock is released, and the `GLock` is marked as poisoned. Any further attempt to acquire
+    /// or upgrade a lock on it will return a `LockError::Poisoned` error.
+    FailStop,
+}
+
+
+/// Fairness statistics of a `GLock`, returned by `GLock::stats()`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct LockStats {
+    /// The longest time an acquisition of a lock on the `GLock` had to wait before it was granted.
+    /// Waits that failed (e.g. timed out) are not included.
+    pub max_wait: Duration,
+
+    /// The number of acquisitions that were granted while an earlier waiter for an incompatible
+    /// lock type was still waiting, i.e. that passed it. A growing count indicates that the
+    /// earlier waiters are being starved (e.g. a writer by a steady stream of readers).
+    pub starvation_events: u64,
+}
 
 
 /// A type alias for `Result<T, LockError>`.
@@ -1,221 +1,1287 @@
-use std::collections::HashMap;
+use std::collections::{ BTreeMap, HashMap };
+use std::fmt::{ Debug, Formatter, Error as FmtError };
+use std::hash::Hash;
 use std::ops::Deref;
-use std::sync::{ Arc, Weak, Mutex, MutexGuard, Condvar };
+use std::sync::{ Arc, Weak, PoisonError };
+use std::sync::atomic::{ AtomicBool, AtomicU64, Ordering };
+use std::time::{ Duration, Instant };
 
 use self::super::common::*;
 use self::super::locktype::*;
+use self::super::sync::*;
 
+/// The default identifier type of child `GLock`s. Ids of this type are assigned automatically
+/// by `new_child()` and `new_child_builder()`, counting up from `0` for each parent `GLock`.
 pub type Id = u64;
 
-#[derive(Debug)]
-pub struct LockKernel {
-    id: Option<Id>,
-    parent: Option<LockKernelRc>,
-    condvar: Condvar,
-    state: Mutex<LockKernelState>,
+/// The identifier type of child `GLock`s. Ids are unique among the children of the same parent
+/// `GLock`. This is implemented for any `Copy + Eq + Hash + Debug` type, so that existing node
+/// identifiers can be used as lock ids through `new_child_with_id()`.
+pub trait LockId: Copy + Eq + Hash + Debug {}
+
+impl<I: Copy + Eq + Hash + Debug> LockId for I {}
+
+// The thread a lock is attributed to, i.e. the thread holding the guard of the lock, or `None` if
+// the lock cannot be attributed to any thread (see `LockInstance::detach()`). Guards are not
+// `Send`, so the owner of a lock only changes when it is detached or attached explicitly.
+type Owner = Option<ThreadId>;
+
+/// The admission hook of a `GLock` (see `GLockBuilder::admit()`), called with the id of the
+/// `GLock` and the requested lock type.
+pub type AdmitFn<I> = Arc<dyn Fn(Option<I>, LockType) -> bool + Send + Sync>;
+
+#[derive(Clone)]
+pub struct LockOptions<I: LockId> {
+    pub poison_policy: PoisonPolicy,
+    pub intended_lock_type: Option<LockType>,
+    pub default_timeout: Option<Duration>,
+    pub require_explicit_parent: bool,
+    pub admit: Option<AdmitFn<I>>,
+}
+
+impl<I: LockId> LockOptions<I> {
+
+    /// Returns the options inherited by children: only the policies, not the per-lock settings.
+    pub fn inherited(&self) -> LockOptions<I> {
+        LockOptions {
+            poison_policy: self.poison_policy,
+            default_timeout: self.default_timeout,
+            admit: self.admit.clone(),
+            ..LockOptions::default()
+        }
+    }
+}
+
+impl<I: LockId> Default for LockOptions<I> {
+    fn default() -> LockOptions<I> {
+        LockOptions {
+            poison_policy: PoisonPolicy::default(),
+            intended_lock_type: None,
+            default_timeout: None,
+            require_explicit_parent: false,
+            admit: None,
+        }
+    }
+}
+
+impl<I: LockId> Debug for LockOptions<I> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
+        f.debug_struct("LockOptions")
+            .field("poison_policy", &self.poison_policy)
+            .field("intended_lock_type", &self.intended_lock_type)
+            .field("default_timeout", &self.default_timeout)
+            .field("require_explicit_parent", &self.require_explicit_parent)
+            .field("admit", &self.admit.is_some())
+            .finish()
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Wait {
+    Block,
+    TryOnly,
+    Until(Instant),
+}
+
+impl Wait {
+
+    pub fn timeout(timeout: Duration) -> Wait { Wait::Until(Instant::now() + timeout) }
+
+    fn deadline(self) -> Option<Instant> {
+        match self {
+            Wait::Until(deadline)   => Some(deadline),
+            _                       => None,
+        }
+    }
+}
+
+// The outcome of waiting for a lock to become acquirable (see `wait_compatible()`).
+enum Readiness<'mg, I: LockId> {
+    Ready(MutexGuard<'mg, LockKernelState<I>>),
+    ParentMoved,
+    Rejected,
+}
+
+// The outcome of a single acquisition attempt (see `acquire_once()`).
+enum Acquisition<I: LockId> {
+    Acquired(Arc<LockInstance<I>>),
+    ParentMoved,
+    Rejected,
+}
+
+// The creation sequence of the next kernel, shared by all lock trees.
+static NEXT_SEQ: AtomicU64 = AtomicU64::new(0);
+
+fn park<'mg, T>(condvar: &Condvar, state: MutexGuard<'mg, T>, timeout: Option<Duration>) -> MutexGuard<'mg, T> {
+    // If another thread panicked while holding the state mutex, the guard is recovered and the
+    // caller keeps waiting, as `release()` does: the kernel never panics halfway through an
+    // update of the state, so it is still consistent.
+    match timeout {
+        Some(timeout)   => condvar.wait_timeout(state, timeout).map(|(state, _)| state).unwrap_or_else(|err| err.into_inner().0),
+        None            => condvar.wait(state).unwrap_or_else(PoisonError::into_inner),
+    }
+}
+
+fn remaining(deadline: Option<Instant>) -> LockResult<Option<Duration>> {
+    match deadline {
+        Some(deadline) => {
+            let now = Instant::now();
+            if now >= deadline { return Err(LockError::Timeout); }
+            Ok(Some(deadline - now))
+        },
+
+        None => Ok(None),
+    }
+}
+
+pub struct LockKernel<I: LockId> {
+    id: Option<I>,
+    seq: u64,
+    // Only changes once, from `true` to `false`, when a root kernel is adopted (see `adopt()`).
+    root: AtomicBool,
+    // Whether the options have an admission hook, so that acquisitions only look it up if set.
+    admission: AtomicBool,
+    // Threads waiting to acquire (or upgrade to) a lock type wait on the condvar of that type, so
+    // that releasing a lock only wakes up the threads whose lock types it was blocking.
+    condvars: [Condvar; LOCK_TYPE_COUNT],
+    idle_condvar: Condvar,
+    state: StateMutex<LockKernelState<I>>,
 }
 
 #[derive(Debug)]
-struct LockKernelState {
+struct LockKernelState<I: LockId> {
+    parent: Option<LockKernelRc<I>>,
     owned: bool,
+    released: bool,
+    closed: bool,
+    options: LockOptions<I>,
+    validated: bool,
+    poisoned: bool,
     counts: [usize; LOCK_TYPE_COUNT],
-    children: HashMap<Id, Weak<LockKernel>>,
-    children_counter: Id,
+    holders: HashMap<Owner, [usize; LOCK_TYPE_COUNT]>,
+    waiters: usize,
+    waiting_priorities: BTreeMap<u8, usize>,
+    waiting_tickets: BTreeMap<u64, LockType>,
+    next_ticket: u64,
+    stats: LockStats,
+    pending_upgrades: [[usize; LOCK_TYPE_COUNT]; LOCK_TYPE_COUNT],
+    // Incremented by `force_reset()`, so that the locks granted before it are not released twice.
+    epoch: u64,
+    // Incremented whenever an `Exclusive` lock is released or downgraded, i.e. after every write.
+    write_version: u64,
+    // The number of suspended instances (see `LockInstance::suspend()`) per suspending thread.
+    suspended: HashMap<ThreadId, usize>,
+    children: HashMap<I, Weak<LockKernel<I>>>,
+    children_counter: Option<Id>,
+}
+
+impl<I: LockId> LockKernelState<I> {
+
+    fn add_holder(&mut self, owner: Owner, lock_type: LockType) {
+        self.counts[lock_type.index()] += 1;
+        self.holders.entry(owner).or_insert(LOCK_EMPTY_COUNTS)[lock_type.index()] += 1;
+    }
+
+    fn remove_holder(&mut self, owner: Owner, lock_type: LockType) {
+        self.counts[lock_type.index()] -= 1;
+
+        let empty = match self.holders.get_mut(&owner) {
+            Some(counts) => {
+                counts[lock_type.index()] -= 1;
+                counts.iter().all(|c| *c == 0)
+            },
+
+            None => false,
+        };
+
+        if empty { self.holders.remove(&owner); }
+    }
+
+    fn parent_matches(&self, parent_instance: &Option<Arc<LockInstance<I>>>) -> bool {
+        match (self.parent.as_ref(), parent_instance.as_ref()) {
+            (Some(parent), Some(instance))  => parent.ptr_eq(&instance.kernel),
+            (None, None)                    => true,
+            _                               => false,
+        }
+    }
+
+    fn has_child(&self, id: &I) -> bool {
+        matches!(self.children.get(id), Some(child) if child.strong_count() > 0)
+    }
+
+    // Takes strong references to the live children. They must only be used (and dropped) after
+    // releasing the state mutex, since dropping the last reference to a child locks it.
+    fn live_children(&self) -> Vec<(I, Arc<LockKernel<I>>)> {
+        self.children.iter().filter_map(|(id, child)| child.upgrade().map(|child| (*id, child))).collect()
+    }
+
+    fn resolve_wait(&self, wait: Wait) -> Wait {
+        match (wait, self.options.default_timeout) {
+            (Wait::Block, Some(timeout))    => Wait::timeout(timeout),
+            _                               => wait,
+        }
+    }
+
+    fn add_waiting_priority(&mut self, priority: u8) {
+        if priority > 0 { *self.waiting_priorities.entry(priority).or_insert(0) += 1; }
+    }
+
+    fn remove_waiting_priority(&mut self, priority: u8) -> bool {
+        let empty = match self.waiting_priorities.get_mut(&priority) {
+            Some(count) => {
+                *count -= 1;
+                *count == 0
+            },
+
+            None => return false,
+        };
+
+        if empty { self.waiting_priorities.remove(&priority); }
+        true
+    }
+
+    // Waiters are registered by arrival ticket, so that acquisitions passing earlier waiters can be
+    // detected (see `record_grant()`).
+    fn start_waiting(&mut self, priority: u8, ticket: u64, lock_type: LockType) {
+        self.add_waiting_priority(priority);
+        self.waiting_tickets.insert(ticket, lock_type);
+    }
+
+    fn stop_waiting(&mut self, priority: u8, ticket: u64) -> bool {
+        self.waiting_tickets.remove(&ticket);
+        self.remove_waiting_priority(priority)
+    }
+
+    // An acquisition starves a waiter if it is granted while an earlier waiter of an incompatible
+    // type is still waiting, i.e. the later arrival passed it.
+    fn record_grant(&mut self, ticket: u64, lock_type: LockType, waiting_since: Option<Instant>) {
+        if self.waiting_tickets.range(..ticket).any(|(_, lt)| !lt.compatible_with(lock_type)) {
+            self.stats.starvation_events += 1;
+        }
+
+        if let Some(since) = waiting_since {
+            self.stats.max_wait = self.stats.max_wait.max(since.elapsed());
+        }
+    }
+
+    // Like pending upgrades (see `reserved_against()`), higher-priority waiters do not hold back
+    // threads that already hold a lock on the kernel, since they wait for those threads anyway.
+    fn outranked(&self, priority: u8, owner: ThreadId) -> bool {
+        matches!(self.waiting_priorities.keys().next_back(), Some(max) if *max > priority) && !self.held_by(owner)
+    }
+
+    // Threads that already hold a lock on the kernel are not held back by pending upgrades: the
+    // upgrades wait for them to release their locks anyway, e.g. a reader locking a child of the
+    // kernel, which needs an implicit `IntentionShared` lock on it.
+    fn reserved_against(&self, lock_type: LockType, owner: ThreadId) -> bool {
+        self.pending_upgrades_any(|_, to_type| !lock_type.compatible_with(to_type)) && !self.held_by(owner)
+    }
+
+    // Two upgrades wait for each other forever if each one's target type is incompatible with the
+    // lock the other one is upgrading from, e.g. two `Shared` locks both upgrading to `Exclusive`.
+    fn conflicting_upgrade(&self, from_type: LockType, to_type: LockType) -> bool {
+        self.pending_upgrades_any(|pending_from, pending_to| {
+            !pending_to.compatible_with(from_type) && !to_type.compatible_with(pending_from)
+        })
+    }
+
+    fn pending_upgrades_any<F: Fn(LockType, LockType) -> bool>(&self, pred: F) -> bool {
+        LockType::lock_types().iter().any(|from_type| {
+            LockType::lock_types().iter().any(|to_type| {
+                self.pending_upgrades[from_type.index()][to_type.index()] > 0 && pred(*from_type, *to_type)
+            })
+        })
+    }
+
+    fn own_counts(&self, owner: ThreadId) -> [usize; LOCK_TYPE_COUNT] {
+        self.holders.get(&Some(owner)).cloned().unwrap_or(LOCK_EMPTY_COUNTS)
+    }
+
+    // Whether the thread holds any lock through its guards, so that it would wait for itself
+    // forever if it waited for all locks to be released.
+    fn held_by(&self, owner: ThreadId) -> bool {
+        self.holders.contains_key(&Some(owner))
+    }
+
+    fn only_blocked_by(&self, owner: ThreadId, lock_type: LockType) -> bool {
+        let own_counts = self.own_counts(owner);
+
+        LockType::lock_types().iter().all(|lt| {
+            lock_type.compatible_with(*lt) || self.counts[lt.index()] == own_counts[lt.index()]
+        })
+    }
+
+    fn only_held_by(&self, owner: ThreadId, lock_type: LockType, from_type: LockType) -> bool {
+        self.only_blocked_by(owner, lock_type) && self.own_counts(owner)[from_type.index()] > 0
+    }
+
+    // Whether the current holders could let a lock of the given type be acquired, ignoring the
+    // locks being upgraded to it, which do not block their own upgrades.
+    fn holders_compatible_with(&self, lock_type: LockType) -> bool {
+        LockType::lock_types().iter().all(|lt| {
+            let count = self.counts[lt.index()].saturating_sub(self.pending_upgrades[lt.index()][lock_type.index()]);
+            count == 0 || lock_type.compatible_with(*lt)
+        })
+    }
 }
 
-impl LockKernel {
+impl<I: LockId> LockKernel<I> {
 
-    pub fn new(id: Option<Id>, parent: Option<LockKernelRc>) -> LockKernel {
+    pub fn new(id: Option<I>, parent: Option<LockKernelRc<I>>) -> LockKernel<I> {
         LockKernel {
             id,
-            parent,
-            condvar: Condvar::new(),
-            state: Mutex::new(LockKernelState {
+            seq: NEXT_SEQ.fetch_add(1, Ordering::Relaxed),
+            root: AtomicBool::new(parent.is_none()),
+            admission: AtomicBool::new(false),
+            condvars: [Condvar::new(), Condvar::new(), Condvar::new(), Condvar::new(), Condvar::new()],
+            idle_condvar: Condvar::new(),
+            state: StateMutex::new(LockKernelState {
+                parent,
                 owned: false,
+                released: false,
+                closed: false,
+                options: LockOptions::default(),
+                validated: false,
+                poisoned: false,
                 counts: LOCK_EMPTY_COUNTS,
+                holders: HashMap::new(),
+                waiters: 0,
+                waiting_priorities: BTreeMap::new(),
+                waiting_tickets: BTreeMap::new(),
+                next_ticket: 0,
+                stats: LockStats::default(),
+                pending_upgrades: [LOCK_EMPTY_COUNTS; LOCK_TYPE_COUNT],
+                epoch: 0,
+                write_version: 0,
+                suspended: HashMap::new(),
                 children: HashMap::new(),
-                children_counter: 0,
+                children_counter: Some(0),
             }),
         }
     }
 
-    fn lock_state<'slf: 'mg, 'mg>(&'slf self) -> LockResult<MutexGuard<'mg, LockKernelState>> {
-        self.state
-            .lock()
-            .map_err(map_unknown_err)
+    fn lock_state(&self) -> MutexGuard<'_, LockKernelState<I>> {
+        self.state.lock()
     }
 
-    fn dropping(&self, id: &Id) {
-        self.lock_state()
-            .map(|mut state| state.children.remove(id))
-            .unwrap();
+    fn resolve_wait(&self, wait: Wait) -> Wait {
+        match wait {
+            Wait::Block => self.lock_state().resolve_wait(wait),
+            _           => wait,
+        }
     }
 
-    pub fn own(&self) -> LockResult<()> {
-        self.lock_state().map(|mut state| {
-            state.owned = true;
-        })
+    fn wait<'mg>(&self, mut state: MutexGuard<'mg, LockKernelState<I>>, lock_type: LockType, deadline: Option<Instant>) -> LockResult<MutexGuard<'mg, LockKernelState<I>>> {
+        let timeout = remaining(deadline)?;
+
+        state.waiters += 1;
+        let mut state = park(&self.condvars[lock_type.index()], state, timeout);
+        state.waiters -= 1;
+
+        Ok(state)
+    }
+
+    fn notify_all(&self) {
+        for condvar in self.condvars.iter() { condvar.notify_all(); }
+        self.idle_condvar.notify_all();
+    }
+
+    // Releasing (or downgrading) a lock can only unblock threads waiting for lock types that were
+    // incompatible with it, and only if they are compatible with the remaining holders.
+    fn notify_released(&self, state: &LockKernelState<I>, lock_type: LockType) {
+        for lt in LockType::lock_types() {
+            if !lt.compatible_with(lock_type) && state.holders_compatible_with(*lt) {
+                self.condvars[lt.index()].notify_all();
+            }
+        }
+
+        if state.counts.iter().all(|c| *c == 0) { self.idle_condvar.notify_all(); }
+    }
+
+    // Waiting for the kernel to become idle is not counted in `waiters`, since it does not compete
+    // for the lock.
+    pub fn wait_idle(&self, wait: Wait) -> LockResult<()> {
+        let mut state = self.lock_state();
+
+        if state.held_by(thread::current().id()) { return Err(LockError::WouldSelfDeadlock); }
+
+        while state.counts.iter().any(|c| *c > 0) {
+            if wait == Wait::TryOnly { return Err(LockError::LockBusy); }
+
+            let timeout = remaining(wait.deadline())?;
+            state = park(&self.idle_condvar, state, timeout);
+        }
+
+        Ok(())
+    }
+
+    // Marks the kernel and its descendants as closed, so that acquiring new locks on them fails,
+    // including for the threads already waiting, then waits for the locks still held to be
+    // released. Closing cannot be undone.
+    pub fn close(&self, wait: Wait) -> LockResult<()> {
+        if self.lock_state().held_by(thread::current().id()) { return Err(LockError::WouldSelfDeadlock); }
+
+        self.mark_closed();
+        self.wait_idle(wait)
+    }
+
+    fn mark_closed(&self) {
+        let children = {
+            let mut state = self.lock_state();
+            state.closed = true;
+            self.notify_all();
+            state.live_children()
+        };
+
+        // The children are closed after releasing the state mutex, see `live_children()`.
+        children.iter().for_each(|(_, child)| child.mark_closed());
+    }
+
+    // Forgets all the locks currently held and wakes up all waiting threads. The instances of the
+    // forgotten locks are from an older epoch, so dropping them later does not release anything.
+    pub fn force_reset(&self) {
+        let mut state = self.lock_state();
+
+        state.counts = LOCK_EMPTY_COUNTS;
+        state.holders.clear();
+        state.epoch += 1;
+        state.write_version += 1;
+
+        #[cfg(feature = "log")]
+        ::log::debug!("forcibly reset lock; id: {:?}", self.id);
+
+        self.notify_all();
+    }
+
+    pub fn id(&self) -> Option<I> {
+        self.id
+    }
+
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    pub fn waiters(&self) -> usize {
+        self.lock_state().waiters
+    }
+
+    pub fn stats(&self) -> LockStats {
+        self.lock_state().stats
+    }
+
+    pub fn write_version(&self) -> u64 {
+        self.lock_state().write_version
+    }
+
+    pub fn count_of(&self, lock_types: &[LockType]) -> usize {
+        let state = self.lock_state();
+        lock_types.iter().map(|lt| state.counts[lt.index()]).sum()
+    }
+
+    // Calls `f` if no `Exclusive` lock is held, without acquiring a lock: none can be granted while
+    // the state mutex is held, so the data protected by the kernel cannot change meanwhile. Like
+    // `Debug`, this never blocks on the state mutex.
+    pub fn peek<R, F: FnOnce() -> R>(&self, f: F) -> Option<R> {
+        let state = self.state.try_lock()?;
+        if state.counts[LockType::Exclusive.index()] > 0 { return None; }
+        Some(f())
+    }
+
+    #[cfg(any(test, feature = "testing"))]
+    pub fn counts(&self) -> [usize; LOCK_TYPE_COUNT] {
+        self.lock_state().counts
+    }
+
+    // Checks the consistency of the lock accounting: the counts match the holders, the held lock
+    // types are compatible with each other, pending upgrades are backed by held locks, and the
+    // live children point back to the current kernel. Violations are reported as `UnknownError`.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn verify_invariants(&self) -> LockResult<()> {
+        let violation = |message: String| LockError::UnknownError { message: format!("invariant violated; id: {:?}; {}", self.id, message) };
+
+        let children = {
+            let state = self.lock_state();
+
+            let mut holder_counts = LOCK_EMPTY_COUNTS;
+
+            for (owner, counts) in state.holders.iter() {
+                if counts.iter().all(|c| *c == 0) { return Err(violation(format!("empty holder entry for {:?}", owner))); }
+                for lt in LockType::lock_types() { holder_counts[lt.index()] += counts[lt.index()]; }
+            }
+
+            if holder_counts != state.counts {
+                return Err(violation(format!("counts {:?} do not match holders {:?}", state.counts, holder_counts)));
+            }
+
+            for lt1 in LockType::lock_types() {
+                for lt2 in LockType::lock_types() {
+                    let coexisting = if lt1 == lt2 { state.counts[lt1.index()] > 1 } else { state.counts[lt1.index()] > 0 && state.counts[lt2.index()] > 0 };
+
+                    if coexisting && !lt1.compatible_with(*lt2) {
+                        return Err(violation(format!("incompatible {} and {} locks are held", lt1, lt2)));
+                    }
+                }
+
+                let pending: usize = state.pending_upgrades[lt1.index()].iter().sum();
+
+                if pending > state.counts[lt1.index()] {
+                    return Err(violation(format!("{} pending upgrades from {} exceed {} held locks", pending, lt1, state.counts[lt1.index()])));
+                }
+            }
+
+            state.live_children()
+        };
+
+        // The children are checked after releasing the state mutex, see `live_children()`.
+        for (id, child) in children.iter() {
+            if child.id != Some(*id) {
+                return Err(violation(format!("child registered as {:?} has id {:?}", id, child.id)));
+            }
+
+            if !matches!(child.parent(), Some(parent) if std::ptr::eq(&*parent, self)) {
+                return Err(violation(format!("child {:?} has a different parent", id)));
+            }
+        }
+
+        Ok(())
+    }
+
+    #[cfg(test)]
+    pub fn children_len(&self) -> usize {
+        self.lock_state().children.len()
+    }
+
+    // Returns `true` if the current thread holds a lock of at least the specified type, i.e. of a
+    // type that the specified type is upgradable to.
+    pub fn held_by_current_thread(&self, at_least: LockType) -> bool {
+        let own_counts = self.lock_state().own_counts(thread::current().id());
+        LockType::lock_types().iter().any(|lt| own_counts[lt.index()] > 0 && at_least.upgradable_to(*lt))
+    }
+
+    pub fn in_use(&self) -> bool {
+        let state = self.lock_state();
+
+        state.counts.iter().any(|c| *c > 0) ||
+            state.children.values().any(|child| child.strong_count() > 0)
+    }
+
+    fn dropping(&self, id: &I) {
+        let mut state = self.lock_state();
+
+        // A new child may have already reused the id of the child being dropped.
+        if !state.has_child(id) { state.children.remove(id); }
+    }
+
+    pub fn shrink_to_fit(&self) {
+        let mut state = self.lock_state();
+        state.children.retain(|_, child| child.strong_count() > 0);
+        state.children.shrink_to_fit();
+    }
+
+    pub fn options(&self) -> LockOptions<I> {
+        self.lock_state().options.clone()
+    }
+
+    pub fn own(&self, options: LockOptions<I>) {
+        let mut state = self.lock_state();
+        self.admission.store(options.admit.is_some(), Ordering::Release);
+        state.owned = true;
+        state.options = options;
+    }
+
+    // The hook is called without holding the state mutex, so that it may lock other kernels.
+    fn check_admission(&self, lock_type: LockType) -> LockResult<()> {
+        if !self.admission.load(Ordering::Acquire) { return Ok(()); }
+
+        let admit = self.lock_state().options.admit.clone();
+
+        match admit {
+            Some(admit) if !admit(self.id, lock_type)   => Err(LockError::Rejected),
+            _                                           => Ok(()),
+        }
+    }
+
+    fn parent(&self) -> Option<LockKernelRc<I>> {
+        self.lock_state().parent.clone()
+    }
+
+    pub fn depth(&self) -> usize {
+        let mut depth = 0;
+        let mut current = self.parent();
+
+        while let Some(parent) = current {
+            depth += 1;
+            current = parent.parent();
+        }
+
+        depth
+    }
+
+    // Lists the ancestors from the root down to the parent, along with the type of the implicit
+    // lock acquired on each one when acquiring a lock of the specified type directly.
+    pub fn parent_chain_types(&self, lock_type: LockType) -> Vec<(Option<I>, LockType)> {
+        let mut chain = Vec::new();
+        let mut lock_type = lock_type;
+        let mut current = self.parent();
+
+        while let Some(parent) = current {
+            lock_type = lock_type.implicit_parent_type();
+            chain.push((parent.id(), lock_type));
+            current = parent.parent();
+        }
+
+        chain.reverse();
+        chain
+    }
+
+    fn validate_intended_lock_type(&self, parent_lock_type: LockType) -> LockResult<()> {
+        let mut state = self.lock_state();
+
+        match state.options.intended_lock_type {
+            Some(intended_lock_type) if !state.validated => {
+                if !parent_lock_type.supports_children(intended_lock_type) {
+                    return Err(LockError::UnsupportedChildLockType { parent: parent_lock_type, child: intended_lock_type });
+                }
+
+                state.validated = true;
+                Ok(())
+            },
+
+            _ => Ok(()),
+        }
+    }
+
+    pub fn unown(&self) {
+        let mut state = self.lock_state();
+        state.owned = false;
+        state.released = true;
+    }
+}
+
+impl<I: LockId> Debug for LockKernel<I> {
+
+    // Never blocks on the state mutex: the current thread may already be holding it, in which
+    // case blocking would deadlock.
+    fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
+        let mut debug = f.debug_struct("LockKernel");
+        debug.field("id", &self.id);
+
+        match self.state.try_lock() {
+            Some(state) => debug.field("state", &*state),
+            None        => debug.field("state", &format_args!("<locked>")),
+        };
+
+        debug.finish()
+    }
+}
+
+impl<I: LockId> Drop for LockKernel<I> {
+    fn drop(&mut self) {
+        let state = self.state.get_mut();
+
+        if let (Some(id), Some(parent)) = (self.id.as_ref(), state.parent.as_ref()) {
+            parent.dropping(id);
+        }
+    }
+}
+
+
+#[derive(Debug)]
+pub struct LockKernelRc<I: LockId> {
+    kernel: Arc<LockKernel<I>>,
+}
+
+impl<I: LockId> LockKernelRc<I> {
+
+    pub fn new(kernel: LockKernel<I>) -> LockKernelRc<I> {
+        LockKernelRc {
+            kernel: Arc::new(kernel),
+        }
+    }
+
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.kernel, &other.kernel)
+    }
+
+    pub fn order_key(&self) -> u64 {
+        self.seq
+    }
+
+    pub fn new_child(&self, id: I) -> LockResult<LockKernelRc<I>> {
+        self.insert_child(&mut self.kernel.lock_state(), id)
+    }
+
+    fn insert_child(&self, state: &mut LockKernelState<I>, id: I) -> LockResult<LockKernelRc<I>> {
+        if state.has_child(&id) { return Err(LockError::DuplicateId); }
+
+        let kernel = LockKernelRc::new(LockKernel::new(Some(id), Some(self.clone())));
+        state.children.insert(id, kernel.clone_weak());
+        Ok(kernel)
+    }
+
+    pub fn reparent(&self, child: &LockKernelRc<I>, new_parent: &LockKernelRc<I>) -> LockResult<()> {
+        let id = child.id.ok_or(LockError::InvalidParentLock)?;
+
+        if !child.is_child_of(self) { return Err(LockError::InvalidParentLock); }
+        if new_parent.ptr_eq(self) { return Ok(()); }
+
+        // An `Exclusive` lock on the child excludes all locks on the child and its descendants, and
+        // freezes its subtree: moving a node into or out of it requires a lock on the child too.
+        let child_lock = child.lock_unsuspended()?;
+
+        if !child.is_child_of(self) { return Err(LockError::InvalidParentLock); }
+        if new_parent.is_descendant_of(child) { return Err(LockError::InvalidReparent); }
+
+        // An intention lock on the new parent freezes its ancestry, and makes the `Exclusive` lock
+        // on the child valid under the new parent as well until it is released.
+        let _new_parent_lock = new_parent.acquire(LockType::IntentionExclusive, None, true, Wait::Block, 0)?;
+
+        let weak_child = child.clone_weak();
+
+        {
+            let mut state = new_parent.lock_state();
+            if state.has_child(&id) { return Err(LockError::DuplicateId); }
+            state.children.insert(id, weak_child.clone());
+        }
+
+        {
+            let mut state = child.lock_state();
+            state.parent = Some(new_parent.clone());
+            state.validated = false;
+        }
+
+        {
+            let mut state = self.lock_state();
+            if matches!(state.children.get(&id), Some(c) if c.ptr_eq(&weak_child)) { state.children.remove(&id); }
+        }
+
+        // Releasing the child lock wakes up the threads waiting for it, which then find out that
+        // the parent they locked is outdated and retry under the new parent.
+        drop(child_lock);
+        Ok(())
+    }
+
+    // Makes a root kernel a child of the current kernel. The child has no id, since it was not
+    // created by the current kernel, so it is not tracked among its children.
+    pub fn adopt(&self, child: &LockKernelRc<I>) -> LockResult<()> {
+        if child.ptr_eq(self) { return Err(LockError::InvalidReparent); }
+
+        // As in `reparent()`, an `Exclusive` lock on the child freezes its subtree, while an
+        // intention lock on the new parent freezes its ancestry. Threads that were waiting for the
+        // child as a root find out that it has a parent when woken up, and retry.
+        let child_lock = child.lock_unsuspended()?;
+
+        if child.parent().is_some() { return Err(LockError::HasParent); }
+        if self.is_descendant_of(child) { return Err(LockError::InvalidReparent); }
+
+        let _parent_lock = self.acquire(LockType::IntentionExclusive, None, true, Wait::Block, 0)?;
+
+        {
+            let mut state = child.lock_state();
+            state.parent = Some(self.clone());
+            state.validated = false;
+            child.root.store(false, Ordering::Release);
+        }
+
+        drop(child_lock);
+        Ok(())
+    }
+
+    // Acquires an `Exclusive` lock on the kernel, once no instance of it is suspended.
+    fn lock_unsuspended(&self) -> LockResult<Arc<LockInstance<I>>> {
+        loop {
+            let lock = self.acquire(LockType::Exclusive, None, true, Wait::Block, 0)?;
+            if let Some(lock) = self.unless_suspended(lock)? { return Ok(lock); }
+        }
+    }
+
+    fn is_child_of(&self, parent: &LockKernelRc<I>) -> bool {
+        matches!(self.parent(), Some(p) if p.ptr_eq(parent))
+    }
+
+    // Returns `true` if the kernels of the specified instances are the closest ancestors of the
+    // current kernel, listed from the outermost one down to the parent.
+    pub fn is_parent_chain(&self, chain: &[Arc<LockInstance<I>>]) -> bool {
+        let mut current = self.clone();
+
+        for instance in chain.iter().rev() {
+            if !current.is_child_of(&instance.kernel) { return false; }
+            current = instance.kernel.clone();
+        }
+
+        true
+    }
+
+    fn is_descendant_of(&self, ancestor: &LockKernelRc<I>) -> bool {
+        let mut current = Some(self.clone());
+
+        while let Some(kernel) = current {
+            if kernel.ptr_eq(ancestor) { return true; }
+            current = kernel.parent();
+        }
+
+        false
+    }
+
+    pub fn clone_weak(&self) -> Weak<LockKernel<I>> {
+        Arc::downgrade(&self.kernel)
+    }
+
+    pub fn acquire(&self, lock_type: LockType, using_parent: Option<Arc<LockInstance<I>>>, auto_upgrade: bool, wait: Wait, priority: u8) -> LockResult<Arc<LockInstance<I>>> {
+        self.check_admission(lock_type)?;
+
+        loop {
+            if let Acquisition::Acquired(instance) = self.acquire_once(lock_type, using_parent.clone(), auto_upgrade, wait, false, priority, &|_: &LockKernelState<I>| true)? {
+                return Ok(instance);
+            }
+        }
+    }
+
+    // Acquires a lock without waiting for any implicit lock on the ancestors: if one of them is
+    // busy, `LockBusy` is returned, while the lock itself is waited for as specified by `wait`.
+    pub fn acquire_nonblocking_parent(&self, lock_type: LockType, wait: Wait) -> LockResult<Arc<LockInstance<I>>> {
+        self.check_admission(lock_type)?;
+
+        loop {
+            if let Acquisition::Acquired(instance) = self.acquire_once(lock_type, None, true, wait, true, 0, &|_: &LockKernelState<I>| true)? {
+                return Ok(instance);
+            }
+        }
+    }
+
+    // Acquires a lock only if the specified condition holds for the counts of the locks currently
+    // held, checked while holding the state mutex right before the lock is added to them. Returns
+    // `None` as soon as the condition fails, including after waiting for the lock.
+    pub fn acquire_if<F: Fn(&[usize; LOCK_TYPE_COUNT]) -> bool>(&self, lock_type: LockType, wait: Wait, cond: F) -> LockResult<Option<Arc<LockInstance<I>>>> {
+        self.acquire_when(lock_type, wait, |state: &LockKernelState<I>| cond(&state.counts))
     }
 
-    pub fn unown(&self) -> LockResult<()> {
-        self.lock_state().map(|mut state| { state.owned = false; })
+    // Acquires a lock only if the write version still equals the expected one, see `acquire_if()`.
+    pub fn acquire_if_version(&self, lock_type: LockType, wait: Wait, expected: u64) -> LockResult<Option<Arc<LockInstance<I>>>> {
+        self.acquire_when(lock_type, wait, |state: &LockKernelState<I>| state.write_version == expected)
+    }
+
+    fn acquire_when<F: Fn(&LockKernelState<I>) -> bool>(&self, lock_type: LockType, wait: Wait, cond: F) -> LockResult<Option<Arc<LockInstance<I>>>> {
+        self.check_admission(lock_type)?;
+
+        // Checked up front as well, so that no parent lock is acquired (or waited for) in vain.
+        {
+            let state = self.lock_state();
+            if state.released || state.closed { return Err(LockError::LockReleased); }
+            if !cond(&state) { return Ok(None); }
+        }
+
+        loop {
+            match self.acquire_once(lock_type, None, true, wait, false, 0, &cond)? {
+                Acquisition::Acquired(instance) => return Ok(Some(instance)),
+                Acquisition::Rejected           => return Ok(None),
+                Acquisition::ParentMoved        => {},
+            }
+        }
+    }
+
+    // Returns `ParentMoved` if the kernel was moved to another parent (see `reparent()`) after the
+    // parent lock was acquired, in which case the parent lock is released and the caller has to
+    // retry. If `try_parent` is `true`, the parent lock is only tried, regardless of `wait`.
+    #[allow(clippy::too_many_arguments)]
+    fn acquire_once<F: Fn(&LockKernelState<I>) -> bool>(&self, lock_type: LockType, using_parent: Option<Arc<LockInstance<I>>>, auto_upgrade: bool, wait: Wait, try_parent: bool, priority: u8, admit: &F) -> LockResult<Acquisition<I>> {
+
+        let implicit_parent = using_parent.is_none();
+
+        // Root kernels have no parent lock to acquire, so their wait is only resolved below, while
+        // holding the state mutex.
+        let (wait, parent_instance) = if self.root.load(Ordering::Acquire) {
+            (wait, None)
+        } else {
+            let wait = self.resolve_wait(wait);
+            let parent_wait = if try_parent { Wait::TryOnly } else { wait };
+            (wait, self.ensure_parent_lock(lock_type, using_parent, auto_upgrade, parent_wait, priority)?)
+        };
+
+        let (parent_instance, parent_upgrade) = match parent_instance {
+            Some((parent_instance, parent_upgrade)) => (Some(parent_instance), parent_upgrade),
+            None => (None, None),
+        };
+
+        let registered_parent = parent_instance.clone();
+
+        // The parent lock is acquired before taking the state mutex, but this cannot lose a
+        // wakeup: readiness is only checked while holding the state mutex, `release()` has to
+        // take the same mutex before notifying, and `wait()` releases it atomically.
+        let result = self.acquire_locked(lock_type, parent_instance, implicit_parent, parent_upgrade, wait, priority, admit);
+
+        // On success, the registration is handed over to the new instance (see its `Drop`).
+        if let Some(p) = registered_parent {
+            if !matches!(result, Ok(Acquisition::Acquired(_))) { p.child_type_changed(Some(lock_type), None); }
+        }
+
+        result
+    }
+
+    // Acquires the lock once the parent lock (if any) is held, see `acquire_once()`.
+    #[allow(clippy::too_many_arguments)]
+    fn acquire_locked<F: Fn(&LockKernelState<I>) -> bool>(&self, lock_type: LockType, parent_instance: Option<Arc<LockInstance<I>>>, implicit_parent: bool, parent_upgrade: Option<ParentUpgrade>, wait: Wait, priority: u8, admit: &F) -> LockResult<Acquisition<I>> {
+        let state = self.lock_state();
+
+        if state.released || state.closed { return Err(LockError::LockReleased); }
+        if state.poisoned { return Err(LockError::Poisoned); }
+
+        let wait = state.resolve_wait(wait);
+
+        let mut state = match self.wait_compatible(state, lock_type, &parent_instance, wait, priority, false, admit)? {
+            Readiness::Ready(state)                         => state,
+            Readiness::ParentMoved if implicit_parent       => return Ok(Acquisition::ParentMoved),
+            Readiness::ParentMoved                          => return Err(LockError::InvalidParentLock),
+            Readiness::Rejected                             => return Ok(Acquisition::Rejected),
+        };
+
+        let owner = Some(thread::current().id());
+        state.add_holder(owner, lock_type);
+
+        #[cfg(feature = "log")]
+        ::log::trace!("acquired {} lock; id: {:?}", lock_type, self.id);
+
+        Ok(Acquisition::Acquired(LockInstance::new(self.clone(), parent_instance, implicit_parent, parent_upgrade, lock_type, owner, state.epoch)))
+    }
+
+    // Waits until a lock of the specified type can be acquired. Returns `ParentMoved` if the kernel
+    // was moved to a parent other than the one of `parent_instance` in the meantime, or `Rejected`
+    // if `admit` does not accept the current state, e.g. the counts of the locks currently held.
+    // Unless `resuming`, fails with `LockReleased` once the kernel is closed.
+    #[allow(clippy::too_many_arguments)]
+    fn wait_compatible<'mg, F: Fn(&LockKernelState<I>) -> bool>(&self, mut state: MutexGuard<'mg, LockKernelState<I>>, lock_type: LockType, parent_instance: &Option<Arc<LockInstance<I>>>, wait: Wait, priority: u8, resuming: bool, admit: &F) -> LockResult<Readiness<'mg, I>> {
+        let mut waiting = false;
+        let mut waiting_since = None;
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+
+        loop {
+            if state.closed && !resuming {
+                if waiting && state.stop_waiting(priority, ticket) { self.notify_all(); }
+                return Err(LockError::LockReleased);
+            }
+
+            let readiness = if !state.parent_matches(parent_instance) {
+                Some(Readiness::ParentMoved)
+            } else if !admit(&state) {
+                Some(Readiness::Rejected)
+            } else {
+                None
+            };
+
+            if let Some(readiness) = readiness {
+                if waiting && state.stop_waiting(priority, ticket) { self.notify_all(); }
+                return Ok(readiness);
+            }
+
+            let compatible = LockType::lock_types().iter().all(|lt| {
+                state.counts[lt.index()] == 0 || lock_type.compatible_with(*lt)
+            });
+
+            if compatible && !state.outranked(priority, thread::current().id()) && !state.reserved_against(lock_type, thread::current().id()) { break; }
+
+            if wait == Wait::TryOnly { return Err(LockError::LockBusy); }
+
+            // Blocking forever on locks held by the current thread is always a bug, so
+            // debug builds turn the hang into a panic (after releasing the state mutex). Only the
+            // locks held by guards of the current thread are attributed to it (see `Owner`), so
+            // the hang is certain: locks that may be released elsewhere, such as `LockToken`s,
+            // are never counted.
+            #[cfg(debug_assertions)]
+            {
+                if !compatible && wait == Wait::Block && state.only_blocked_by(thread::current().id(), lock_type) {
+                    if waiting { state.stop_waiting(priority, ticket); }
+                    drop(state);
+                    panic!("acquiring {} lock would deadlock on locks held by the current thread; id: {:?}", lock_type, self.id);
+                }
+            }
+
+            #[cfg(feature = "tracing")]
+            ::tracing::trace!(id = ?self.id, lock_type = %lock_type, "waiting to acquire lock");
+
+            #[cfg(feature = "log")]
+            ::log::debug!("waiting to acquire {} lock; id: {:?}", lock_type, self.id);
+
+            if !waiting {
+                state.start_waiting(priority, ticket, lock_type);
+                waiting = true;
+                waiting_since = Some(Instant::now());
+            }
+
+            state = match self.wait(state, lock_type, wait.deadline()) {
+                Ok(state) => state,
+
+                Err(err) => {
+                    self.abandon_wait(|state| state.stop_waiting(priority, ticket));
+                    return Err(err);
+                },
+            };
+        }
+
+        if waiting && state.stop_waiting(priority, ticket) {
+            self.notify_all();
+        }
+
+        state.record_grant(ticket, lock_type, waiting_since);
+
+        Ok(Readiness::Ready(state))
+    }
+
+    // Releases the lock of an instance while it is suspended. The instance is counted before its
+    // lock is released, so that `reparent()` and `adopt()` cannot miss it (see `unless_suspended()`).
+    fn suspend(&self, lock_type: LockType, owner: Owner, epoch: u64) {
+        *self.lock_state().suspended.entry(thread::current().id()).or_insert(0) += 1;
+        self.release(lock_type, owner, epoch, false);
+    }
+
+    // Acquires the lock of a suspended instance again (see `LockInstance::suspend()`). The default
+    // timeout, poisoning and closing are ignored, since the instance logically kept holding its
+    // lock. The kernel cannot be moved to another parent meanwhile (see `unless_suspended()`).
+    fn resume(&self, lock_type: LockType, parent_instance: &Option<Arc<LockInstance<I>>>) -> LockResult<(Owner, u64)> {
+        let state = self.lock_state();
+
+        let mut state = match self.wait_compatible(state, lock_type, parent_instance, Wait::Block, 0, true, &|_: &LockKernelState<I>| true)? {
+            Readiness::Ready(state) => state,
+            _                       => return Err(LockError::InvalidParentLock),
+        };
+
+        let owner = Some(thread::current().id());
+        state.add_holder(owner, lock_type);
+        self.stop_suspending(&mut state);
+
+        #[cfg(feature = "log")]
+        ::log::trace!("resumed {} lock; id: {:?}", lock_type, self.id);
+
+        Ok((owner, state.epoch))
+    }
+
+    // Called when a suspended instance is resumed, or dropped without being resumed.
+    fn stop_suspending(&self, state: &mut LockKernelState<I>) {
+        let owner = thread::current().id();
+
+        if let Some(count) = state.suspended.get_mut(&owner) {
+            *count -= 1;
+            if *count == 0 { state.suspended.remove(&owner); }
+        }
+
+        if state.suspended.is_empty() { self.notify_all(); }
+    }
+
+    fn forget_suspended(&self) {
+        self.stop_suspending(&mut self.lock_state());
+    }
+
+    // Returns `lock` if no instance of the kernel is suspended. Otherwise, releases it and waits
+    // until the suspended instances are resumed (or dropped), since they could not be resumed
+    // after moving the kernel to another parent, and returns `None` so that the caller retries.
+    fn unless_suspended(&self, lock: Arc<LockInstance<I>>) -> LockResult<Option<Arc<LockInstance<I>>>> {
+        let state = self.lock_state();
+        if state.suspended.is_empty() { return Ok(Some(lock)); }
+
+        let self_deadlock = state.suspended.contains_key(&thread::current().id());
+        drop(state);
+        drop(lock);
+
+        if self_deadlock { return Err(LockError::WouldSelfDeadlock); }
+
+        let mut state = self.lock_state();
+        while !state.suspended.is_empty() { state = self.wait(state, LockType::Exclusive, None)?; }
+        Ok(None)
+    }
+
+    fn abandon_wait<F: FnOnce(&mut LockKernelState<I>) -> bool>(&self, undo: F) {
+        let mut state = self.lock_state();
+
+        if undo(&mut state) {
+            self.notify_all();
+        }
+    }
+
+    fn release(&self, lock_type: LockType, owner: Owner, epoch: u64, panicking: bool) {
+        let mut state = self.lock_state();
+        if state.epoch != epoch { return; }
+
+        state.remove_holder(owner, lock_type);
+        if lock_type == LockType::Exclusive { state.write_version += 1; }
+        if panicking && state.options.poison_policy == PoisonPolicy::FailStop { state.poisoned = true; }
+
+        #[cfg(feature = "tracing")]
+        ::tracing::trace!(id = ?self.id, lock_type = %lock_type, "released lock");
+
+        #[cfg(feature = "log")]
+        ::log::trace!("released {} lock; id: {:?}", lock_type, self.id);
+
+        self.notify_released(&state, lock_type);
+    }
+
+    fn transfer(&self, lock_type: LockType, from: Owner, to: Owner, epoch: u64) {
+        let mut state = self.lock_state();
+        if state.epoch != epoch { return; }
+        state.remove_holder(from, lock_type);
+        state.add_holder(to, lock_type);
+    }
+
+    fn downgrade(&self, from_type: LockType, to_type: LockType, owner: Owner, epoch: u64) {
+        let mut state = self.lock_state();
+        if state.epoch != epoch { return; }
+        state.remove_holder(owner, from_type);
+        if from_type == LockType::Exclusive { state.write_version += 1; }
+        state.add_holder(owner, to_type);
+
+        #[cfg(feature = "log")]
+        ::log::trace!("downgraded {} lock to {}; id: {:?}", from_type, to_type, self.id);
+
+        self.notify_released(&state, from_type);
     }
-}
 
-impl Drop for LockKernel {
-    fn drop(&mut self) {
-        if self.id.is_some() && self.parent.is_some() {
-            self.parent.as_ref().unwrap().dropping(self.id.as_ref().unwrap());
+    fn upgrade(&self, from_type: LockType, to_type: LockType, owner: Owner, using_parent: Option<Arc<LockInstance<I>>>, auto_upgrade: bool, wait: Wait) -> LockResult<()> {
+
+        if from_type == to_type { return Ok(()); }
+
+        if from_type.downgradable_to(to_type) {
+            return Err(LockError::UpgradeToLowerType { original: from_type, requested: to_type });
         }
-    }
-}
 
+        if !from_type.upgradable_to(to_type) {
+            return Err(LockError::InvalidUpgrade { original: from_type, requested: to_type });
+        }
 
-#[derive(Debug)]
-pub struct LockKernelRc {
-    kernel: Arc<LockKernel>,
-}
+        let (wait, parent_instance) = if self.root.load(Ordering::Acquire) {
+            (wait, None)
+        } else {
+            let wait = self.resolve_wait(wait);
+            (wait, self.ensure_parent_lock(to_type, using_parent, auto_upgrade, wait, 0)?.map(|(p, _)| p))
+        };
 
-impl LockKernelRc {
+        let result = self.upgrade_locked(from_type, to_type, owner, wait);
 
-    pub fn new(kernel: LockKernel) -> LockKernelRc {
-        LockKernelRc {
-            kernel: Arc::new(kernel),
+        // The parent lock now supports both types, so drop whichever is no longer required.
+        if let Some(parent_instance) = parent_instance {
+            let stale_type = if result.is_ok() { from_type } else { to_type };
+            parent_instance.child_type_changed(Some(stale_type), None);
         }
-    }
 
-    pub fn ptr_eq(&self, other: &Self) -> bool {
-        Arc::ptr_eq(&self.kernel, &other.kernel)
+        result
     }
 
-    pub fn new_child(&self) -> LockResult<LockKernelRc> {
-        self.kernel
-            .lock_state()
-            .map(|mut state| {
-                let id = state.children_counter;
-                state.children_counter += 1;
+    // Upgrades the lock once the parent lock (if any) supports the requested type, see `upgrade()`.
+    fn upgrade_locked(&self, from_type: LockType, to_type: LockType, owner: Owner, wait: Wait) -> LockResult<()> {
+        let mut state = self.lock_state();
+        let epoch = state.epoch;
 
-                let kernel = LockKernelRc::new(LockKernel::new(Some(id), Some(self.clone())));
-                state.children.insert(id, kernel.clone_weak());
-                kernel
-            })
-    }
+        if state.poisoned { return Err(LockError::Poisoned); }
 
-    pub fn clone_weak(&self) -> Weak<LockKernel> {
-        Arc::downgrade(&self.kernel)
-    }
+        let wait = state.resolve_wait(wait);
 
-    pub fn acquire(&self, lock_type: LockType, using_parent: Option<Arc<LockInstance>>, auto_upgrade: bool, try_only: bool) -> LockResult<Arc<LockInstance>> {
+        let mut ready = false;
+        let mut reserved = false;
 
-        let parent_instance = self.ensure_parent_lock(lock_type, using_parent, auto_upgrade, try_only)?;
+        while !ready {
+            ready = true;
 
-        self.lock_state()
-            .and_then(|mut state| {
-                let mut ready = false;
+            for lt in LockType::lock_types().iter() {
+                let max_count = if *lt == from_type { 1 } else { 0 };
 
-                while !ready {
-                    ready = true;
+                if state.counts[lt.index()] > max_count && !to_type.compatible_with(*lt) {
+                    ready = false;
+                    break;
+                }
+            }
 
-                    for lt in LockType::lock_types().iter() {
-                        if state.counts[lt.index()] > 0 && !lock_type.compatible_with(*lt) {
-                            ready = false;
-                            break;
-                        }
-                    }
+            if !ready {
+                if wait == Wait::TryOnly { return Err(LockError::LockBusy); }
 
-                    if !ready {
-                        if try_only { return Err(LockError::LockBusy); }
-                        else { state = self.condvar.wait(state).map_err(map_unknown_err)?; }
+                if state.only_held_by(thread::current().id(), to_type, from_type) {
+                    if reserved {
+                        state.pending_upgrades[from_type.index()][to_type.index()] -= 1;
+                        self.notify_all();
                     }
-                }
 
-                state.counts[lock_type.index()] += 1;
-
-                Ok(LockInstance::new(self.clone(), parent_instance, lock_type))
-            })
-    }
+                    return Err(LockError::WouldSelfDeadlock);
+                }
 
-    fn release(&self, lock_type: LockType) -> LockResult<()> {
-        self.lock_state()
-            .map(|mut state| {
-                state.counts[lock_type.index()] -= 1;
-                self.condvar.notify_all();
-            })
-    }
+                // Only upgrades that are already waiting are checked, so the first of two
+                // conflicting upgrades keeps waiting and the second one fails.
+                if !reserved && state.conflicting_upgrade(from_type, to_type) {
+                    return Err(LockError::ConflictingUpgrade);
+                }
 
-    fn upgrade(&self, from_type: LockType, to_type: LockType, using_parent: Option<Arc<LockInstance>>, auto_upgrade: bool, try_only: bool) -> LockResult<()> {
+                #[cfg(feature = "tracing")]
+                ::tracing::trace!(id = ?self.id, from_type = %from_type, to_type = %to_type, "waiting to upgrade lock");
 
-        if from_type == to_type { return Ok(()); }
+                #[cfg(feature = "log")]
+                ::log::debug!("waiting to upgrade {} lock to {}; id: {:?}", from_type, to_type, self.id);
 
-        if !from_type.upgradable_to(to_type) {
-            return Err(LockError::InvalidUpgrade { original: from_type, requested: to_type });
-        }
+                // Reserve the kernel, so that new acquisitions that are incompatible with
+                // the requested type wait behind this upgrade instead of starving it.
+                if !reserved {
+                    state.pending_upgrades[from_type.index()][to_type.index()] += 1;
+                    reserved = true;
+                }
 
-        self.ensure_parent_lock(to_type, using_parent, auto_upgrade, try_only)?;
+                state = match self.wait(state, to_type, wait.deadline()) {
+                    Ok(state) => state,
 
-        self.lock_state()
-            .and_then(|mut state| {
-                let mut ready = false;
+                    Err(err) => {
+                        self.abandon_wait(|state| {
+                            state.pending_upgrades[from_type.index()][to_type.index()] -= 1;
+                            true
+                        });
 
-                while !ready {
-                    ready = true;
+                        return Err(err);
+                    },
+                };
 
-                    for lt in LockType::lock_types().iter() {
-                        let max_count = if *lt == from_type { 1 } else { 0 };
+                // The lock being upgraded was forgotten by `force_reset()` in the meantime.
+                if state.epoch != epoch {
+                    state.pending_upgrades[from_type.index()][to_type.index()] -= 1;
+                    self.notify_all();
+                    return Err(LockError::LockReleased);
+                }
+            }
+        }
 
-                        if state.counts[lt.index()] > max_count && !to_type.compatible_with(*lt) {
-                            ready = false;
-                            break;
-                        }
-                    }
+        if reserved { state.pending_upgrades[from_type.index()][to_type.index()] -= 1; }
 
-                    if !ready {
-                        if try_only { return Err(LockError::LockBusy); }
-                        else { state = self.condvar.wait(state).map_err(map_unknown_err)?; }
-                    }
-                }
+        state.remove_holder(owner, from_type);
+        state.add_holder(owner, to_type);
 
-                state.counts[from_type.index()] -= 1;
-                state.counts[to_type.index()] += 1;
+        #[cfg(feature = "log")]
+        ::log::trace!("upgraded {} lock to {}; id: {:?}", from_type, to_type, self.id);
 
-                Ok(())
-            })
+        Ok(())
     }
 
-    fn ensure_parent_lock(&self, lock_type: LockType, using_parent: Option<Arc<LockInstance>>, auto_upgrade: bool, try_only: bool) -> LockResult<Option<Arc<LockInstance>>> {
-        match self.parent.as_ref() {
+    #[allow(clippy::type_complexity)]
+    fn ensure_parent_lock(&self, lock_type: LockType, using_parent: Option<Arc<LockInstance<I>>>, auto_upgrade: bool, wait: Wait, priority: u8) -> LockResult<Option<(Arc<LockInstance<I>>, Option<ParentUpgrade>)>> {
+        match self.parent().as_ref() {
             Some(parent) => {
                 match using_parent {
                     Some(p) => {
                         if !parent.ptr_eq(&p.kernel) { return Err(LockError::InvalidParentLock); }
 
-                        let required_parent_lock_type = lock_type.implicit_parent_type();
-                        let actual_parent_lock_type = p.lock_state()?.lock_type;
-
-                        if required_parent_lock_type.index() > actual_parent_lock_type.index() {
-                            if auto_upgrade {
-                                let upgrade_type = actual_parent_lock_type.min_upgradable(required_parent_lock_type);
-                                p.upgrade(upgrade_type, auto_upgrade, try_only)?;
-                            } else {
-                                return Err(LockError::InvalidParentLockType { required: required_parent_lock_type, actual: actual_parent_lock_type });
-                            }
-
-                        } else if required_parent_lock_type.index() < actual_parent_lock_type.index() {
-                            if !required_parent_lock_type.upgradable_to(actual_parent_lock_type) {
-                                if auto_upgrade {
-                                    let upgrade_type = required_parent_lock_type.min_upgradable(actual_parent_lock_type);
-                                    p.upgrade(upgrade_type, auto_upgrade, try_only)?;
-                                } else {
-                                    return Err(LockError::InvalidParentLockType { required: required_parent_lock_type, actual: actual_parent_lock_type });
-                                }
-                            }
-                        }
+                        // The child lock type is registered before checking the parent lock, so
+                        // that the parent lock is not relaxed in between (see `relax()`).
+                        p.child_type_changed(None, Some(lock_type));
 
-                        Ok(Some(p))
+                        match self.ensure_explicit_parent_type(lock_type, &p, auto_upgrade, wait) {
+                            Ok(parent_upgrade) => Ok(Some((p, parent_upgrade))),
+
+                            Err(err) => {
+                                p.child_type_changed(Some(lock_type), None);
+                                Err(err)
+                            },
+                        }
                     },
 
                     None => {
-                        Ok(Some(parent.acquire(lock_type.implicit_parent_type(), None, auto_upgrade, try_only)?))
+                        if self.lock_state().options.require_explicit_parent {
+                            return Err(LockError::ParentNotHeld);
+                        }
+
+                        let p = parent.acquire(lock_type.implicit_parent_type(), None, auto_upgrade, wait, priority)?;
+                        p.held_for_child(lock_type);
+                        Ok(Some((p, None)))
                     },
                 }
             },
@@ -223,14 +1289,46 @@ impl LockKernelRc {
             None => { Ok(None) },
         }
     }
+
+    fn ensure_explicit_parent_type(&self, lock_type: LockType, p: &Arc<LockInstance<I>>, auto_upgrade: bool, wait: Wait) -> LockResult<Option<ParentUpgrade>> {
+        let required_parent_lock_type = lock_type.implicit_parent_type();
+        let actual_parent_lock_type = p.lock_type();
+
+        self.validate_intended_lock_type(actual_parent_lock_type)?;
+
+        if actual_parent_lock_type.supports_children(lock_type) { return Ok(None); }
+
+        if !auto_upgrade {
+            return Err(LockError::InvalidParentLockType { required: required_parent_lock_type, actual: actual_parent_lock_type });
+        }
+
+        let upgrade_type = actual_parent_lock_type.min_upgradable(required_parent_lock_type);
+        p.escalate(upgrade_type, wait)?;
+        Ok(Some(ParentUpgrade { from: actual_parent_lock_type, to: upgrade_type }))
+    }
+}
+
+impl LockKernelRc<Id> {
+
+    pub fn new_auto_child(&self) -> LockResult<LockKernelRc<Id>> {
+        let mut state = self.kernel.lock_state();
+
+        // The counter becomes `None` once the last id has been handed out. It never wraps around,
+        // so that the same id is never assigned twice.
+        let mut id = state.children_counter.ok_or(LockError::IdExhausted)?;
+        while state.has_child(&id) { id = id.checked_add(1).ok_or(LockError::IdExhausted)?; }
+
+        state.children_counter = id.checked_add(1);
+        self.insert_child(&mut state, id)
+    }
 }
 
-impl Deref for LockKernelRc {
-    type Target = LockKernel;
+impl<I: LockId> Deref for LockKernelRc<I> {
+    type Target = LockKernel<I>;
     fn deref(&self) -> &<Self as Deref>::Target { self.kernel.deref() }
 }
 
-impl Clone for LockKernelRc {
+impl<I: LockId> Clone for LockKernelRc<I> {
     fn clone(&self) -> Self {
         LockKernelRc { kernel: self.kernel.clone() }
     }
@@ -238,63 +1336,279 @@ impl Clone for LockKernelRc {
 
 
 #[derive(Debug)]
-pub struct LockInstance {
-    kernel: LockKernelRc,
-    parent: Option<Arc<LockInstance>>,
-    state: Mutex<LockInstanceState>,
+pub struct LockInstance<I: LockId> {
+    kernel: LockKernelRc<I>,
+    parent: Option<Arc<LockInstance<I>>>,
+    implicit_parent: bool,
+    parent_upgrade: Option<ParentUpgrade>,
+    state: StateMutex<LockInstanceState>,
+    #[cfg(feature = "timestamps")]
+    acquired_at: Instant,
+    #[cfg(feature = "tracing")]
+    span: ::tracing::Span,
 }
 
 #[derive(Debug)]
 struct LockInstanceState {
     lock_type: LockType,
+    requested_type: LockType,
+    owner: Owner,
+    epoch: u64,
+    child_types: [usize; LOCK_TYPE_COUNT],
+    suspended: bool,
 }
 
-impl LockInstance {
+impl<I: LockId> LockInstance<I> {
+
+    #[allow(clippy::too_many_arguments)]
+    fn new(kernel: LockKernelRc<I>, parent: Option<Arc<LockInstance<I>>>, implicit_parent: bool, parent_upgrade: Option<ParentUpgrade>, lock_type: LockType, owner: Owner, epoch: u64) -> Arc<LockInstance<I>> {
 
-    fn new(kernel: LockKernelRc, parent: Option<Arc<LockInstance>>, lock_type: LockType) -> Arc<LockInstance> {
+        #[cfg(feature = "tracing")]
+        let span = ::tracing::trace_span!("glock", id = ?kernel.id, lock_type = %lock_type);
 
         Arc::new(LockInstance {
             kernel,
             parent,
-            state: Mutex::new(LockInstanceState { lock_type, }),
+            implicit_parent,
+            parent_upgrade,
+            state: StateMutex::new(LockInstanceState { lock_type, requested_type: lock_type, owner, epoch, child_types: LOCK_EMPTY_COUNTS, suspended: false }),
+            #[cfg(feature = "timestamps")]
+            acquired_at: Instant::now(),
+            #[cfg(feature = "tracing")]
+            span,
         })
     }
 
-    fn lock_state<'slf: 'mg, 'mg>(&'slf self) -> LockResult<MutexGuard<'mg, LockInstanceState>> {
-        self.state
-            .lock()
-            .map_err(map_unknown_err)
+    fn lock_state(&self) -> MutexGuard<'_, LockInstanceState> {
+        self.state.lock()
     }
 
-    pub fn lock_type(&self) -> LockResult<LockType> {
-        self.lock_state().map(|state| state.lock_type)
+    pub fn lock_type(&self) -> LockType {
+        self.lock_state().lock_type
     }
 
-    pub fn upgrade(&self, to_type: LockType, auto_upgrade: bool, try_only: bool) -> LockResult<()> {
-        self.lock_state()
-            .and_then(|mut state| {
-                self.kernel.upgrade(state.lock_type, to_type, self.parent.clone(), auto_upgrade, try_only)?;
-                state.lock_type = to_type;
-                Ok(())
-            })
+    pub fn parent(&self) -> Option<Arc<LockInstance<I>>> {
+        self.parent.clone()
+    }
+
+    pub fn parent_upgrade(&self) -> Option<ParentUpgrade> {
+        self.parent_upgrade
+    }
+
+    #[cfg(feature = "timestamps")]
+    pub fn acquired_at(&self) -> Instant {
+        self.acquired_at
+    }
+
+    #[cfg(any(test, feature = "testing"))]
+    pub fn count_contribution(&self) -> [usize; LOCK_TYPE_COUNT] {
+        let mut counts = LOCK_EMPTY_COUNTS;
+        counts[self.lock_type().index()] += 1;
+        counts
+    }
+
+    pub fn belongs_to(&self, kernel: &LockKernelRc<I>) -> bool {
+        self.kernel.ptr_eq(kernel)
+    }
+
+    // Attributes the lock (and its implicit parent locks) to the current thread, e.g. when a
+    // `LockToken` is converted back into a guard.
+    pub fn attach(&self) {
+        self.transfer_to(Some(thread::current().id()));
+    }
+
+    // Stops attributing the lock (and its implicit parent locks) to any thread, when it is no longer
+    // held by a guard of its owner thread, e.g. when it is converted into a `LockToken`, which can
+    // be sent to another thread.
+    pub fn detach(&self) {
+        self.transfer_to(None);
+    }
+
+    fn transfer_to(&self, to: Owner) {
+        {
+            let mut state = self.lock_state();
+
+            if state.owner != to {
+                self.kernel.transfer(state.lock_type, state.owner, to, state.epoch);
+                state.owner = to;
+            }
+        }
+
+        match self.parent.as_ref() {
+            Some(parent) if self.implicit_parent => parent.transfer_to(to),
+            _ => {},
+        }
+    }
+
+    pub fn downgrade(&self, to_type: LockType) -> LockResult<()> {
+        let mut state = self.lock_state();
+        let from_type = state.lock_type;
+
+        if !from_type.downgradable_to(to_type) || !state.supports_child_types(to_type) {
+            return Err(LockError::InvalidDowngrade { original: from_type, requested: to_type });
+        }
+
+        state.requested_type = to_type;
+        self.change_type(&mut state, to_type);
+        Ok(())
+    }
+
+    pub fn upgrade(&self, to_type: LockType, auto_upgrade: bool, wait: Wait) -> LockResult<()> {
+        let mut state = self.lock_state();
+
+        self.kernel.upgrade(state.lock_type, to_type, state.owner, self.parent.clone(), auto_upgrade, wait)?;
+        state.lock_type = to_type;
+        state.requested_type = to_type;
+
+        #[cfg(feature = "tracing")]
+        self.span.record("lock_type", ::tracing::field::display(to_type));
+
+        Ok(())
+    }
+
+    // Releases the lock held by this instance, but keeps its parent lock. The instance must not be
+    // shared (i.e. no child lock or `ParentGuard` may use it as a parent lock) and must be resumed
+    // before it is used again.
+    pub fn suspend(self: &Arc<Self>) -> LockResult<()> {
+        if Arc::strong_count(self) > 1 { return Err(LockError::ChildLocksHeld); }
+
+        let mut state = self.lock_state();
+        self.kernel.suspend(state.lock_type, state.owner, state.epoch);
+        state.suspended = true;
+        Ok(())
+    }
+
+    pub fn resume(&self) -> LockResult<()> {
+        let mut state = self.lock_state();
+
+        if let Some(parent) = self.parent.as_ref() {
+            let parent_lock_type = parent.lock_type();
+
+            if !parent_lock_type.supports_children(state.lock_type) {
+                return Err(LockError::InvalidParentLockType { required: state.lock_type.implicit_parent_type(), actual: parent_lock_type });
+            }
+        }
+
+        let (owner, epoch) = self.kernel.resume(state.lock_type, &self.parent)?;
+        state.owner = owner;
+        state.epoch = epoch;
+        state.suspended = false;
+        Ok(())
+    }
+
+    // Upgrades the lock on behalf of a child lock. Unlike `upgrade()`, the requested type is kept,
+    // so that the lock can be relaxed back to it once the child lock no longer needs the upgrade.
+    fn escalate(&self, to_type: LockType, wait: Wait) -> LockResult<()> {
+        let mut state = self.lock_state();
+
+        self.kernel.upgrade(state.lock_type, to_type, state.owner, self.parent.clone(), true, wait)?;
+        state.lock_type = to_type;
+
+        #[cfg(feature = "tracing")]
+        self.span.record("lock_type", ::tracing::field::display(to_type));
+
+        Ok(())
+    }
+
+    // An implicit parent lock is acquired on behalf of a child lock, but it may be shared by more
+    // child locks later (see `GLockGuard::try_acquire_additional()` and `ParentGuard`), which may
+    // escalate it, e.g. from `IntentionShared` to `IntentionExclusive`. It is never requested for
+    // itself, so the `child_types` accounting alone decides how far it can be relaxed: down to
+    // whatever the child locks still using it require.
+    fn held_for_child(&self, lock_type: LockType) {
+        let mut state = self.lock_state();
+
+        state.requested_type = LockType::IntentionShared;
+        state.child_types[lock_type.index()] += 1;
+    }
+
+    // Records a child lock acquired using this instance as its parent lock (`from` is `None`), a
+    // change of its type, or its release (`to` is `None`). A change or release may leave this
+    // lock more restrictive than needed, in which case it is relaxed.
+    fn child_type_changed(&self, from: Option<LockType>, to: Option<LockType>) {
+        let mut state = self.lock_state();
+
+        if let Some(to) = to { state.child_types[to.index()] += 1; }
+
+        if let Some(from) = from {
+            state.child_types[from.index()] -= 1;
+            self.relax(&mut state);
+        }
+    }
+
+    // Downgrades the lock to the least restrictive type that is at least as restrictive as the
+    // requested type and supports all the child locks held using it, e.g. back from
+    // `IntentionExclusive` to `IntentionShared` after its last `Exclusive` child lock is released.
+    fn relax(&self, state: &mut LockInstanceState) {
+        let mut relaxed_type = state.requested_type;
+
+        for lt in LockType::lock_types().iter() {
+            if state.child_types[lt.index()] > 0 && !relaxed_type.supports_children(*lt) {
+                relaxed_type = relaxed_type.min_upgradable(lt.implicit_parent_type());
+            }
+        }
+
+        if relaxed_type == state.lock_type || !state.lock_type.downgradable_to(relaxed_type) { return; }
+
+        self.change_type(state, relaxed_type);
+    }
+
+    // Lowers the type of the lock without waiting, since a less restrictive lock never conflicts
+    // with locks that were compatible with the original one. The parent lock is relaxed as well if
+    // it no longer needs to support the original type.
+    fn change_type(&self, state: &mut LockInstanceState, to_type: LockType) {
+        let from_type = state.lock_type;
+        if from_type == to_type { return; }
+
+        self.kernel.downgrade(from_type, to_type, state.owner, state.epoch);
+        state.lock_type = to_type;
+
+        #[cfg(feature = "tracing")]
+        self.span.record("lock_type", ::tracing::field::display(to_type));
+
+        if let Some(parent) = self.parent.as_ref() {
+            parent.child_type_changed(Some(from_type), Some(to_type));
+        }
+    }
+}
+
+impl LockInstanceState {
+
+    fn supports_child_types(&self, lock_type: LockType) -> bool {
+        LockType::lock_types()
+            .iter()
+            .all(|lt| self.child_types[lt.index()] == 0 || lock_type.supports_children(*lt))
     }
 }
 
-impl Drop for LockInstance {
+impl<I: LockId> Drop for LockInstance<I> {
     fn drop(&mut self) {
-        self.lock_state()
-            .and_then(|state| self.kernel.release(state.lock_type))
-            .unwrap();
+        let (lock_type, owner, epoch, suspended) = {
+            let state = self.lock_state();
+
+            (state.lock_type, state.owner, state.epoch, state.suspended)
+        };
+
+        if suspended {
+            self.kernel.forget_suspended();
+        } else {
+            self.kernel.release(lock_type, owner, epoch, thread::panicking());
+        }
+
+        if let Some(parent) = self.parent.as_ref() {
+            parent.child_type_changed(Some(lock_type), None);
+        }
     }
 }
 
-#[cfg(test)]
+// These tests use `std` threads, so they cannot run under `loom`.
+#[cfg(all(test, not(loom)))]
 mod test {
     use super::*;
 
     #[test]
     fn clone_clone_weak() {
-        let k = LockKernelRc::new(LockKernel::new(None, None));
+        let k = LockKernelRc::<Id>::new(LockKernel::new(None, None));
 
         assert_eq!(Arc::strong_count(&k.kernel), 1);
         assert_eq!(Arc::weak_count(&k.kernel), 0);
@@ -310,7 +1624,7 @@ mod test {
         assert_eq!(Arc::weak_count(&k.kernel), 1);
 
         {
-            let k_child = k.new_child().unwrap();
+            let k_child = k.new_auto_child().unwrap();
 
             assert_eq!(Arc::strong_count(&k.kernel), 3);
             assert_eq!(Arc::weak_count(&k.kernel), 1);
@@ -328,15 +1642,15 @@ mod test {
         for t1 in LockType::lock_types().iter() {
             for t2 in LockType::lock_types().iter() {
                 let should_succeed = t1.compatible_with(*t2);
-                let k = LockKernelRc::new(LockKernel::new(None, None));
+                let k = LockKernelRc::<Id>::new(LockKernel::new(None, None));
 
                 {
-                    let _t1_lock = k.acquire(*t1, None, true, true).unwrap();
-                    assert_eq!(k.acquire(*t2, None, true, true).is_ok(), should_succeed);
+                    let _t1_lock = k.acquire(*t1, None, true, Wait::TryOnly, 0).unwrap();
+                    assert_eq!(k.acquire(*t2, None, true, Wait::TryOnly, 0).is_ok(), should_succeed);
                 }
 
                 if !should_succeed {
-                    assert_eq!(k.acquire(*t2, None, true, true).is_ok(), true);
+                    assert_eq!(k.acquire(*t2, None, true, Wait::TryOnly, 0).is_ok(), true);
                 }
             }
         }
@@ -347,16 +1661,16 @@ mod test {
         for t1 in LockType::lock_types().iter() {
             for t2 in LockType::lock_types().iter() {
                 let should_succeed = t1.implicit_parent_type().compatible_with(*t2);
-                let k = LockKernelRc::new(LockKernel::new(None, None));
-                let k1 = k.new_child().unwrap();
+                let k = LockKernelRc::<Id>::new(LockKernel::new(None, None));
+                let k1 = k.new_auto_child().unwrap();
 
                 {
-                    let _t1_lock = k1.acquire(*t1, None, true, true).unwrap();
-                    assert_eq!(k.acquire(*t2, None, true, true).is_ok(), should_succeed);
+                    let _t1_lock = k1.acquire(*t1, None, true, Wait::TryOnly, 0).unwrap();
+                    assert_eq!(k.acquire(*t2, None, true, Wait::TryOnly, 0).is_ok(), should_succeed);
                 }
 
                 if !should_succeed {
-                    assert_eq!(k.acquire(*t2, None, true, true).is_ok(), true);
+                    assert_eq!(k.acquire(*t2, None, true, Wait::TryOnly, 0).is_ok(), true);
                 }
             }
         }
@@ -368,14 +1682,14 @@ mod test {
             for t1a in LockType::lock_types().iter() {
                 for t1b in LockType::lock_types().iter() {
                     for t2 in LockType::lock_types().iter() {
-                        let k = LockKernelRc::new(LockKernel::new(None, None));
-                        let k1 = k.new_child().unwrap();
-                        let k2 = k.new_child().unwrap();
-
-                        let p_lock = k.acquire(*parent_type, None, true, true).unwrap();
-                        let _l1a = k1.acquire(*t1a, Some(p_lock.clone()), true, true).unwrap();
-                        assert_eq!(k1.acquire(*t1b, Some(p_lock.clone()), true, true).is_ok(), t1a.compatible_with(*t1b));
-                        assert_eq!(k2.acquire(*t2, Some(p_lock.clone()), true, true).is_ok(), true);
+                        let k = LockKernelRc::<Id>::new(LockKernel::new(None, None));
+                        let k1 = k.new_auto_child().unwrap();
+                        let k2 = k.new_auto_child().unwrap();
+
+                        let p_lock = k.acquire(*parent_type, None, true, Wait::TryOnly, 0).unwrap();
+                        let _l1a = k1.acquire(*t1a, Some(p_lock.clone()), true, Wait::TryOnly, 0).unwrap();
+                        assert_eq!(k1.acquire(*t1b, Some(p_lock.clone()), true, Wait::TryOnly, 0).is_ok(), t1a.compatible_with(*t1b));
+                        assert_eq!(k2.acquire(*t2, Some(p_lock.clone()), true, Wait::TryOnly, 0).is_ok(), true);
                     }
                 }
             }
@@ -387,20 +1701,20 @@ mod test {
         for initial_type in LockType::lock_types().iter() {
             for upgrade_type in LockType::lock_types().iter() {
                 let should_upgrade_succeed = initial_type.upgradable_to(*upgrade_type);
-                let k = LockKernelRc::new(LockKernel::new(None, None));
+                let k = LockKernelRc::<Id>::new(LockKernel::new(None, None));
 
-                let l1 = k.acquire(*initial_type, None, true, true).unwrap();
+                let l1 = k.acquire(*initial_type, None, true, Wait::TryOnly, 0).unwrap();
 
                 for other_type in LockType::lock_types().iter() {
-                    assert_eq!(k.acquire(*other_type, None, true, true).is_ok(), initial_type.compatible_with(*other_type));
+                    assert_eq!(k.acquire(*other_type, None, true, Wait::TryOnly, 0).is_ok(), initial_type.compatible_with(*other_type));
                 }
 
-                match l1.upgrade(*upgrade_type, true, true) {
+                match l1.upgrade(*upgrade_type, true, Wait::TryOnly) {
                     Ok(()) => {
                         assert_eq!(should_upgrade_succeed, true);
 
                         for other_type in LockType::lock_types().iter() {
-                            assert_eq!(k.acquire(*other_type, None, true, true).is_ok(), upgrade_type.compatible_with(*other_type));
+                            assert_eq!(k.acquire(*other_type, None, true, Wait::TryOnly, 0).is_ok(), upgrade_type.compatible_with(*other_type));
                         }
                     },
 
@@ -417,21 +1731,21 @@ mod test {
         for initial_type in LockType::lock_types().iter() {
             for upgrade_type in LockType::lock_types().iter() {
                 let should_upgrade_succeed = initial_type.upgradable_to(*upgrade_type);
-                let k = LockKernelRc::new(LockKernel::new(None, None));
-                let k1 = k.new_child().unwrap();
+                let k = LockKernelRc::<Id>::new(LockKernel::new(None, None));
+                let k1 = k.new_auto_child().unwrap();
 
-                let l1 = k1.acquire(*initial_type, None, true, true).unwrap();
+                let l1 = k1.acquire(*initial_type, None, true, Wait::TryOnly, 0).unwrap();
 
                 for other_type in LockType::lock_types().iter() {
-                    assert_eq!(k.acquire(*other_type, None, true, true).is_ok(), initial_type.implicit_parent_type().compatible_with(*other_type));
+                    assert_eq!(k.acquire(*other_type, None, true, Wait::TryOnly, 0).is_ok(), initial_type.implicit_parent_type().compatible_with(*other_type));
                 }
 
-                match l1.upgrade(*upgrade_type, true, true) {
+                match l1.upgrade(*upgrade_type, true, Wait::TryOnly) {
                     Ok(()) => {
                         assert_eq!(should_upgrade_succeed, true);
 
                         for other_type in LockType::lock_types().iter() {
-                            assert_eq!(k.acquire(*other_type, None, true, true).is_ok(), upgrade_type.implicit_parent_type().compatible_with(*other_type));
+                            assert_eq!(k.acquire(*other_type, None, true, Wait::TryOnly, 0).is_ok(), upgrade_type.implicit_parent_type().compatible_with(*other_type));
                         }
                     },
 
@@ -442,4 +1756,398 @@ mod test {
             }
         }
     }
+
+    #[test]
+    fn no_lost_wakeup_after_parent_acquire() {
+        use std::thread;
+        use std::sync::mpsc::channel;
+        use std::time::Duration;
+
+        let k = LockKernelRc::<Id>::new(LockKernel::new(None, None));
+        let k1 = k.new_auto_child().unwrap();
+
+        for i in 0..200 {
+            let holder = k1.acquire(LockType::Exclusive, None, true, Wait::Block, 0).unwrap();
+            let (sender, receiver) = channel();
+
+            let waiter = {
+                let k1 = k1.clone();
+
+                thread::spawn(move || {
+                    let _l = k1.acquire(LockType::Exclusive, None, true, Wait::Block, 0).unwrap();
+                    sender.send(()).unwrap();
+                })
+            };
+
+            if i % 2 == 0 { thread::yield_now(); }
+            drop(holder);
+
+            assert_eq!(receiver.recv_timeout(Duration::from_secs(10)).is_ok(), true);
+            waiter.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn acquire_explicit_parent_upgrades_only_when_needed() {
+        for parent_type in LockType::lock_types().iter() {
+            for child_type in LockType::lock_types().iter() {
+                let k = LockKernelRc::<Id>::new(LockKernel::new(None, None));
+                let k1 = k.new_auto_child().unwrap();
+
+                let p_lock = k.acquire(*parent_type, None, true, Wait::TryOnly, 0).unwrap();
+                let _c_lock = k1.acquire(*child_type, Some(p_lock.clone()), true, Wait::TryOnly, 0).unwrap();
+
+                let expected_parent_type = if parent_type.supports_children(*child_type) {
+                    *parent_type
+                } else {
+                    parent_type.min_upgradable(child_type.implicit_parent_type())
+                };
+
+                assert_eq!(p_lock.lock_type(), expected_parent_type);
+            }
+        }
+
+        let k = LockKernelRc::<Id>::new(LockKernel::new(None, None));
+        let k1 = k.new_auto_child().unwrap();
+
+        let p_lock = k.acquire(LockType::IntentionExclusive, None, true, Wait::TryOnly, 0).unwrap();
+        let _c_lock = k1.acquire(LockType::Shared, Some(p_lock.clone()), true, Wait::TryOnly, 0).unwrap();
+
+        assert_eq!(p_lock.lock_type(), LockType::IntentionExclusive);
+    }
+
+    #[test]
+    fn shrink_to_fit() {
+        let k = LockKernelRc::<Id>::new(LockKernel::new(None, None));
+        let children: Vec<_> = (0..100).map(|_| k.new_auto_child().unwrap()).collect();
+        let kept = k.new_auto_child().unwrap();
+
+        let capacity = k.lock_state().children.capacity();
+        drop(children);
+
+        k.shrink_to_fit();
+
+        let state = k.lock_state();
+        assert_eq!(state.children.len(), 1);
+        assert_eq!(state.children.contains_key(&kept.id().unwrap()), true);
+        assert_eq!(state.children.capacity() < capacity, true);
+    }
+
+    #[test]
+    fn debug_does_not_block() {
+        let k = LockKernelRc::<Id>::new(LockKernel::new(None, None));
+        let k_child = k.new_auto_child().unwrap();
+
+        let _lock = k_child.acquire(LockType::Exclusive, None, true, Wait::TryOnly, 0).unwrap();
+        assert_eq!(format!("{:?}", k_child).contains("<locked>"), false);
+
+        let _state = k.lock_state();
+        let debug = format!("{:?}", k_child);
+
+        assert_eq!(debug.contains("id: Some(0)"), true);
+        assert_eq!(debug.contains("<locked>"), true);
+    }
+
+    #[test]
+    fn wait_survives_panic_holding_state() {
+        let k = LockKernelRc::<Id>::new(LockKernel::new(None, None));
+        let holder = k.acquire(LockType::Exclusive, None, true, Wait::TryOnly, 0).unwrap();
+
+        let waiter = {
+            let k = k.clone();
+            thread::spawn(move || k.acquire(LockType::Exclusive, None, true, Wait::Block, 0).map(|_| ()))
+        };
+
+        while k.waiters() == 0 { thread::sleep(Duration::from_millis(1)); }
+
+        let poisoner = {
+            let k = k.clone();
+
+            thread::spawn(move || {
+                let _state = k.lock_state();
+                panic!("panicking while holding the state mutex");
+            })
+        };
+
+        assert_eq!(poisoner.join().is_err(), true);
+
+        drop(holder);
+
+        assert_eq!(waiter.join().unwrap(), Ok(()));
+        assert_eq!(k.waiters(), 0);
+    }
+
+    #[test]
+    fn children_counter_overflow() {
+        let k = LockKernelRc::<Id>::new(LockKernel::new(None, None));
+        k.lock_state().children_counter = Some(Id::MAX - 2);
+
+        let k1 = k.new_child(Id::MAX - 1).unwrap();
+        let k2 = k.new_auto_child().unwrap();
+        assert_eq!(k2.id(), Some(Id::MAX - 2));
+
+        let k3 = k.new_auto_child().unwrap();
+        assert_eq!(k3.id(), Some(Id::MAX));
+
+        assert_eq!(k.new_auto_child().err(), Some(LockError::IdExhausted));
+
+        drop(k1);
+        drop(k2);
+        drop(k3);
+        assert_eq!(k.new_auto_child().err(), Some(LockError::IdExhausted));
+        assert_eq!(k.new_child(0).is_ok(), true);
+    }
+
+    #[test]
+    fn seq() {
+        let k1 = LockKernelRc::<Id>::new(LockKernel::new(None, None));
+        let k2 = LockKernelRc::<Id>::new(LockKernel::new(None, None));
+
+        let k1_child = k1.new_auto_child().unwrap();
+        let k2_child = k2.new_auto_child().unwrap();
+        assert_eq!(k1_child.id(), k2_child.id());
+
+        let mut seqs = vec![k1.seq(), k2.seq(), k1_child.seq(), k2_child.seq()];
+        assert_eq!(k1.seq() < k2.seq() && k2.seq() < k1_child.seq() && k1_child.seq() < k2_child.seq(), true);
+
+        seqs.dedup();
+        assert_eq!(seqs.len(), 4);
+    }
+
+    #[test]
+    fn verify_invariants() {
+        let k = LockKernelRc::<Id>::new(LockKernel::new(None, None));
+        let c1 = k.new_auto_child().unwrap();
+        let c2 = k.new_auto_child().unwrap();
+        assert_eq!(k.verify_invariants(), Ok(()));
+
+        let l1 = c1.acquire(LockType::Shared, None, true, Wait::TryOnly, 0).unwrap();
+        let l2 = c2.acquire(LockType::Exclusive, None, true, Wait::TryOnly, 0).unwrap();
+        l1.upgrade(LockType::SharedIntentionExclusive, true, Wait::TryOnly).unwrap();
+        l2.downgrade(LockType::IntentionExclusive).unwrap();
+
+        for kernel in [&k, &c1, &c2].iter() {
+            assert_eq!(kernel.verify_invariants(), Ok(()));
+        }
+
+        drop(l1);
+        drop(c2);
+        assert_eq!(k.verify_invariants(), Ok(()));
+
+        k.lock_state().counts[LockType::Exclusive.index()] += 1;
+        assert_eq!(matches!(k.verify_invariants(), Err(LockError::UnknownError { .. })), true);
+
+        {
+            let mut state = k.lock_state();
+            state.counts[LockType::Exclusive.index()] -= 1;
+            state.add_holder(Some(thread::current().id()), LockType::Exclusive);
+        }
+
+        assert_eq!(matches!(k.verify_invariants(), Err(LockError::UnknownError { .. })), true);
+        k.lock_state().remove_holder(Some(thread::current().id()), LockType::Exclusive);
+        assert_eq!(k.verify_invariants(), Ok(()));
+
+        drop(l2);
+        assert_eq!(k.verify_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn upgrade_to_lower_type() {
+        for initial_type in LockType::lock_types().iter() {
+            for upgrade_type in LockType::lock_types().iter() {
+                let k = LockKernelRc::<Id>::new(LockKernel::new(None, None));
+                let l = k.acquire(*initial_type, None, true, Wait::TryOnly, 0).unwrap();
+
+                let expected = if initial_type == upgrade_type {
+                    Ok(())
+                } else if initial_type.downgradable_to(*upgrade_type) {
+                    Err(LockError::UpgradeToLowerType { original: *initial_type, requested: *upgrade_type })
+                } else if initial_type.upgradable_to(*upgrade_type) {
+                    Ok(())
+                } else {
+                    Err(LockError::InvalidUpgrade { original: *initial_type, requested: *upgrade_type })
+                };
+
+                assert_eq!(l.upgrade(*upgrade_type, true, Wait::TryOnly), expected);
+            }
+        }
+
+        let k = LockKernelRc::<Id>::new(LockKernel::new(None, None));
+        let l = k.acquire(LockType::Exclusive, None, true, Wait::TryOnly, 0).unwrap();
+        let err = l.upgrade(LockType::Shared, true, Wait::TryOnly).unwrap_err();
+
+        assert_eq!(format!("{}", err), "Lock of type Exclusive cannot be upgraded to less restrictive type Shared; use downgrade() instead");
+        assert_eq!(l.lock_type(), LockType::Exclusive);
+    }
+}
+
+// Model tests exploring all interleavings of the kernel's state mutex and condvars. Run them using
+// `RUSTFLAGS="--cfg loom" cargo test --release loom_test`.
+#[cfg(all(test, loom))]
+mod loom_test {
+    use super::*;
+    use loom::sync::atomic::AtomicUsize;
+
+    fn root() -> LockKernelRc<Id> {
+        LockKernelRc::new(LockKernel::new(None, None))
+    }
+
+    #[test]
+    fn exclusive_locks_exclude_each_other() {
+        loom::model(|| {
+            let k = root();
+            let inside = Arc::new(AtomicUsize::new(0));
+
+            let threads: Vec<_> = (0..2).map(|_| {
+                let k = k.clone();
+                let inside = inside.clone();
+
+                thread::spawn(move || {
+                    let _lock = k.acquire(LockType::Exclusive, None, true, Wait::Block, 0).unwrap();
+                    assert_eq!(inside.fetch_add(1, Ordering::SeqCst), 0);
+                    inside.fetch_sub(1, Ordering::SeqCst);
+                })
+            }).collect();
+
+            for t in threads { t.join().unwrap(); }
+            assert_eq!(k.counts(), LOCK_EMPTY_COUNTS);
+        });
+    }
+
+    #[test]
+    fn release_wakes_up_waiter() {
+        loom::model(|| {
+            let k = root();
+            let lock = k.acquire(LockType::Shared, None, true, Wait::Block, 0).unwrap();
+
+            let t = {
+                let k = k.clone();
+                thread::spawn(move || { k.acquire(LockType::Exclusive, None, true, Wait::Block, 0).map(|_| ()) })
+            };
+
+            drop(lock);
+            assert_eq!(t.join().unwrap(), Ok(()));
+            assert_eq!(k.counts(), LOCK_EMPTY_COUNTS);
+        });
+    }
+
+    #[test]
+    fn child_locks_with_implicit_parent() {
+        loom::model(|| {
+            let k = root();
+            let k_child = k.new_auto_child().unwrap();
+
+            let t = {
+                let k_child = k_child.clone();
+                thread::spawn(move || { k_child.acquire(LockType::Exclusive, None, true, Wait::Block, 0).map(|_| ()) })
+            };
+
+            let lock = k.acquire(LockType::Shared, None, true, Wait::Block, 0).unwrap();
+            drop(lock);
+
+            assert_eq!(t.join().unwrap(), Ok(()));
+            assert_eq!(k.counts(), LOCK_EMPTY_COUNTS);
+            assert_eq!(k_child.counts(), LOCK_EMPTY_COUNTS);
+        });
+    }
+
+    #[test]
+    fn upgrade_waits_for_readers() {
+        loom::model(|| {
+            let k = root();
+            let reader = k.acquire(LockType::Shared, None, true, Wait::Block, 0).unwrap();
+
+            let t = {
+                let k = k.clone();
+
+                thread::spawn(move || {
+                    let lock = k.acquire(LockType::Shared, None, true, Wait::Block, 0).unwrap();
+                    lock.upgrade(LockType::Exclusive, true, Wait::Block)
+                })
+            };
+
+            drop(reader);
+            assert_eq!(t.join().unwrap(), Ok(()));
+            assert_eq!(k.counts(), LOCK_EMPTY_COUNTS);
+        });
+    }
+
+    #[test]
+    fn concurrent_upgrades_do_not_deadlock() {
+        loom::model(|| {
+            let k = root();
+
+            let threads: Vec<_> = (0..2).map(|_| {
+                let k = k.clone();
+
+                thread::spawn(move || {
+                    let lock = k.acquire(LockType::Shared, None, true, Wait::Block, 0).unwrap();
+                    lock.upgrade(LockType::Exclusive, true, Wait::Block)
+                })
+            }).collect();
+
+            for t in threads {
+                match t.join().unwrap() {
+                    Ok(()) | Err(LockError::ConflictingUpgrade) => {},
+                    Err(err) => panic!("unexpected error: {:?}", err),
+                }
+            }
+
+            assert_eq!(k.counts(), LOCK_EMPTY_COUNTS);
+        });
+    }
+
+    #[test]
+    fn downgrade_wakes_up_compatible_waiter() {
+        loom::model(|| {
+            let k = root();
+            let lock = k.acquire(LockType::Exclusive, None, true, Wait::Block, 0).unwrap();
+
+            let t = {
+                let k = k.clone();
+                thread::spawn(move || { k.acquire(LockType::Shared, None, true, Wait::Block, 0).map(|_| ()) })
+            };
+
+            lock.downgrade(LockType::Shared).unwrap();
+            assert_eq!(t.join().unwrap(), Ok(()));
+
+            drop(lock);
+            assert_eq!(k.counts(), LOCK_EMPTY_COUNTS);
+        });
+    }
+
+    #[test]
+    fn release_wakes_up_idle_waiter() {
+        loom::model(|| {
+            let k = root();
+            let lock = k.acquire(LockType::IntentionShared, None, true, Wait::Block, 0).unwrap();
+
+            let t = {
+                let k = k.clone();
+                thread::spawn(move || { k.wait_idle(Wait::Block) })
+            };
+
+            drop(lock);
+            assert_eq!(t.join().unwrap(), Ok(()));
+        });
+    }
+
+    #[test]
+    fn last_release_wakes_up_waiter() {
+        loom::model(|| {
+            let k = root();
+            let lock1 = k.acquire(LockType::Shared, None, true, Wait::Block, 0).unwrap();
+            let lock2 = k.acquire(LockType::IntentionShared, None, true, Wait::Block, 0).unwrap();
+
+            let t = {
+                let k = k.clone();
+                thread::spawn(move || { k.acquire(LockType::Exclusive, None, true, Wait::Block, 0).map(|_| ()) })
+            };
+
+            drop(lock1);
+            drop(lock2);
+            assert_eq!(t.join().unwrap(), Ok(()));
+            assert_eq!(k.counts(), LOCK_EMPTY_COUNTS);
+        });
+    }
 }
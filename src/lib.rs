@@ -5,17 +5,52 @@
 //!
 //! `git clone https://github.com/aymanmadkour/glock`
 //!
+#![cfg_attr(test, allow(clippy::bool_assert_comparison))]
+
+#[cfg(feature = "tracing")]
+extern crate tracing;
+
+#[cfg(feature = "log")]
+extern crate log;
+
+#[cfg(loom)]
+extern crate loom;
+
+#[macro_use]
+mod macros;
+
 mod common;
 mod locktype;
 mod lock;
 mod kernel;
+mod sync;
 
 pub use self::common::LockError;
 pub use self::common::LockResult;
+pub use self::common::PoisonPolicy;
+pub use self::common::LockStats;
 
 pub use self::locktype::LockType;
+pub use self::locktype::ParentUpgrade;
+
+pub use self::kernel::{ Id, LockId };
 
 pub use self::lock::GLock;
 pub use self::lock::GLockBuilder;
+pub use self::lock::SharedGLock;
+pub use self::lock::GLockMap;
+pub use self::lock::LockSpec;
+pub use self::lock::LockNode;
 pub use self::lock::GLockGuard;
 pub use self::lock::GLockGuardMut;
+pub use self::lock::GLockGuardRef;
+pub use self::lock::ExclusiveOrShared;
+pub use self::lock::LockToken;
+pub use self::lock::Transaction;
+pub use self::lock::ParentGuard;
+pub use self::lock::AsParentGuard;
+pub use self::lock::GLockProjection;
+pub use self::lock::GLockProjectionMut;
+pub use self::lock::GLockProjectionGuard;
+pub use self::lock::GLockProjectionGuardMut;
+pub use self::lock::lock_all_exclusive;
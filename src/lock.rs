@@ -1,5 +1,18 @@
+use std::borrow::Borrow;
+use std::cell::UnsafeCell;
+use std::collections::HashMap;
+use std::collections::hash_map::{ Entry, Iter, Keys };
+use std::fmt::{ Debug, Formatter, Error as FmtError };
+use std::hash::Hash;
+use std::marker::PhantomData;
+use std::mem::{ self, ManuallyDrop };
 use std::ops::{ Deref, DerefMut };
+use std::pin::Pin;
+use std::ptr;
 use std::sync::Arc;
+use std::time::Duration;
+#[cfg(feature = "timestamps")]
+use std::time::Instant;
 
 use self::super::common::*;
 use self::super::locktype::*;
@@ -33,23 +46,35 @@ use self::super::kernel::*;
 ///
 ///     parent_lock_builder.build(parent).unwrap()
 /// };
+///
+/// // A root `GLock` without nested children does not need the builder ceremony.
+/// let simple_lock = GLockBuilder::root(0u32).unwrap();
 /// ```
-pub struct GLockBuilder {
-    kernel: LockKernelRc,
+pub struct GLockBuilder<I: LockId = Id> {
+    kernel: LockKernelRc<I>,
+    options: LockOptions<I>,
 }
 
 impl GLockBuilder {
 
     /// Creates a new root `GLock` builder
     pub fn new_root_builder() -> GLockBuilder {
-        GLockBuilder { kernel: LockKernelRc::new(LockKernel::new(None, None)) }
+        GLockBuilder::new_root_builder_with_id_type()
+    }
+
+    /// Creates a new root `GLock` protecting the specified data, using the default options. This
+    /// is a shorthand for `GLockBuilder::new_root_builder().build(data)`.
+    pub fn root<T>(data: T) -> LockResult<GLock<T>> {
+        GLockBuilder::new_root_builder().build(data)
     }
 
-    /// Creates a builder for a `GLock` that is a child of the current `GLock`.
+    /// Creates a builder for a `GLock` that is a child of the current `GLock`. The child is
+    /// assigned the next unused id of the current `GLock`, and inherits its policies (see
+    /// `new_child_builder_with_id()`).
     pub fn new_child_builder(&self) -> LockResult<GLockBuilder> {
         self.kernel
-            .new_child()
-            .map(|child_kernel| GLockBuilder { kernel: child_kernel })
+            .new_auto_child()
+            .map(|kernel| GLockBuilder::new(kernel, self.options.inherited()))
     }
 
     /// Creates a new `Glock` that is a child of the current `GLock` and protects the specified.
@@ -57,13 +82,137 @@ impl GLockBuilder {
         self.new_child_builder().and_then(|cb| cb.build(data))
     }
 
+    /// Creates an empty `GLockMap` whose child `GLock`s are children of the `GLock` being built.
+    /// This allows a map of child `GLock`s to be placed inside the protected data. See
+    /// `GLock::new_child_map()`.
+    pub fn new_child_map<K: Eq + Hash, V>(&self) -> GLockMap<K, V> {
+        GLockMap::new(self.kernel.clone())
+    }
+
+    /// Builds a tree of `GLock`s described by the specified `LockSpec`, in a single call. The
+    /// `GLock` being built protects the root `LockNode` of the tree, and a child `GLock` is built
+    /// for each child `LockSpec` (recursively, using `new_child_builder()`), inheriting the
+    /// policies of its parent.
+    pub fn build_tree<T>(self, spec: LockSpec<T>) -> LockResult<GLock<LockNode<T>>> {
+        let LockSpec { data, children } = spec;
+
+        let children = children
+            .into_iter()
+            .map(|child| self.new_child_builder().and_then(|cb| cb.build_tree(child)))
+            .collect::<LockResult<Vec<_>>>()?;
+
+        self.build(LockNode { data, children })
+    }
+}
+
+impl<I: LockId> GLockBuilder<I> {
+
+    fn new(kernel: LockKernelRc<I>, options: LockOptions<I>) -> GLockBuilder<I> {
+        GLockBuilder { kernel, options }
+    }
+
+    /// Creates a new root `GLock` builder whose descendants are identified by ids of type `I`,
+    /// which are supplied by the caller using `new_child_builder_with_id()`. Root `GLock`s do not
+    /// have an id.
+    pub fn new_root_builder_with_id_type() -> GLockBuilder<I> {
+        GLockBuilder::new(LockKernelRc::new(LockKernel::new(None, None)), LockOptions::default())
+    }
+
+    /// Creates a builder for a `GLock` that is a child of the current `GLock`, identified by the
+    /// specified id. If another live child of the current `GLock` has the same id, it will return
+    /// a `LockError::DuplicateId` error.
+    ///
+    /// The child builder inherits the policies set on this builder so far, i.e. its
+    /// `poison_policy()` and `default_timeout()`, which can be overridden by calling the same
+    /// methods on the child builder. Per-lock settings such as `require_explicit_parent()` and
+    /// `intended_lock_type()` are not inherited.
+    pub fn new_child_builder_with_id(&self, id: I) -> LockResult<GLockBuilder<I>> {
+        self.kernel
+            .new_child(id)
+            .map(|kernel| GLockBuilder::new(kernel, self.options.inherited()))
+    }
+
+    /// Creates a new `GLock` that is a child of the current `GLock`, identified by the specified
+    /// id and protecting the specified data. See `new_child_builder_with_id()`.
+    pub fn new_child_with_id<T>(&self, id: I, data: T) -> LockResult<GLock<T, I>> {
+        self.new_child_builder_with_id(id).and_then(|cb| cb.build(data))
+    }
+
+    /// Sets the `PoisonPolicy` of the `GLock` being built, which determines what happens when one
+    /// of its `GLockGuard`s is dropped while panicking. Defaults to the policy of the parent `GLock`
+    /// for child `GLock`s, and to `PoisonPolicy::BestEffort` for root `GLock`s.
+    pub fn poison_policy(mut self, poison_policy: PoisonPolicy) -> GLockBuilder<I> {
+        self.options.poison_policy = poison_policy;
+        self
+    }
+
+    /// Sets a default timeout for all blocking lock acquisitions and upgrades on the `GLock` being
+    /// built (e.g. `lock()`, `lock_exclusive()` and `GLockGuard::upgrade()`). If a lock cannot be
+    /// acquired before the timeout expires, a `LockError::Timeout` error is returned. The timeout
+    /// covers the implicit locks acquired on the ancestors of the `GLock` as well.
+    ///
+    /// Methods taking an explicit timeout (e.g. `lock_timeout()`) use their own timeout instead.
+    pub fn default_timeout(mut self, timeout: Duration) -> GLockBuilder<I> {
+        self.options.default_timeout = Some(timeout);
+        self
+    }
+
+    /// Removes the default timeout of the `GLock` being built, e.g. one inherited from its parent,
+    /// so that blocking lock acquisitions and upgrades block until the lock is ready.
+    pub fn no_default_timeout(mut self) -> GLockBuilder<I> {
+        self.options.default_timeout = None;
+        self
+    }
+
+    /// Requires the child `GLock` being built to be locked using one of the `lock_using_parent`
+    /// variants. Locking it using `lock()`, `try_lock()`, `lock_exclusive()` or any of their other
+    /// variants that would implicitly acquire a lock on its parent `GLock` will return a
+    /// `LockError::ParentNotHeld` error instead. This has no effect on root `GLock`s.
+    pub fn require_explicit_parent(mut self) -> GLockBuilder<I> {
+        self.options.require_explicit_parent = true;
+        self
+    }
+
+    /// Records the type of lock that is intended to be acquired on the child `GLock` being built.
+    /// This is an opt-in correctness aid: the first time the child `GLock` is locked using a
+    /// parent `GLockGuard`, the parent lock type is checked using `LockType::supports_children()`,
+    /// and a `LockError::UnsupportedChildLockType` error is returned if it does not support the
+    /// intended type, instead of silently upgrading the parent lock.
+    pub fn intended_lock_type(mut self, lock_type: LockType) -> GLockBuilder<I> {
+        self.options.intended_lock_type = Some(lock_type);
+        self
+    }
+
+    /// Sets an admission hook for the `GLock` being built, which is called with the id of the
+    /// `GLock` and the requested lock type before each acquisition of a lock on it, including the
+    /// implicit locks acquired on behalf of its descendants. If it returns `false`, the
+    /// acquisition fails with a `LockError::Rejected` error before blocking. This allows plugging in
+    /// external policies such as quotas or circuit breakers. Upgrades of locks already held are
+    /// not checked. Defaults to the hook of the parent `GLock` for child `GLock`s.
+    ///
+    /// The hook is called without holding any internal lock, so it may acquire locks on other
+    /// `GLock`s.
+    pub fn admit<F: Fn(Option<I>, LockType) -> bool + Send + Sync + 'static>(mut self, admit: F) -> GLockBuilder<I> {
+        self.options.admit = Some(Arc::new(admit));
+        self
+    }
+
     /// Builds the `GLock` object that protects the specified `data`.
-    pub fn build<T>(self, data: T) -> LockResult<GLock<T>> {
-        self.kernel.own()
-            .map(|_| GLock {
-                kernel: self.kernel,
-                data,
-            })
+    pub fn build<T>(self, data: T) -> LockResult<GLock<T, I>> {
+        let GLockBuilder { kernel, options } = self;
+
+        kernel.own(options);
+
+        Ok(GLock {
+            kernel,
+            data: UnsafeCell::new(data),
+        })
+    }
+
+    /// Builds a `GLock` object that protects the specified `data`, pinned on the heap. See
+    /// `GLock::new_root_pinned()`.
+    pub fn build_pinned<T>(self, data: T) -> LockResult<GLock<Pin<Box<T>>, I>> {
+        self.build(Box::pin(data))
     }
 }
 
@@ -81,25 +230,32 @@ impl GLockBuilder {
 /// If you do not lock the parent and proceed to lock the child `GLock` directly using `lock()`,
 /// `try_lock()`, `lock_exclusive()` or `try_lock_exclusive()`, an implicit lock will be acquired
 /// for the parent `GLock` that will be release when dropping this lock's `GLockGuard`.
-#[derive(Debug)]
-pub struct GLock< T> {
-    kernel: LockKernelRc,
-    data: T,
+pub struct GLock<T, I: LockId = Id> {
+    kernel: LockKernelRc<I>,
+    data: UnsafeCell<T>,
 }
 
-impl< T> GLock<T> {
+// Like `RwLock`, sharing a `GLock` between threads hands out references to the data to several
+// threads at once, as well as mutable references that allow moving the data between threads.
+unsafe impl<T: Send + Sync, I: LockId + Send + Sync> Sync for GLock<T, I> {}
+
+impl<T> GLock<T> {
 
     /// Creates a new root `GLockBuilder`. This is similar to calling `GLockBuilder::new_root_builder()`.
     pub fn new_root_builder() -> GLockBuilder { GLockBuilder::new_root_builder() }
 
-    /// Creates a new root `GLock` protecting the specified data.
-    pub fn new_root(data: T) -> LockResult<GLock<T>> { GLockBuilder::new_root_builder().build(data) }
+    /// Creates a new root `GLock` protecting the specified data. This is similar to calling `GLockBuilder::root()`.
+    pub fn new_root(data: T) -> LockResult<GLock<T>> { GLockBuilder::root(data) }
 
-    /// Creates a `GLockBuilder` for a lock that is a child of the current `GLock`.
+    /// Creates a `GLockBuilder` for a lock that is a child of the current `GLock`. The child is
+    /// assigned the next unused id of the current `GLock`, and inherits its policies (see
+    /// `new_child_builder_with_id()`).
     pub fn new_child_builder(&self) -> LockResult<GLockBuilder> {
+        let options = self.kernel.options().inherited();
+
         self.kernel
-            .new_child()
-            .map(|child_kernel| GLockBuilder { kernel: child_kernel })
+            .new_auto_child()
+            .map(|kernel| GLockBuilder::new(kernel, options))
     }
 
     /// Creates a `GLock` that is a child of the current `GLock`, protecting the specified data.
@@ -107,24 +263,376 @@ impl< T> GLock<T> {
         self.new_child_builder().and_then(|cb| cb.build(data))
     }
 
+    /// Creates a `GLock` that is a child of the current `GLock`, protecting the specified data
+    /// pinned on the heap. See `GLock::new_root_pinned()`.
+    pub fn new_child_pinned<T2>(&self, data: T2) -> LockResult<GLock<Pin<Box<T2>>>> {
+        self.new_child_builder().and_then(|cb| cb.build_pinned(data))
+    }
+
+    /// Creates an empty `GLockMap`, which creates child `GLock`s of the current `GLock` on demand,
+    /// one per key. Locks on the child `GLock`s of the map follow the same rules as those on any
+    /// other child `GLock`.
+    pub fn new_child_map<K: Eq + Hash, V>(&self) -> GLockMap<K, V> {
+        GLockMap::new(self.kernel.clone())
+    }
+
+    /// Creates a transient child `GLock` protecting the specified data, calls the specified
+    /// function with a reference to it, then drops the child and returns the function's result.
+    /// The entry of the child is removed from the current `GLock` when it is dropped, so this can
+    /// be used for short-lived, per-operation locks without growing the lock tree.
+    ///
+    /// Locks acquired on the child `GLock` borrow it, so they are always released before it is
+    /// dropped.
+    pub fn with_child<U, R, F: FnOnce(&GLock<U>) -> R>(&self, data: U, f: F) -> LockResult<R> {
+        self.new_child(data).map(|child| f(&child))
+    }
+}
+
+impl<T, I: LockId> GLock<T, I> {
+
+    /// Creates a new root `GLock` protecting the specified data, whose descendants are identified
+    /// by ids of type `I`. This is similar to calling
+    /// `GLockBuilder::new_root_builder_with_id_type().build(data)`.
+    pub fn new_root_with_id_type(data: T) -> LockResult<GLock<T, I>> {
+        GLockBuilder::new_root_builder_with_id_type().build(data)
+    }
+
+    /// Creates a `GLockBuilder` for a lock that is a child of the current `GLock`, identified by
+    /// the specified id. If another live child of the current `GLock` has the same id, it will
+    /// return a `LockError::DuplicateId` error.
+    ///
+    /// The child builder inherits the `poison_policy()` and `default_timeout()` of the current
+    /// `GLock`, which can be overridden on the child builder. See
+    /// `GLockBuilder::new_child_builder_with_id()`.
+    pub fn new_child_builder_with_id(&self, id: I) -> LockResult<GLockBuilder<I>> {
+        let options = self.kernel.options().inherited();
+
+        self.kernel
+            .new_child(id)
+            .map(|kernel| GLockBuilder::new(kernel, options))
+    }
+
+    /// Creates a `GLock` that is a child of the current `GLock`, identified by the specified id and
+    /// protecting the specified data. See `new_child_builder_with_id()`.
+    pub fn new_child_with_id<T2>(&self, id: I, data: T2) -> LockResult<GLock<T2, I>> {
+        self.new_child_builder_with_id(id).and_then(|cb| cb.build(data))
+    }
+
+    /// Moves `child`, which must be a child of the current `GLock`, to `new_parent`. The child
+    /// keeps its id, and its whole subtree moves with it.
+    ///
+    /// This acquires an `Exclusive` lock on `child`, which waits for all locks on the child and
+    /// its descendants to be released, and an `IntentionExclusive` lock on `new_parent`, which
+    /// prevents its ancestry from changing during the move. Both locks are released before
+    /// returning. Threads that were waiting to lock `child` when it was moved retry under
+    /// `new_parent`, unless they passed an explicit parent `GLockGuard` of the old parent, in which
+    /// case they fail with `LockError::InvalidParentLock`, like any later attempt to lock `child`
+    /// using an old parent `GLockGuard`.
+    ///
+    /// Returns `LockError::InvalidParentLock` if `child` is not a child of the current `GLock`,
+    /// `LockError::InvalidReparent` if `new_parent` is `child` itself or one of its descendants,
+    /// and `LockError::DuplicateId` if `new_parent` already has a live child with the same id.
+    ///
+    /// Locks on `child` that are released temporarily by `GLockGuard::unlocked()` are waited for as
+    /// well, since they must be acquired again under the old parent. Returns
+    /// `LockError::WouldSelfDeadlock` if the current thread released one of them.
+    ///
+    /// This must not be called while the current thread holds a lock on `child`, one of its
+    /// descendants, or one of its ancestors that conflicts with the locks described above.
+    pub fn reparent<U, V>(&self, child: &GLock<U, I>, new_parent: &GLock<V, I>) -> LockResult<()> {
+        self.kernel.reparent(&child.kernel, &new_parent.kernel)
+    }
+
+    /// Makes `child`, which must be a root `GLock`, a child of the current `GLock`. This allows
+    /// linking non-nested `GLock`s that could not be created in tree order, e.g. cross-referencing
+    /// structures. Once adopted, locking `child` implicitly locks the current `GLock`, like for any
+    /// other child `GLock`. The policies of `child` are left unchanged.
+    ///
+    /// Like `reparent()`, this acquires an `Exclusive` lock on `child`, which waits for all locks on
+    /// the child and its descendants to be released, and an `IntentionExclusive` lock on the
+    /// current `GLock`. Both locks are released before returning. Threads that were waiting to
+    /// lock `child` when it was adopted retry under the current `GLock`. As for `reparent()`, locks
+    /// on `child` released temporarily by `GLockGuard::unlocked()` are waited for as well.
+    ///
+    /// Adopted child `GLock`s have no id (see `GLockGuard::lock_id()`), so they cannot be moved
+    /// using `reparent()`.
+    ///
+    /// Returns `LockError::HasParent` if `child` already has a parent `GLock`, and
+    /// `LockError::InvalidReparent` if the current `GLock` is `child` itself or one of its
+    /// descendants.
+    pub fn adopt_child<U>(&self, child: &GLock<U, I>) -> LockResult<()> {
+        self.kernel.adopt(&child.kernel)
+    }
+
+    /// Returns the creation sequence number of the current `GLock`. Sequence numbers are assigned
+    /// from a single process-wide counter, so unlike ids they are unique across all lock trees,
+    /// and give a total order over all `GLock`s (e.g. the order in which `lock_all_exclusive()`
+    /// acquires its locks).
+    pub fn global_seq(&self) -> u64 {
+        self.kernel.seq()
+    }
+
+    /// Returns the number of ancestors of the current `GLock`, which is `0` for a root `GLock`. This
+    /// is also the number of implicit parent locks acquired when locking this `GLock` directly
+    /// using `lock()`, `try_lock()`, `lock_exclusive()` or `try_lock_exclusive()`.
+    pub fn depth(&self) -> usize {
+        self.kernel.depth()
+    }
+
+    /// Returns the ancestors of the current `GLock` that are implicitly locked when acquiring a
+    /// lock of the specified type on it directly (e.g. using `lock()`), along with the type of the
+    /// lock acquired on each one. The ancestors are identified by their ids (`None` for the root
+    /// `GLock`), and listed from the root `GLock` down to the parent `GLock`, which is the order in
+    /// which their locks are acquired. The returned `Vec` is empty for a root `GLock`.
+    ///
+    /// This can be used to predict which ancestor locks an acquisition will contend on.
+    pub fn parent_chain_types(&self, leaf_type: LockType) -> Vec<(Option<I>, LockType)> {
+        self.kernel.parent_chain_types(leaf_type)
+    }
+
+    /// Returns the number of threads currently blocked waiting to acquire or upgrade a lock on the
+    /// current `GLock`. This is a live snapshot that may already be outdated by the time it is
+    /// returned, so it should only be used as a hint (e.g. for load balancing or monitoring).
+    pub fn waiters(&self) -> LockResult<usize> {
+        Ok(self.kernel.waiters())
+    }
+
+    /// Returns the fairness statistics of the current `GLock` since it was created: the longest
+    /// wait of a granted acquisition, and the number of acquisitions that passed an earlier
+    /// waiter for an incompatible lock type (see `LockStats`). This can be used to check whether
+    /// waiters are being starved, e.g. whether high-priority acquisitions (see
+    /// `lock_with_priority()`) are needed.
+    pub fn stats(&self) -> LockResult<LockStats> {
+        Ok(self.kernel.stats())
+    }
+
+    /// Returns the write version of the current `GLock`, which is incremented whenever an
+    /// `Exclusive` lock on it is released or downgraded, i.e. after every possible modification of
+    /// the protected data. Reading it while holding a `Shared` lock allows checking later whether
+    /// the data was modified in the meantime, see `lock_exclusive_if_version()`.
+    pub fn write_version(&self) -> LockResult<u64> {
+        Ok(self.kernel.write_version())
+    }
+
+    /// Returns the number of locks currently held on the current `GLock` that allow reading its
+    /// data, i.e. `Shared` and `SharedIntentionExclusive` locks. Like `waiters()`, this is a live
+    /// snapshot that should only be used as a hint (e.g. for monitoring).
+    pub fn reader_count(&self) -> LockResult<usize> {
+        Ok(self.kernel.count_of(&[LockType::Shared, LockType::SharedIntentionExclusive]))
+    }
+
+    /// Returns the number of `Exclusive` locks currently held on the current `GLock`, which is
+    /// either 0 or 1. Like `waiters()`, this is a live snapshot that should only be used as a hint
+    /// (e.g. for monitoring).
+    pub fn writer_count(&self) -> LockResult<usize> {
+        Ok(self.kernel.count_of(&[LockType::Exclusive]))
+    }
+
+    /// Blocks until no lock of any type is held on the current `GLock`, including the implicit
+    /// locks held on behalf of its descendants, e.g. to drain all users of a `GLock` before tearing
+    /// it down. Nothing prevents new locks from being acquired once this returns.
+    ///
+    /// Returns `LockError::WouldSelfDeadlock` if the current thread holds a lock on the current
+    /// `GLock`, since it would wait for itself forever.
+    pub fn wait_idle(&self) -> LockResult<()> {
+        self.kernel.wait_idle(Wait::Block)
+    }
+
+    /// Blocks until no lock of any type is held on the current `GLock`, for at most the specified
+    /// `timeout`. If it is still in use after the timeout expires, it will return a
+    /// `LockError::Timeout` error. See `wait_idle()`.
+    pub fn wait_idle_timeout(&self, timeout: Duration) -> LockResult<()> {
+        self.kernel.wait_idle(Wait::timeout(timeout))
+    }
+
+    /// Closes the current `GLock` and its child `GLock`s for a graceful shutdown, then blocks until
+    /// all locks held on the current `GLock` are released, like `wait_idle()`. Once closed, any
+    /// attempt to acquire a new lock on these `GLock`s fails with a `LockError::LockReleased`
+    /// error, including for the threads already waiting for a lock. The `GLockGuard`s already held
+    /// remain usable until they are dropped. Closing cannot be undone.
+    ///
+    /// Returns `LockError::WouldSelfDeadlock`, without closing anything, if the current thread
+    /// holds a lock on the current `GLock`.
+    pub fn close(&self) -> LockResult<()> {
+        self.kernel.close(Wait::Block)
+    }
+
+    /// Closes the current `GLock` and its child `GLock`s, then blocks for at most the specified
+    /// `timeout` until all locks held on the current `GLock` are released. If it is still in use
+    /// after the timeout expires, it will return a `LockError::Timeout` error, but the `GLock`s
+    /// remain closed. See `close()`.
+    pub fn close_timeout(&self, timeout: Duration) -> LockResult<()> {
+        self.kernel.close(Wait::timeout(timeout))
+    }
+
+    /// Forcibly releases all the locks currently held on the current `GLock`, and wakes up all the
+    /// threads waiting for it. This is a last-resort recovery tool, e.g. for a supervised restart
+    /// after a thread exited without dropping its `GLockGuard` (through `mem::forget()` or FFI),
+    /// which would otherwise leave the `GLock` stuck forever.
+    ///
+    /// Only the current `GLock` is reset: implicit locks held on its parent `GLock` and locks held
+    /// on its child `GLock`s are kept. The `GLockGuard`s and `LockToken`s that held the released
+    /// locks do not release anything when they are dropped afterwards, and pending upgrades of
+    /// these locks fail with a `LockError::LockReleased` error. The write version (see
+    /// `write_version()`) is incremented, since the released locks may have modified the data.
+    ///
+    /// # Safety
+    ///
+    /// Any `GLockGuard` (or `LockToken`) still alive on the current `GLock` no longer holds a lock,
+    /// so other threads may acquire conflicting locks, e.g. an `Exclusive` lock while the old
+    /// guard still reads the protected data. The caller must ensure that such guards are no longer
+    /// used, except for being dropped. This does not apply to the `GLockGuard`s whose lock is
+    /// released by `GLockGuard::unlocked()`, which acquire their lock again as usual.
+    pub unsafe fn force_reset(&self) -> LockResult<()> {
+        self.kernel.force_reset();
+        Ok(())
+    }
+
+    /// Returns the number of lock instances currently held on the current `GLock`, indexed by
+    /// `LockType::index()`. This includes implicit locks held on behalf of its descendants.
+    ///
+    /// This is only available in tests and with the `testing` feature, for checking the lock
+    /// accounting after complex sequences of operations.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn lock_counts(&self) -> LockResult<[usize; LOCK_TYPE_COUNT]> {
+        Ok(self.kernel.counts())
+    }
+
+    /// Checks the consistency of the lock accounting of the current `GLock`: the held lock types
+    /// must be compatible with each other, the counts must match the locks held by each thread,
+    /// and its live children must point back to it. Returns a `LockError::UnknownError` describing
+    /// the first violation found. Like `lock_counts()`, this is a live snapshot.
+    ///
+    /// This is only available in tests and with the `testing` feature, for calling between the
+    /// operations of stress tests and fuzzers.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn verify_invariants(&self) -> LockResult<()> {
+        self.kernel.verify_invariants()
+    }
+
+    /// Releases excess memory held for tracking the children of the current `GLock`, e.g. after a
+    /// burst of child creation and destruction. Entries of children that have already been dropped
+    /// are removed first.
+    pub fn shrink_to_fit(&self) -> LockResult<()> {
+        self.kernel.shrink_to_fit();
+        Ok(())
+    }
+
     /// Acquires a lock of the specified type on the current `GLock`. If the lock is busy, it will
     /// block until it is ready. If this is a child `GLock`, it will implicitly acquire the
     /// appropriate lock on its parent `GLock`.
+    ///
+    /// In debug builds, this panics instead of blocking forever if the lock (or an implicit parent
+    /// lock) can only be acquired after a lock held by the current thread is released.
     /// 
     /// If you are trying to acquire an `Exclusive` lock, it is better to use `lock_exclusive()`,
     /// because the `GLockGuard` returned by `lock()` will not allow mutation of protected data.
-    pub fn lock(&self, lock_type: LockType) -> LockResult<GLockGuard<T>> {
-        self.do_lock::<()>(lock_type, None, false)
+    #[must_use = "the lock is released as soon as the guard is dropped"]
+    pub fn lock(&self, lock_type: LockType) -> LockResult<GLockGuard<'_, T, I>> {
+        self.do_lock(lock_type, None, Wait::Block)
     }
 
     /// Attempts to acquire a lock of the specified type on the current `GLock`. If the lock is busy,
     /// it will return a `LockError::LockBusy` error. If this is a child `GLock`, it will implicitly
     /// attempt to acquire the appropriate lock on its parent `GLock`.
+    ///
+    /// This never blocks: if any of the implicit locks on the ancestors of this `GLock` is busy, it
+    /// will return a `LockError::LockBusy` error as well.
     /// 
     /// If you are trying to acquire an `Exclusive` lock, it is better to use `try_lock_exclusive()`,
     /// because the `GLockGuard` returned by `try_lock()` will not allow mutation of protected data.
-    pub fn try_lock(&self, lock_type: LockType) -> LockResult<GLockGuard<T>> {
-        self.do_lock::<()>(lock_type, None, true)
+    #[must_use = "the lock is released as soon as the guard is dropped"]
+    pub fn try_lock(&self, lock_type: LockType) -> LockResult<GLockGuard<'_, T, I>> {
+        self.do_lock(lock_type, None, Wait::TryOnly)
+    }
+
+    /// Acquires a lock of the specified type on the current `GLock`, blocking for at most the
+    /// specified `timeout`. If the lock is still busy after the timeout expires, it will return a
+    /// `LockError::Timeout` error. If this is a child `GLock`, the implicit locks on its ancestors
+    /// are acquired within the same timeout.
+    #[must_use = "the lock is released as soon as the guard is dropped"]
+    pub fn lock_timeout(&self, lock_type: LockType, timeout: Duration) -> LockResult<GLockGuard<'_, T, I>> {
+        self.do_lock(lock_type, None, Wait::timeout(timeout))
+    }
+
+    /// Attempts to acquire a lock of the `preferred` type on the current `GLock` without blocking,
+    /// and if it is busy, acquires a lock of the `fallback` type instead, blocking until it is
+    /// ready. For example, preferring `Exclusive` and falling back to `Shared` degrades to reading
+    /// under contention. Use `GLockGuard::lock_type()` to find out which type was acquired.
+    ///
+    /// The `fallback` type must be strictly less restrictive than the `preferred` type (i.e.
+    /// `fallback` is upgradable to `preferred`, and different from it), otherwise a
+    /// `LockError::InvalidLockType` error is returned without acquiring any lock.
+    #[must_use = "the lock is released as soon as the guard is dropped"]
+    pub fn lock_preferred(&self, preferred: LockType, fallback: LockType) -> LockResult<GLockGuard<'_, T, I>> {
+        if fallback == preferred || !fallback.upgradable_to(preferred) {
+            return Err(LockError::InvalidLockType);
+        }
+
+        match self.try_lock(preferred) {
+            Err(LockError::LockBusy)    => self.lock(fallback),
+            result                      => result,
+        }
+    }
+
+    /// Acquires a lock of the specified type on the current `GLock` with the specified priority.
+    /// This is similar to `lock()`, except that while a thread is blocked acquiring a lock with a
+    /// higher priority, acquisitions with lower priorities wait behind it (or fail with a
+    /// `LockError::LockBusy` error when using `try_lock()`), even if they are compatible with the
+    /// locks currently held. All other acquisitions have priority `0`. Threads that already hold a
+    /// lock on the same `GLock` (e.g. a reader locking one of its children) are not held back,
+    /// since the higher-priority acquisition waits for them anyway.
+    ///
+    /// The priority applies to the implicit locks on the ancestors of this `GLock` as well. It does
+    /// not apply to upgrades.
+    #[must_use = "the lock is released as soon as the guard is dropped"]
+    pub fn lock_with_priority(&self, lock_type: LockType, priority: u8) -> LockResult<GLockGuard<'_, T, I>> {
+        self.do_lock_with_priority(lock_type, None, Wait::Block, priority)
+    }
+
+    /// Acquires a lock of the specified type on the current `GLock`, without waiting for the
+    /// implicit locks on its ancestors. If any of them is busy, it will return a
+    /// `LockError::LockBusy` error right away, but if the lock on the current `GLock` itself is
+    /// busy, it will block until it is ready. This is useful when contention on the ancestors
+    /// means backing off entirely, while contention on the current `GLock` is expected.
+    ///
+    /// For a root `GLock`, this is similar to calling `lock()`.
+    #[must_use = "the lock is released as soon as the guard is dropped"]
+    pub fn lock_nonblocking_parent(&self, lock_type: LockType) -> LockResult<GLockGuard<'_, T, I>> {
+        self.kernel
+            .acquire_nonblocking_parent(lock_type, Wait::Block)
+            .map(|lock_instance| GLockGuard::new(self, lock_instance))
+    }
+
+    /// Acquires a lock of the specified type on the current `GLock`, only if the specified
+    /// condition holds for the counts of the locks currently held on it, one per lock type (indexed
+    /// by `LockType::index()`). This can be used for custom admission
+    /// control, e.g. capping the number of concurrent `Shared` locks.
+    ///
+    /// The condition is checked atomically with acquiring the lock. If it fails, `None` is returned
+    /// without blocking. If it holds but the lock is busy, it will block until the lock is ready,
+    /// and check the condition again before acquiring it.
+    #[must_use = "the lock is released as soon as the guard is dropped"]
+    pub fn lock_if<F: Fn(&[usize; LOCK_TYPE_COUNT]) -> bool>(&self, lock_type: LockType, cond: F) -> LockResult<Option<GLockGuard<'_, T, I>>> {
+        self.kernel
+            .acquire_if(lock_type, Wait::Block, cond)
+            .map(|lock_instance| lock_instance.map(|lock_instance| GLockGuard::new(self, lock_instance)))
+    }
+
+    /// Acquires an `Exclusive` lock on the current `GLock`, only if its write version (see
+    /// `write_version()`) still equals the `expected` one, i.e. if the protected data was not
+    /// modified since the version was read. Otherwise, `None` is returned without acquiring the
+    /// lock, and the write version is left unchanged. This gives compare-and-lock semantics for
+    /// optimistic concurrency: read the version and the data under a `Shared` lock, release it,
+    /// do the work, then apply the result only if nobody else wrote in the meantime.
+    ///
+    /// The version is checked atomically with acquiring the lock. If the lock is busy, it will
+    /// block until it is ready, and check the version again before acquiring it.
+    #[must_use = "the lock is released as soon as the guard is dropped"]
+    pub fn lock_exclusive_if_version(&self, expected: u64) -> LockResult<Option<GLockGuardMut<'_, T, I>>> {
+        self.kernel
+            .acquire_if_version(LockType::Exclusive, Wait::Block, expected)
+            .map(|lock_instance| lock_instance.map(|lock_instance| GLockGuardMut::new(GLockGuard::new(self, lock_instance))))
     }
 
     /// Acquires a lock of the specified type on the current child `GLock`, using the specified
@@ -133,8 +641,31 @@ impl< T> GLock<T> {
     /// If you are trying to acquire an `Exclusive` lock, it is better to use
     /// `lock_exclusive_using_parent()`, because the `GLockGuard` returned by
     /// `lock_using_parent()` will not allow mutation of protected data.
-    pub fn lock_using_parent<T2>(&self, lock_type: LockType, parent: &GLockGuard<T2>) -> LockResult<GLockGuard<T>> {
-        self.do_lock(lock_type, Some(parent), false)
+    #[must_use = "the lock is released as soon as the guard is dropped"]
+    pub fn lock_using_parent<P: AsParentGuard<I>>(&self, lock_type: LockType, parent: &P) -> LockResult<GLockGuard<'_, T, I>> {
+        self.do_lock(lock_type, Some(parent.parent_instance()), Wait::Block)
+    }
+
+    /// Acquires a lock of the specified type on the current child `GLock`, using the specified
+    /// chain of guards held on its ancestors, listed from the outermost ancestor down to the parent
+    /// `GLock` (e.g. `&[&root_guard, &parent_guard]`). The chain does not have to start at the root
+    /// `GLock`, but it must not skip any level, otherwise a `LockError::InvalidParentLock` error is
+    /// returned. If the chain is empty, a `LockError::ParentNotHeld` error is returned. If the lock
+    /// is busy, it will block until it is ready.
+    ///
+    /// Like `lock_using_parent()`, no implicit lock is acquired on any ancestor: the last guard in
+    /// the chain is used as the parent lock, and is upgraded if it does not support `lock_type`.
+    /// Use `GLockGuard::upgraded_mut()` to mutate the protected data under an `Exclusive` lock.
+    #[must_use = "the lock is released as soon as the guard is dropped"]
+    pub fn lock_using_parents(&self, lock_type: LockType, parents: &[&dyn AsParentGuard<I>]) -> LockResult<GLockGuard<'_, T, I>> {
+        let chain: Vec<Arc<LockInstance<I>>> = parents.iter().map(|parent| parent.parent_instance()).collect();
+
+        if !self.kernel.is_parent_chain(&chain) { return Err(LockError::InvalidParentLock); }
+
+        match chain.last() {
+            Some(parent)    => self.do_lock(lock_type, Some(parent.clone()), Wait::Block),
+            None            => Err(LockError::ParentNotHeld),
+        }
     }
 
     /// Attempts to acquire a lock of the specified type on the current child `GLock`, using the
@@ -144,8 +675,46 @@ impl< T> GLock<T> {
     /// If you are trying to acquire an `Exclusive` lock, it is better to use
     /// `try_lock_exclusive_using_parent()`, because the `GLockGuard` returned by
     /// `try_lock_using_parent()` will not allow mutation of protected data.
-    pub fn try_lock_using_parent<T2>(&self, lock_type: LockType, parent: &GLockGuard<T2>) -> LockResult<GLockGuard<T>> {
-        self.do_lock(lock_type, Some(parent), true)
+    #[must_use = "the lock is released as soon as the guard is dropped"]
+    pub fn try_lock_using_parent<P: AsParentGuard<I>>(&self, lock_type: LockType, parent: &P) -> LockResult<GLockGuard<'_, T, I>> {
+        self.do_lock(lock_type, Some(parent.parent_instance()), Wait::TryOnly)
+    }
+
+    /// Acquires a lock of the specified type on the current child `GLock`, using the specified
+    /// `GLockGuard` of the parent `GLock`, blocking for at most the specified `timeout`. If the lock
+    /// is still busy after the timeout expires, it will return a `LockError::Timeout` error.
+    #[must_use = "the lock is released as soon as the guard is dropped"]
+    pub fn lock_using_parent_timeout<P: AsParentGuard<I>>(&self, lock_type: LockType, parent: &P, timeout: Duration) -> LockResult<GLockGuard<'_, T, I>> {
+        self.do_lock(lock_type, Some(parent.parent_instance()), Wait::timeout(timeout))
+    }
+
+    /// Acquires a `Shared` lock on the current `GLock`. This is similar to calling
+    /// `lock(LockType::Shared)`.
+    #[must_use = "the lock is released as soon as the guard is dropped"]
+    pub fn lock_shared(&self) -> LockResult<GLockGuard<'_, T, I>> {
+        self.do_lock(LockType::Shared, None, Wait::Block)
+    }
+
+    /// Attempts to acquire a `Shared` lock on the current `GLock`. This is similar to calling
+    /// `try_lock(LockType::Shared)`.
+    #[must_use = "the lock is released as soon as the guard is dropped"]
+    pub fn try_lock_shared(&self) -> LockResult<GLockGuard<'_, T, I>> {
+        self.do_lock(LockType::Shared, None, Wait::TryOnly)
+    }
+
+    /// Acquires a `Shared` lock on the current child `GLock`, using the specified `GLockGuard` of
+    /// the parent `GLock`. This is similar to calling `lock_using_parent(LockType::Shared, parent)`.
+    #[must_use = "the lock is released as soon as the guard is dropped"]
+    pub fn lock_shared_using_parent<P: AsParentGuard<I>>(&self, parent: &P) -> LockResult<GLockGuard<'_, T, I>> {
+        self.do_lock(LockType::Shared, Some(parent.parent_instance()), Wait::Block)
+    }
+
+    /// Attempts to acquire a `Shared` lock on the current child `GLock`, using the specified
+    /// `GLockGuard` of the parent `GLock`. This is similar to calling
+    /// `try_lock_using_parent(LockType::Shared, parent)`.
+    #[must_use = "the lock is released as soon as the guard is dropped"]
+    pub fn try_lock_shared_using_parent<P: AsParentGuard<I>>(&self, parent: &P) -> LockResult<GLockGuard<'_, T, I>> {
+        self.do_lock(LockType::Shared, Some(parent.parent_instance()), Wait::TryOnly)
     }
 
     /// Acquires an `Exclusive` lock on the current `GLock`. If the lock is busy, it will block
@@ -153,8 +722,23 @@ impl< T> GLock<T> {
     /// lock on its parent `GLock`.
     ///
     /// The returned `GLockGuardMut` allows mutating the protected data.
-    pub fn lock_exclusive(&self) -> LockResult<GLockGuardMut<T>> {
-        self.do_lock_exclusive::<()>(None, false)
+    #[must_use = "the lock is released as soon as the guard is dropped"]
+    pub fn lock_exclusive(&self) -> LockResult<GLockGuardMut<'_, T, I>> {
+        self.do_lock_exclusive(None, Wait::Block)
+    }
+
+    /// Acquires an `Exclusive` lock on the current `GLock`, without waiting for the implicit
+    /// locks on its ancestors. See `lock_nonblocking_parent()`.
+    #[must_use = "the lock is released as soon as the guard is dropped"]
+    pub fn lock_exclusive_nonblocking_parent(&self) -> LockResult<GLockGuardMut<'_, T, I>> {
+        self.lock_nonblocking_parent(LockType::Exclusive).map(GLockGuardMut::new)
+    }
+
+    /// Acquires an `Exclusive` lock on the current `GLock` with the specified priority. See
+    /// `lock_with_priority()`.
+    #[must_use = "the lock is released as soon as the guard is dropped"]
+    pub fn lock_exclusive_with_priority(&self, priority: u8) -> LockResult<GLockGuardMut<'_, T, I>> {
+        self.do_lock_with_priority(LockType::Exclusive, None, Wait::Block, priority).map(GLockGuardMut::new)
     }
 
     /// Attempts to acquire an `Exclusive` lock on the current `GLock`. If the lock is busy,
@@ -162,16 +746,44 @@ impl< T> GLock<T> {
     /// attempt to acquire the appropriate lock on its parent `GLock`.
     ///
     /// The returned `GLockGuardMut` allows mutating the protected data.
-    pub fn try_lock_exclusive(&self) -> LockResult<GLockGuardMut<T>> {
-        self.do_lock_exclusive::<()>(None, true)
+    #[must_use = "the lock is released as soon as the guard is dropped"]
+    pub fn try_lock_exclusive(&self) -> LockResult<GLockGuardMut<'_, T, I>> {
+        self.do_lock_exclusive(None, Wait::TryOnly)
+    }
+
+    /// Acquires an `Exclusive` lock on the current `GLock`, blocking for at most the specified
+    /// `timeout`. If the lock is still busy after the timeout expires, it will return a
+    /// `LockError::Timeout` error. See `lock_timeout()`.
+    ///
+    /// The returned `GLockGuardMut` allows mutating the protected data.
+    #[must_use = "the lock is released as soon as the guard is dropped"]
+    pub fn lock_exclusive_timeout(&self, timeout: Duration) -> LockResult<GLockGuardMut<'_, T, I>> {
+        self.do_lock_exclusive(None, Wait::timeout(timeout))
+    }
+
+    /// Acquires an `Exclusive` lock on the current `GLock`, blocking for at most the specified
+    /// `timeout`, and if the lock is still busy after the timeout expires, acquires a `Shared` lock
+    /// instead, blocking until it is ready. This way, the caller always makes progress, with at
+    /// least read access to the protected data. See `ExclusiveOrShared`.
+    ///
+    /// Unlike `lock_preferred()`, the `Exclusive` lock is waited for up to the timeout, instead of
+    /// only being tried once.
+    #[must_use = "the lock is released as soon as the guard is dropped"]
+    pub fn lock_exclusive_or_timeout_shared(&self, timeout: Duration) -> LockResult<ExclusiveOrShared<'_, T, I>> {
+        match self.lock_exclusive_timeout(timeout) {
+            Ok(lock_guard)              => Ok(ExclusiveOrShared::Exclusive(lock_guard)),
+            Err(LockError::Timeout)     => self.lock_shared().map(ExclusiveOrShared::Shared),
+            Err(err)                    => Err(err),
+        }
     }
 
     /// Acquires an `Exclusive` lock on the current child `GLock`, using the specified `GLockGuard`
     /// of the parent `GLock`. If the lock is busy, it will block until it is ready.
     ///
     /// The returned `GLockGuardMut` allows mutating the protected data.
-    pub fn lock_exclusive_using_parent<T2>(&self, parent: &GLockGuard<T2>) -> LockResult<GLockGuardMut<T>> {
-        self.do_lock_exclusive(Some(parent), false)
+    #[must_use = "the lock is released as soon as the guard is dropped"]
+    pub fn lock_exclusive_using_parent<P: AsParentGuard<I>>(&self, parent: &P) -> LockResult<GLockGuardMut<'_, T, I>> {
+        self.do_lock_exclusive(Some(parent.parent_instance()), Wait::Block)
     }
 
     /// Attempts to acquire an `Exclusive` lock on the current child `GLock`, using the
@@ -179,205 +791,3946 @@ impl< T> GLock<T> {
     /// `LockError::LockBusy` error.
     ///
     /// The returned `GLockGuardMut` allows mutating the protected data.
-    pub fn try_lock_exclusive_using_parent<T2>(&self, parent: &GLockGuard<T2>) -> LockResult<GLockGuardMut<T>> {
-        self.do_lock_exclusive(Some(parent), true)
+    #[must_use = "the lock is released as soon as the guard is dropped"]
+    pub fn try_lock_exclusive_using_parent<P: AsParentGuard<I>>(&self, parent: &P) -> LockResult<GLockGuardMut<'_, T, I>> {
+        self.do_lock_exclusive(Some(parent.parent_instance()), Wait::TryOnly)
     }
 
-    fn do_lock<T2>(&self, lock_type: LockType, parent: Option<&GLockGuard<T2>>, try_only: bool) -> LockResult<GLockGuard<T>> {
-        self.kernel
-            .acquire(lock_type, parent.map(|p| p.lock_instance.clone()), true, try_only)
-            .map(|lock_instance| GLockGuard { lock: self, lock_instance })
+    /// Acquires an `Exclusive` lock on the current child `GLock`, using the specified `GLockGuard`
+    /// of the parent `GLock`, blocking for at most the specified `timeout`. If the lock is still
+    /// busy after the timeout expires, it will return a `LockError::Timeout` error.
+    ///
+    /// The returned `GLockGuardMut` allows mutating the protected data.
+    #[must_use = "the lock is released as soon as the guard is dropped"]
+    pub fn lock_exclusive_using_parent_timeout<P: AsParentGuard<I>>(&self, parent: &P, timeout: Duration) -> LockResult<GLockGuardMut<'_, T, I>> {
+        self.do_lock_exclusive(Some(parent.parent_instance()), Wait::timeout(timeout))
     }
 
-    fn do_lock_exclusive<T2>(&self, parent: Option<&GLockGuard<T2>>, try_only: bool) -> LockResult<GLockGuardMut<T>> {
-        self.do_lock(LockType::Exclusive, parent, try_only).map(|lg| GLockGuardMut { lock_guard: lg })
+    /// Acquires a `Shared` lock on the current `GLock`, calls the specified function with a
+    /// reference to the protected data, then releases the lock and returns the function's result.
+    pub fn with_shared<R, F: FnOnce(&T) -> R>(&self, f: F) -> LockResult<R> {
+        self.lock_shared().map(|lg| f(&lg))
     }
 
-    fn data_ptr(&self) -> *mut T {
-        (&self.data as *const T) as *mut T
+    /// Acquires an `Exclusive` lock on the current `GLock`, calls the specified function with a
+    /// mutable reference to the protected data, then releases the lock and returns the function's
+    /// result.
+    pub fn with_exclusive<R, F: FnOnce(&mut T) -> R>(&self, f: F) -> LockResult<R> {
+        self.lock_exclusive().map(|mut lg| f(&mut lg))
     }
-}
 
-impl< T> Drop for GLock<T> {
-    fn drop(&mut self) {
-        self.kernel
-            .unown()
-            .unwrap();
+    /// Runs the specified function as a transaction over the current `GLock` and its children.
+    /// An `IntentionExclusive` lock is acquired on the current `GLock` and passed to the function,
+    /// which can then lock any of its children using the `*_using_parent` methods. All locks are
+    /// released when the function returns: child `GLockGuard`s borrow the parent `GLockGuard`, so
+    /// they are always released before it.
+    ///
+    /// Transactions locking disjoint children of the same `GLock` can run concurrently.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use glock::{ GLock, GLockBuilder };
+    ///
+    /// struct Accounts {
+    ///     checking: GLock<i64>,
+    ///     savings: GLock<i64>,
+    /// }
+    ///
+    /// let accounts = {
+    ///     let builder = GLockBuilder::new_root_builder();
+    ///
+    ///     let accounts = Accounts {
+    ///         checking: builder.new_child(100).unwrap(),
+    ///         savings: builder.new_child(0).unwrap(),
+    ///     };
+    ///
+    ///     builder.build(accounts).unwrap()
+    /// };
+    ///
+    /// accounts.transaction(|accounts_guard| {
+    ///     let mut checking = accounts_guard.checking.lock_exclusive_using_parent(accounts_guard)?;
+    ///     let mut savings = accounts_guard.savings.lock_exclusive_using_parent(accounts_guard)?;
+    ///
+    ///     *checking -= 40;
+    ///     *savings += 40;
+    ///     Ok(())
+    /// }).unwrap();
+    ///
+    /// assert_eq!(accounts.try_lock_exclusive().is_ok(), true);
+    /// ```
+    pub fn transaction<R, F: FnOnce(&GLockGuard<T, I>) -> LockResult<R>>(&self, f: F) -> LockResult<R> {
+        self.lock(LockType::IntentionExclusive).and_then(|lg| f(&lg))
     }
-}
 
+    /// Returns a copy of the protected data, holding a `Shared` lock only while copying it.
+    pub fn get(&self) -> LockResult<T> where T: Copy {
+        self.with_shared(|data| *data)
+    }
 
-/// A `GLockGuard` represents an acquired lock instance of any type. It can be used to access the
-/// protected data. The lock is released by dropping the `GLockGuard` object.
-#[derive(Debug)]
-pub struct GLockGuard<'lck, T: 'lck> {
-    lock: &'lck GLock<T>,
-    lock_instance: Arc<LockInstance>,
-}
+    /// Returns a clone of the protected data, holding a `Shared` lock only while cloning it. This
+    /// avoids holding a `GLockGuard` across expensive work on a consistent copy of the data.
+    pub fn snapshot(&self) -> LockResult<T> where T: Clone {
+        self.with_shared(T::clone)
+    }
 
-impl<'lck, T: 'lck> GLockGuard<'lck, T> {
+    /// Replaces the protected data with the specified value, holding an `Exclusive` lock only
+    /// while writing it.
+    pub fn set(&self, value: T) -> LockResult<()> {
+        self.with_exclusive(|data| *data = value)
+    }
 
-    /// Returns the type of the lock currently held.
-    pub fn lock_type(&self) -> LockResult<LockType> {
-        self.lock_instance.lock_type()
+    /// Converts a `LockToken` created from a `GLockGuard` of the current `GLock` back into a
+    /// `GLockGuard`, without releasing or re-acquiring the lock. This can be used to hand over a
+    /// held lock to another thread. The lock (and any implicit locks on its ancestors) is then
+    /// considered held by the current thread.
+    ///
+    /// If the `LockToken` does not belong to the current `GLock`, it will return a tuple containing
+    /// a `LockError::InvalidToken` error as well as the original `LockToken`.
+    #[must_use = "the lock is released as soon as the guard is dropped"]
+    pub fn from_token(&self, token: LockToken<I>) -> Result<GLockGuard<'_, T, I>, (LockError, LockToken<I>)> {
+        if !token.lock_instance.belongs_to(&self.kernel) {
+            return Err((LockError::InvalidToken, token));
+        }
+
+        token.lock_instance.attach();
+        Ok(GLockGuard::new(self, token.lock_instance))
     }
 
-    /// Upgrades the type of this `GLockGuard` to the specified type. If parent lock does not support
-    /// the new type, it will be upgraded as well. If the lock is currently busy, it will block until
-    /// it is ready.
-    pub fn upgrade(&self, to_type: LockType) -> LockResult<()> {
-        self.lock_instance.upgrade(to_type, true, false)
+    /// Creates a read-only view of the current `GLock` that projects the protected data to a part
+    /// of it (e.g. a field) using the specified function. The view does not have a lock kernel of
+    /// its own: locking it locks the current `GLock` itself, so the same locking rules apply.
+    ///
+    /// Guards returned by the view only give read access to the projected data, even when an
+    /// `Exclusive` lock is held. Use `project_mut()` for mutable access.
+    pub fn project<U: ?Sized, F: Fn(&T) -> &U>(&self, project: F) -> GLockProjection<'_, T, U, F, I> {
+        GLockProjection { lock: self, project, _marker: PhantomData }
     }
 
-    /// Attempts to upgrade the type of this `GLockGuard` to the specified type. If parent lock
-    /// does not support the new type, it will be upgraded as well. If the lock is currently busy,
-    /// it will return a `LockError::LockBusy` error.
-    pub fn try_upgrade(&self, to_type: LockType) -> LockResult<()> {
-        self.lock_instance.upgrade(to_type, true, true)
+    /// Creates a view of the current `GLock` that projects the protected data to a part of it
+    /// (e.g. a field) using the specified function, and can be locked exclusively to mutate it.
+    ///
+    /// Since all views of a `GLock` share its lock kernel, an `Exclusive` lock acquired through
+    /// any view excludes all other locks on the `GLock` and all its other views. Therefore, two
+    /// views projecting disjoint fields can never be mutated at the same time; if that is needed,
+    /// use child `GLock`s instead.
+    pub fn project_mut<U: ?Sized, F: Fn(&mut T) -> &mut U>(&self, project: F) -> GLockProjectionMut<'_, T, U, F, I> {
+        GLockProjectionMut { lock: self, project, _marker: PhantomData }
     }
 
-    /// Upgrades the type of this `GLockGuard` to `Exclusive`. If parent lock does not support
-    /// the new type, it will be upgraded as well. If the lock is currently busy, it will block until
-    /// it is ready.
+    /// Returns a reference to the protected data without acquiring a lock, for code that already
+    /// holds a lock on the current `GLock` but has no access to its `GLockGuard` (e.g. across
+    /// layers of abstraction). The lock counts are left untouched.
     ///
-    /// This method consumes the current `GLockGuard` and returns a new `GLockGuardMut`.
-    /// In case of failure, it will return a tuple containing the error as well as the original
-    /// `GLockGuard`.
-    pub fn upgrade_to_exclusive(self) -> Result<GLockGuardMut<'lck, T>, (LockError, GLockGuard<'lck, T>)> {
-        match self.lock_instance.upgrade(LockType::Exclusive, true, false) {
-            Ok(_)   => { Ok(GLockGuardMut { lock_guard: self }) },
-            Err(e)  => { Err((e, self)) },
+    /// In debug builds, it will return a `LockError::LockNotHeld` error if the current thread does
+    /// not hold a lock of at least the specified type (i.e. of a type that `at_least` is upgradable
+    /// to) on the current `GLock`. This is not validated in release builds.
+    ///
+    /// # Safety
+    ///
+    /// The current thread must hold a lock of at least the specified type on the current `GLock`
+    /// for as long as the returned reference is alive, and must not mutate the protected data
+    /// (e.g. through a `GLockGuardMut`) meanwhile.
+    pub unsafe fn assume_locked(&self, at_least: LockType) -> LockResult<&T> {
+        if cfg!(debug_assertions) && !self.kernel.held_by_current_thread(at_least) {
+            return Err(LockError::LockNotHeld);
         }
+
+        Ok(&*self.data_ptr())
     }
 
-    /// Attempts to upgrade the type of this `GLockGuard` to `Exclusive`. If parent lock does not support
-    /// the new type, it will be upgraded as well. If the lock is currently busy, If the lock is
-    /// currently busy, it will return a `LockError::LockBusy` error.
+    /// Consumes the current `GLock`, returning the protected data. Any outstanding `LockToken`s
+    /// for this `GLock` can no longer be redeemed and are simply released when dropped.
+    pub fn into_inner(self) -> T {
+        let (kernel, data) = self.into_parts();
+        kernel.unown();
+        data
+    }
+
+    /// Attempts to consume the specified `GLock`, returning the protected data. If the `GLock` is
+    /// still in use, i.e. it has outstanding lock instances (held through `LockToken`s) or live
+    /// child `GLock`s, the `GLock` is returned back unchanged in `Err`.
     ///
-    /// This method consumes the current `GLockGuard` and returns a new `GLockGuardMut`.
-    /// In case of failure, it will return a tuple containing the error as well as the original
-    /// `GLockGuard`.
-    pub fn try_upgrade_to_exclusive(self) -> Result<GLockGuardMut<'lck, T>, (LockError, GLockGuard<'lck, T>)> {
-        match self.lock_instance.upgrade(LockType::Exclusive, true, true) {
-            Ok(_)   => { Ok(GLockGuardMut { lock_guard: self }) },
-            Err(e)  => { Err((e, self)) },
-        }
+    /// This is the `GLock` analogue of `Arc::try_unwrap()`, and is typically combined with it
+    /// when the `GLock` is shared between threads.
+    pub fn try_into_inner(this: GLock<T, I>) -> Result<T, GLock<T, I>> {
+        if this.kernel.in_use() { Err(this) } else { Ok(this.into_inner()) }
     }
-}
 
-impl<'lck, T: 'lck> Deref for GLockGuard<'lck, T> {
-    type Target = T;
+    /// Converts the current `GLock` into a `SharedGLock`, which can be cloned cheaply using
+    /// `SharedGLock::clone_handle()` to share the same lock and data between several owners. This
+    /// is similar to calling `SharedGLock::from()`.
+    pub fn into_shared(self) -> SharedGLock<T, I> {
+        SharedGLock::from(self)
+    }
 
-    fn deref(&self) -> &<Self as Deref>::Target {
-        unsafe { &*self.lock.data_ptr() }
+    fn into_parts(self) -> (LockKernelRc<I>, T) {
+        let this = ManuallyDrop::new(self);
+
+        // `this` is never dropped, so each field is moved out exactly once.
+        unsafe { (ptr::read(&this.kernel), ptr::read(&this.data).into_inner()) }
     }
-}
 
-/// A `GLockGuard` represents an acquired `Exclusive` lock instance. It can be used to read as well
-/// as mutate  the protected data. The lock is released by dropping the `GLockGuardMut` object.
-#[derive(Debug)]
-pub struct GLockGuardMut<'lck, T: 'lck> {
-    lock_guard: GLockGuard<'lck, T>,
+    fn do_lock(&self, lock_type: LockType, parent: Option<Arc<LockInstance<I>>>, wait: Wait) -> LockResult<GLockGuard<'_, T, I>> {
+        self.do_lock_with_priority(lock_type, parent, wait, 0)
+    }
+
+    fn do_lock_with_priority(&self, lock_type: LockType, parent: Option<Arc<LockInstance<I>>>, wait: Wait, priority: u8) -> LockResult<GLockGuard<'_, T, I>> {
+        self.kernel
+            .acquire(lock_type, parent, true, wait, priority)
+            .map(|lock_instance| GLockGuard::new(self, lock_instance))
+    }
+
+    fn do_lock_exclusive(&self, parent: Option<Arc<LockInstance<I>>>, wait: Wait) -> LockResult<GLockGuardMut<'_, T, I>> {
+        self.do_lock(LockType::Exclusive, parent, wait).map(GLockGuardMut::new)
+    }
+
+    // Guards dereference this pointer, relying on the lock protocol to never let a mutable
+    // reference coexist with any other reference to the data:
+    //
+    // * Mutable references are only handed out by a `GLockGuardMut`, which is only created for an
+    //   `Exclusive` lock (see `GLockGuardMut::new()`), or by `GLockGuard::upgraded_mut()`, which
+    //   requires an `Exclusive` lock and borrows its `GLockGuard` mutably.
+    // * The kernel never grants an `Exclusive` lock while any other lock is held on the same
+    //   `GLock`, by any thread. Acquisitions and upgrades that conflict with locks held by the
+    //   current thread fail (or block forever) instead of being granted.
+    // * Guards whose lock is released temporarily (see `GLockGuard::unlocked()`) are borrowed
+    //   mutably meanwhile, so no reference derived from them is alive.
+    //
+    // The data is kept in an `UnsafeCell`, so that mutating it through this pointer is allowed
+    // while the `GLock` itself is only borrowed immutably.
+    fn data_ptr(&self) -> *mut T {
+        self.data.get()
+    }
 }
 
-impl<'lck, T: 'lck> Deref for GLockGuardMut<'lck, T> {
-    type Target = T;
-    fn deref(&self) -> &<Self as Deref>::Target { self.lock_guard.deref() }
+impl<T, I: LockId> GLock<Option<T>, I> {
+
+    /// Acquires an `Exclusive` lock on the current `GLock`, initializes the protected data using
+    /// the specified function if it is `None`, and returns a guard giving access to the now present
+    /// value. This allows lazily building expensive data on first access. Since the data is only
+    /// initialized while holding the `Exclusive` lock, `init` runs at most once, even if several
+    /// threads race to initialize it.
+    #[must_use = "the lock is released as soon as the guard is dropped"]
+    pub fn lock_or_init<F: FnOnce() -> T>(&self, init: F) -> LockResult<GLockProjectionGuardMut<'_, Option<T>, T, I>> {
+        self.lock_exclusive().map(|mut lg| {
+            let data = lg.get_or_insert_with(init) as *mut T;
+            GLockProjectionGuardMut { _lock_guard: lg, data: unsafe { &mut *data } }
+        })
+    }
 }
 
-impl<'lck, T: 'lck> DerefMut for GLockGuardMut<'lck, T> {
-    fn deref_mut(&mut self) -> &mut <Self as Deref>::Target {
-        unsafe { &mut *self.lock_guard.lock.data_ptr() }
+/// A `GLock` protecting pinned data, for data that must not be moved once it is in use, such as
+/// self-referential structures or `!Unpin` futures. The data is allocated on the heap once, when
+/// the `GLock` is created, and is never moved by the `GLock` or its guards: it stays at the same
+/// address until it is dropped, even if the `GLock` itself is moved.
+///
+/// Guards give pinned access to the data through `GLockGuard::as_pin()` and
+/// `GLockGuardMut::as_pin_mut()`. A `GLockGuardMut` can also replace the whole `Pin<Box<T>>`,
+/// which drops the previous data in place, as pinning requires.
+impl<T> GLock<Pin<Box<T>>> {
+
+    /// Creates a new root `GLock` protecting the specified data, pinned on the heap.
+    pub fn new_root_pinned(data: T) -> LockResult<GLock<Pin<Box<T>>>> {
+        GLockBuilder::new_root_builder().build_pinned(data)
     }
 }
 
+impl<T: Debug, I: LockId> Debug for GLock<T, I> {
 
-#[cfg(test)]
+    // The data is read without acquiring a lock, so that formatting has no side effects (e.g. on
+    // admission hooks, statistics or implicit parent locks), while the kernel guarantees that no
+    // `Exclusive` lock is held. It is never waited for, so formatting does not deadlock either.
+    fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
+        let mut debug = f.debug_struct("GLock");
+        debug.field("kernel", &self.kernel);
+
+        let read = self.kernel.peek(|| { debug.field("data", unsafe { &*self.data_ptr() }); });
+        if read.is_none() { debug.field("data", &format_args!("<locked>")); }
+
+        debug.finish()
+    }
+}
+
+impl<T: Default> Default for GLock<T> {
+
+    /// Creates a new root `GLock` protecting the default value of `T`. Creating a root `GLock`
+    /// never fails in practice; if it does, this will panic. Use `GLock::new_root()` to handle
+    /// the error instead.
+    fn default() -> Self {
+        GLock::new_root(T::default()).expect("failed to create root GLock")
+    }
+}
+
+impl<T, I: LockId> Drop for GLock<T, I> {
+    fn drop(&mut self) {
+        self.kernel.unown();
+    }
+}
+
+
+/// A `SharedGLock` is a handle to a `GLock` whose lock and protected data are shared between
+/// several owners, without wrapping the `GLock` in an `Arc` externally. Each call to
+/// `clone_handle()` returns another handle to the same `GLock`, and the `GLock` is dropped when its
+/// last handle is dropped.
+///
+/// A `SharedGLock` dereferences to the underlying `GLock`, so it can be locked (and used as a
+/// parent) exactly like a plain `GLock`. A plain `GLock` can be converted into a `SharedGLock`
+/// using `GLock::into_shared()` or `SharedGLock::from()`, and converted back using
+/// `SharedGLock::try_unwrap()` once it has a single handle left.
+///
+/// # Example
+///
+/// ```
+/// use glock::GLock;
+/// use std::thread;
+///
+/// let lock = GLock::new_root(0u32).unwrap().into_shared();
+///
+/// let handle = lock.clone_handle();
+/// thread::spawn(move || { *handle.lock_exclusive().unwrap() += 1; }).join().unwrap();
+///
+/// assert_eq!(lock.get(), Ok(1));
+/// ```
+#[derive(Debug)]
+pub struct SharedGLock<T, I: LockId = Id> {
+    lock: Arc<GLock<T, I>>,
+}
+
+impl<T, I: LockId> SharedGLock<T, I> {
+
+    /// Returns another handle to the same `GLock`. This only increments a reference count; neither
+    /// the lock nor the protected data is cloned.
+    pub fn clone_handle(&self) -> SharedGLock<T, I> {
+        SharedGLock { lock: self.lock.clone() }
+    }
+
+    /// Returns the number of live handles to the current `GLock`.
+    pub fn handle_count(&self) -> usize {
+        Arc::strong_count(&self.lock)
+    }
+
+    /// Returns `true` if both handles refer to the same `GLock`.
+    pub fn same_lock(&self, other: &SharedGLock<T, I>) -> bool {
+        Arc::ptr_eq(&self.lock, &other.lock)
+    }
+
+    /// Attempts to convert the specified handle back into a plain `GLock`. If other handles to the
+    /// same `GLock` are still alive, the handle is returned back unchanged in `Err`.
+    pub fn try_unwrap(this: SharedGLock<T, I>) -> Result<GLock<T, I>, SharedGLock<T, I>> {
+        Arc::try_unwrap(this.lock).map_err(|lock| SharedGLock { lock })
+    }
+}
+
+impl<T, I: LockId> From<GLock<T, I>> for SharedGLock<T, I> {
+    fn from(lock: GLock<T, I>) -> Self {
+        SharedGLock { lock: Arc::new(lock) }
+    }
+}
+
+impl<T, I: LockId> Clone for SharedGLock<T, I> {
+    fn clone(&self) -> Self { self.clone_handle() }
+}
+
+impl<T, I: LockId> Deref for SharedGLock<T, I> {
+    type Target = GLock<T, I>;
+
+    fn deref(&self) -> &<Self as Deref>::Target {
+        &self.lock
+    }
+}
+
+
+/// A declarative description of a tree of `GLock`s, built using `GLockBuilder::build_tree()`. Each
+/// `LockSpec` describes the data protected by a `GLock` and the specs of its child `GLock`s.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LockSpec<T> {
+    /// The data protected by the `GLock`.
+    pub data: T,
+
+    /// The specs of the child `GLock`s.
+    pub children: Vec<LockSpec<T>>,
+}
+
+impl<T> LockSpec<T> {
+
+    /// Creates a `LockSpec` for a `GLock` protecting the specified data, with the specified
+    /// child `GLock`s.
+    pub fn new(data: T, children: Vec<LockSpec<T>>) -> LockSpec<T> {
+        LockSpec { data, children }
+    }
+
+    /// Creates a `LockSpec` for a `GLock` protecting the specified data, without child `GLock`s.
+    pub fn leaf(data: T) -> LockSpec<T> {
+        LockSpec::new(data, Vec::new())
+    }
+}
+
+/// The data protected by each `GLock` of a tree built using `GLockBuilder::build_tree()`: the data
+/// of its `LockSpec`, and its child `GLock`s, in the order of their specs. The child `GLock`s can
+/// be locked using the parent `GLockGuard`, like any nested `GLock`.
+#[derive(Debug)]
+pub struct LockNode<T> {
+    /// The data of the `LockSpec` this node was built from.
+    pub data: T,
+
+    /// The child `GLock`s.
+    pub children: Vec<GLock<LockNode<T>>>,
+}
+
+/// A `GLockMap` is a map of child `GLock`s of the same parent `GLock`, keyed by values of type
+/// `K`. Child `GLock`s are created on demand using `child_entry()`, and are linked to the parent
+/// `GLock` like those created using `GLock::new_child()`. This removes the boilerplate of managing
+/// a `HashMap<K, GLock<V>>` of child `GLock`s by hand.
+///
+/// A `GLockMap` is created using `GLock::new_child_map()`, or `GLockBuilder::new_child_map()` when
+/// it is placed inside the data protected by the parent `GLock`. In the latter case, adding or
+/// removing child `GLock`s requires an `Exclusive` lock on the parent `GLock`, while locking the
+/// existing child `GLock`s only requires the appropriate intention lock.
+///
+/// # Example
+///
+/// ```
+/// use glock::{ GLockBuilder, GLockMap, LockType };
+///
+/// let parent_builder = GLockBuilder::new_root_builder();
+/// let map: GLockMap<&str, u32> = parent_builder.new_child_map();
+/// let parent_lock = parent_builder.build(map).unwrap();
+///
+/// parent_lock.lock_exclusive().unwrap().child_entry("a", || 0).unwrap();
+///
+/// let p_guard = parent_lock.lock(LockType::IntentionExclusive).unwrap();
+/// *p_guard.get("a").unwrap().lock_exclusive_using_parent(&p_guard).unwrap() += 1;
+/// ```
+#[derive(Debug)]
+pub struct GLockMap<K: Eq + Hash, V> {
+    parent: LockKernelRc<Id>,
+    children: HashMap<K, GLock<V>>,
+}
+
+impl<K: Eq + Hash, V> GLockMap<K, V> {
+
+    fn new(parent: LockKernelRc<Id>) -> GLockMap<K, V> {
+        GLockMap { parent, children: HashMap::new() }
+    }
+
+    /// Returns the child `GLock` for the specified key, creating it first if it does not exist,
+    /// protecting the data returned by `default`. New child `GLock`s inherit the policies of the
+    /// parent `GLock` (see `GLock::new_child_builder()`).
+    pub fn child_entry<F: FnOnce() -> V>(&mut self, key: K, default: F) -> LockResult<&GLock<V>> {
+        match self.children.entry(key) {
+            Entry::Occupied(entry) => Ok(entry.into_mut()),
+
+            Entry::Vacant(entry) => {
+                let options = self.parent.options().inherited();
+
+                self.parent
+                    .new_auto_child()
+                    .and_then(|kernel| GLockBuilder::new(kernel, options).build(default()))
+                    .map(|child| &*entry.insert(child))
+            },
+        }
+    }
+
+    /// Returns the child `GLock` for the specified key, or `None` if it does not exist.
+    pub fn get<Q: ?Sized + Eq + Hash>(&self, key: &Q) -> Option<&GLock<V>> where K: Borrow<Q> {
+        self.children.get(key)
+    }
+
+    /// Returns `true` if a child `GLock` exists for the specified key.
+    pub fn contains_key<Q: ?Sized + Eq + Hash>(&self, key: &Q) -> bool where K: Borrow<Q> {
+        self.children.contains_key(key)
+    }
+
+    /// Removes the child `GLock` for the specified key from the map and returns it, or `None` if it
+    /// does not exist. The child `GLock` stays linked to the parent `GLock` until it is dropped.
+    pub fn remove<Q: ?Sized + Eq + Hash>(&mut self, key: &Q) -> Option<GLock<V>> where K: Borrow<Q> {
+        self.children.remove(key)
+    }
+
+    /// Returns the number of child `GLock`s in the map.
+    pub fn len(&self) -> usize {
+        self.children.len()
+    }
+
+    /// Returns `true` if the map contains no child `GLock`s.
+    pub fn is_empty(&self) -> bool {
+        self.children.is_empty()
+    }
+
+    /// Returns an iterator over the keys of the map, in arbitrary order.
+    pub fn keys(&self) -> Keys<'_, K, GLock<V>> {
+        self.children.keys()
+    }
+
+    /// Returns an iterator over the keys and child `GLock`s of the map, in arbitrary order.
+    pub fn iter(&self) -> Iter<'_, K, GLock<V>> {
+        self.children.iter()
+    }
+}
+
+impl<'a, K: Eq + Hash, V> IntoIterator for &'a GLockMap<K, V> {
+    type Item = (&'a K, &'a GLock<V>);
+    type IntoIter = Iter<'a, K, GLock<V>>;
+
+    fn into_iter(self) -> Self::IntoIter { self.iter() }
+}
+
+
+/// Acquires `Exclusive` locks on all the specified `GLock`s, blocking until all of them are
+/// acquired. This is mainly intended for root `GLock`s, which do not share a common parent that
+/// can be used to coordinate them.
+///
+/// To avoid deadlocks between threads locking the same `GLock`s in different orders, locks are
+/// always acquired in a consistent global order (see `GLock::global_seq()`), regardless of the
+/// order in which they are specified. The returned `GLockGuardMut`s are in the same order as the
+/// specified `GLock`s.
+///
+/// If any of the locks fails to be acquired, all locks acquired so far are released. If the same
+/// `GLock` is specified more than once, a `LockError::DuplicateLock` error is returned.
+#[must_use = "the lock is released as soon as the guard is dropped"]
+pub fn lock_all_exclusive<'lck, T, I: LockId>(locks: &[&'lck GLock<T, I>]) -> LockResult<Vec<GLockGuardMut<'lck, T, I>>> {
+    let mut order: Vec<usize> = (0..locks.len()).collect();
+    order.sort_by_key(|i| locks[*i].kernel.order_key());
+
+    if order.windows(2).any(|w| locks[w[0]].kernel.ptr_eq(&locks[w[1]].kernel)) {
+        return Err(LockError::DuplicateLock);
+    }
+
+    let mut guards: Vec<Option<GLockGuardMut<'lck, T, I>>> = locks.iter().map(|_| None).collect();
+
+    for i in order {
+        guards[i] = Some(locks[i].lock_exclusive()?);
+    }
+
+    Ok(guards.into_iter().map(|g| g.unwrap()).collect())
+}
+
+
+// Makes guards `!Send` while keeping them `Sync`. The kernel attributes each lock to the thread
+// holding its guard, e.g. to detect upgrades that would wait for the current thread itself, so a
+// guard must not change threads behind its back; `LockToken`s are used to move locks instead.
+#[derive(Debug, Copy, Clone, Default)]
+struct NotSend(PhantomData<*const ()>);
+
+unsafe impl Sync for NotSend {}
+
+/// A `GLockGuard` represents an acquired lock instance of any type. It can be used to access the
+/// protected data. The lock is released by dropping the `GLockGuard` object.
+///
+/// A `GLockGuard` cannot be sent to another thread, since the lock is attributed to the thread that
+/// acquired it. Use `into_token()` to move the lock to another thread.
+#[must_use = "the lock is released as soon as the guard is dropped"]
+#[derive(Debug)]
+pub struct GLockGuard<'lck, T: 'lck, I: LockId = Id> {
+    lock: &'lck GLock<T, I>,
+    lock_instance: Arc<LockInstance<I>>,
+    _not_send: NotSend,
+}
+
+impl<'lck, T: 'lck, I: LockId> GLockGuard<'lck, T, I> {
+
+    fn new(lock: &'lck GLock<T, I>, lock_instance: Arc<LockInstance<I>>) -> GLockGuard<'lck, T, I> {
+        GLockGuard { lock, lock_instance, _not_send: NotSend::default() }
+    }
+
+    /// Returns the id of the `GLock` this `GLockGuard` belongs to, or `None` if it is a root
+    /// `GLock` (or was adopted, see `GLock::adopt_child()`). Ids are only unique among the
+    /// children of the same parent `GLock`.
+    pub fn lock_id(&self) -> Option<I> {
+        self.lock.kernel.id()
+    }
+
+    /// Returns the type of the lock currently held.
+    pub fn lock_type(&self) -> LockResult<LockType> {
+        Ok(self.lock_instance.lock_type())
+    }
+
+    /// Returns whether the lock currently held supports child locks of the specified type, i.e.
+    /// whether passing this `GLockGuard` to one of the `lock_using_parent` variants of a child
+    /// `GLock` with `child_type` would not upgrade it.
+    pub fn supports_child(&self, child_type: LockType) -> bool {
+        self.lock_instance.lock_type().supports_children(child_type)
+    }
+
+    /// Returns whether the lock currently held is at least as strong as the specified type, i.e.
+    /// whether a lock of type `at_least` is upgradable to it (see `LockType::upgradable_to()`). For
+    /// example, `holds_at_least(LockType::Shared)` checks for read access to the protected data,
+    /// which is granted by `Shared`, `SharedIntentionExclusive` and `Exclusive` locks.
+    pub fn holds_at_least(&self, at_least: LockType) -> LockResult<bool> {
+        self.lock_type().map(|lock_type| at_least.upgradable_to(lock_type))
+    }
+
+    /// Attempts to acquire an additional lock of the specified type on the same `GLock`, e.g. an
+    /// `IntentionShared` lock for a sub-task while holding a `SharedIntentionExclusive` lock. The
+    /// additional lock uses the parent lock of this `GLockGuard` (if any) as its own parent lock,
+    /// so no implicit lock is acquired on the ancestors. Both `GLockGuard`s can be dropped in any
+    /// order.
+    ///
+    /// If the shared parent lock does not support `lock_type`, it is upgraded (e.g. an implicit
+    /// `IntentionShared` parent lock is upgraded to `IntentionExclusive` for an additional
+    /// `IntentionExclusive` lock), and relaxed again once the additional lock is released.
+    ///
+    /// If `lock_type` is not compatible with the type of this `GLockGuard`, it will return a
+    /// `LockError::WouldSelfDeadlock` error. If the lock is busy for other reasons, it will return
+    /// a `LockError::LockBusy` error.
+    #[must_use = "the lock is released as soon as the guard is dropped"]
+    pub fn try_acquire_additional(&self, lock_type: LockType) -> LockResult<GLockGuard<'lck, T, I>> {
+        if !self.lock_type()?.compatible_with(lock_type) { return Err(LockError::WouldSelfDeadlock); }
+
+        self.lock.do_lock(lock_type, self.lock_instance.parent(), Wait::TryOnly)
+    }
+
+    /// Returns a reference to the protected data along with the type of the lock currently held,
+    /// so that helpers can adapt to (or validate) the lock level they were handed.
+    pub fn get_with_type(&self) -> LockResult<(&T, LockType)> {
+        self.lock_type().map(|lock_type| (self.deref(), lock_type))
+    }
+
+    /// Returns a copy of the protected data and releases the lock, which is handy for reading a
+    /// single value from an already held `GLockGuard` (e.g. one that was conditionally upgraded).
+    /// To read the value without holding a `GLockGuard`, use `GLock::get()` instead.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use glock::GLock;
+    ///
+    /// let lock = GLock::new_root(42u32).unwrap();
+    /// let value = lock.lock_shared().unwrap().copy_out();
+    ///
+    /// assert_eq!(value, 42);
+    /// assert!(lock.try_lock_exclusive().is_ok());
+    /// ```
+    pub fn copy_out(self) -> T where T: Copy {
+        *self
+    }
+
+    /// Returns a mutable reference to the protected data if the lock currently held is
+    /// `Exclusive`, e.g. after calling `upgrade(LockType::Exclusive)`, or `None` otherwise. This
+    /// allows mutating the data after a non-consuming upgrade, which keeps the `GLockGuard` type.
+    ///
+    /// This takes `&mut self`, so that the returned reference cannot coexist with any other
+    /// reference to the data obtained through this `GLockGuard`.
+    pub fn upgraded_mut(&mut self) -> Option<&mut T> {
+        match self.lock_type() {
+            Ok(LockType::Exclusive) => Some(unsafe { &mut *self.lock.data_ptr() }),
+            _                       => None,
+        }
+    }
+
+    /// Returns the automatic upgrade of the parent lock performed while acquiring this lock, if
+    /// any. This happens when the lock is acquired using a parent `GLockGuard` whose type does not
+    /// support the requested lock type, e.g. an `Exclusive` lock using a `Shared` parent lock,
+    /// which upgrades the parent lock to `SharedIntentionExclusive`. The upgraded parent lock
+    /// reduces the concurrency of all other locks under the parent `GLock`, which may be worth
+    /// logging or avoiding by acquiring the parent lock with the appropriate type up front.
+    ///
+    /// The parent lock is relaxed back to its original type once no remaining child lock held
+    /// using it requires the upgrade, i.e. when this lock is released or downgraded.
+    pub fn parent_upgrade(&self) -> Option<ParentUpgrade> {
+        self.lock_instance.parent_upgrade()
+    }
+
+    /// Returns the counts this `GLockGuard` contributes to `GLock::lock_counts()`, indexed by
+    /// `LockType::index()`. The implicit locks held on the ancestors are not included.
+    ///
+    /// This is only available in tests and with the `testing` feature.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn count_contribution(&self) -> LockResult<[usize; LOCK_TYPE_COUNT]> {
+        Ok(self.lock_instance.count_contribution())
+    }
+
+    /// Returns the time at which the lock held by this `GLockGuard` was acquired. Upgrading or
+    /// downgrading the lock does not change it.
+    ///
+    /// This is only available with the `timestamps` feature.
+    #[cfg(feature = "timestamps")]
+    pub fn acquired_at(&self) -> Instant {
+        self.lock_instance.acquired_at()
+    }
+
+    /// Returns a `ParentGuard` sharing the lock held on the parent `GLock` on behalf of this
+    /// `GLockGuard`, or `None` if this is a root `GLock`. This is mainly useful when the parent
+    /// lock was acquired implicitly (e.g. using `lock()`), so that siblings of this `GLock` can be
+    /// locked using the `*_using_parent` methods without acquiring another parent lock.
+    ///
+    /// The parent lock stays held until both this `GLockGuard` and the returned `ParentGuard` are
+    /// dropped.
+    pub fn take_parent_guard(&self) -> Option<ParentGuard<I>> {
+        self.lock_instance
+            .parent()
+            .map(|lock_instance| ParentGuard { lock_instance, _not_send: NotSend::default() })
+    }
+
+    /// Swaps the data protected by the specified child `GLock`s, using this `GLockGuard` as their
+    /// parent lock. `Exclusive` locks are acquired on both children, blocking until they are ready,
+    /// and released once their data is swapped. Like `lock_all_exclusive()`, the children are
+    /// always locked in a consistent global order to avoid deadlocks.
+    ///
+    /// If both children are the same `GLock`, it will return a `LockError::DuplicateLock` error.
+    pub fn swap_children<U>(&self, a: &GLock<U, I>, b: &GLock<U, I>) -> LockResult<()> {
+        if a.kernel.ptr_eq(&b.kernel) { return Err(LockError::DuplicateLock); }
+
+        let (first, second) = if a.kernel.order_key() < b.kernel.order_key() { (a, b) } else { (b, a) };
+
+        let mut first_lg = first.lock_exclusive_using_parent(self)?;
+        let mut second_lg = second.lock_exclusive_using_parent(self)?;
+
+        mem::swap(&mut *first_lg, &mut *second_lg);
+        Ok(())
+    }
+
+    /// Returns `true` if this `GLockGuard` and the specified `GLockGuard` belong to the same
+    /// `GLock`, `false` otherwise.
+    pub fn same_lock<U>(&self, other: &GLockGuard<U, I>) -> bool {
+        self.lock.kernel.ptr_eq(&other.lock.kernel)
+    }
+
+    /// Upgrades the type of this `GLockGuard` to the specified type. If parent lock does not support
+    /// the new type, it will be upgraded as well. If the lock is currently busy, it will block until
+    /// it is ready.
+    ///
+    /// If the only locks preventing the upgrade are held by the current thread, it will return a
+    /// `LockError::WouldSelfDeadlock` error instead of blocking forever. Likewise, if another
+    /// thread is already waiting to upgrade a lock on this `GLock`, and each upgrade waits for the
+    /// other one's lock (e.g. two `Shared` locks both upgrading to `Exclusive`), it will return a
+    /// `LockError::ConflictingUpgrade` error, leaving the type of this `GLockGuard` unchanged. The
+    /// other upgrade proceeds once this `GLockGuard` is dropped.
+    ///
+    /// While the upgrade is blocked, new acquisitions of lock types that are incompatible with the
+    /// requested type wait behind it, so a steady stream of readers cannot starve it. Threads that
+    /// already hold a lock on the same `GLock` (e.g. a reader locking one of its children) are not
+    /// held back, since the upgrade waits for them anyway. Blocking upgrades of
+    /// `upgrade_to_exclusive()`, `upgrade_if()` and the timeout variants behave the same.
+    ///
+    /// Requesting a type that is less restrictive than the current one (e.g. `Shared` while
+    /// holding `Exclusive`) returns a `LockError::UpgradeToLowerType` error; use `downgrade()`
+    /// instead.
+    pub fn upgrade(&self, to_type: LockType) -> LockResult<()> {
+        self.lock_instance.upgrade(to_type, true, Wait::Block)
+    }
+
+    /// Attempts to upgrade the type of this `GLockGuard` to the specified type. If parent lock
+    /// does not support the new type, it will be upgraded as well. If the lock is currently busy,
+    /// it will return a `LockError::LockBusy` error.
+    ///
+    /// If an error is returned, the type of the lock held by this `GLockGuard` is left unchanged,
+    /// so `lock_type()` keeps reporting the original type. A parent lock that was upgraded for the
+    /// new type before the failure is relaxed back to the type it had before.
+    pub fn try_upgrade(&self, to_type: LockType) -> LockResult<()> {
+        self.lock_instance.upgrade(to_type, true, Wait::TryOnly)
+    }
+
+    /// Upgrades the type of this `GLockGuard` to the specified type, blocking for at most the
+    /// specified `timeout`. If the lock is still busy after the timeout expires, it will return a
+    /// `LockError::Timeout` error, leaving the type of this `GLockGuard` unchanged.
+    pub fn upgrade_timeout(&self, to_type: LockType, timeout: Duration) -> LockResult<()> {
+        self.lock_instance.upgrade(to_type, true, Wait::timeout(timeout))
+    }
+
+    /// Upgrades the type of this `GLockGuard` to `Exclusive`. If parent lock does not support
+    /// the new type, it will be upgraded as well. If the lock is currently busy, it will block until
+    /// it is ready.
+    ///
+    /// This method consumes the current `GLockGuard` and returns a new `GLockGuardMut`.
+    /// In case of failure, it will return a tuple containing the error as well as the original
+    /// `GLockGuard`.
+    #[must_use = "the lock is released as soon as the guard is dropped"]
+    pub fn upgrade_to_exclusive(self) -> Result<GLockGuardMut<'lck, T, I>, (LockError, GLockGuard<'lck, T, I>)> {
+        match self.lock_instance.upgrade(LockType::Exclusive, true, Wait::Block) {
+            Ok(_)   => { Ok(GLockGuardMut::new(self)) },
+            Err(e)  => { Err((e, self)) },
+        }
+    }
+
+    /// Attempts to upgrade the type of this `GLockGuard` to `Exclusive`. If parent lock does not support
+    /// the new type, it will be upgraded as well. If the lock is currently busy, If the lock is
+    /// currently busy, it will return a `LockError::LockBusy` error.
+    ///
+    /// This method consumes the current `GLockGuard` and returns a new `GLockGuardMut`.
+    /// In case of failure, it will return a tuple containing the error as well as the original
+    /// `GLockGuard`.
+    #[must_use = "the lock is released as soon as the guard is dropped"]
+    pub fn try_upgrade_to_exclusive(self) -> Result<GLockGuardMut<'lck, T, I>, (LockError, GLockGuard<'lck, T, I>)> {
+        match self.lock_instance.upgrade(LockType::Exclusive, true, Wait::TryOnly) {
+            Ok(_)   => { Ok(GLockGuardMut::new(self)) },
+            Err(e)  => { Err((e, self)) },
+        }
+    }
+
+    /// Evaluates the specified predicate on the protected data and, if it holds, upgrades the type
+    /// of this `GLockGuard` to `Exclusive`, blocking until the lock is ready. This allows checking a
+    /// condition under a `Shared` lock and only then mutating the data, without ever releasing the
+    /// lock in between.
+    ///
+    /// The result of the predicate is still valid after the upgrade: while the upgrade waits for
+    /// other locks to be released, those can only be readers (or intention locks), because no
+    /// `Exclusive` lock can be acquired on this `GLock` or its ancestors while this `GLockGuard` is
+    /// held, so the protected data cannot change.
+    ///
+    /// Note that if two threads call this at the same time on `Shared` locks of the same `GLock`,
+    /// one of them fails with `LockError::ConflictingUpgrade`, as with any concurrent upgrade (see
+    /// `upgrade()`), and has to drop its `GLockGuard` for the other one to proceed.
+    ///
+    /// This method consumes the current `GLockGuard` and returns a new `GLockGuardMut`. If the
+    /// predicate does not hold, it will return a tuple containing `None` as well as the original
+    /// `GLockGuard`. If the upgrade fails, the tuple contains the error instead.
+    #[allow(clippy::type_complexity)]
+    #[must_use = "the lock is released as soon as the guard is dropped"]
+    pub fn upgrade_if<F: Fn(&T) -> bool>(self, pred: F) -> Result<GLockGuardMut<'lck, T, I>, (Option<LockError>, GLockGuard<'lck, T, I>)> {
+        if !pred(&self) { return Err((None, self)); }
+
+        self.upgrade_to_exclusive().map_err(|(e, lg)| (Some(e), lg))
+    }
+
+    /// Upgrades the type of this `GLockGuard` to `Exclusive`, blocking for at most the specified
+    /// `timeout`. The implicit upgrades of ancestor locks are performed within the same timeout.
+    ///
+    /// This method consumes the current `GLockGuard` and returns a new `GLockGuardMut`.
+    /// In case of failure (e.g. a `LockError::Timeout` error), it will return a tuple containing
+    /// the error as well as the original `GLockGuard`, which still holds its original lock type.
+    #[must_use = "the lock is released as soon as the guard is dropped"]
+    pub fn upgrade_to_exclusive_timeout(self, timeout: Duration) -> Result<GLockGuardMut<'lck, T, I>, (LockError, GLockGuard<'lck, T, I>)> {
+        match self.lock_instance.upgrade(LockType::Exclusive, true, Wait::timeout(timeout)) {
+            Ok(_)   => { Ok(GLockGuardMut::new(self)) },
+            Err(e)  => { Err((e, self)) },
+        }
+    }
+
+    /// Releases the lock held by this `GLockGuard`, calls the specified function, then acquires a
+    /// lock of the same type again, blocking until it is ready, and returns the function's result.
+    /// This avoids holding the lock across slow operations (e.g. blocking IO) while keeping the
+    /// `GLockGuard`. The protected data may have been changed by other threads while the lock was
+    /// released, so anything derived from it before calling this must be checked again.
+    ///
+    /// The lock held on the parent `GLock` (implicitly, or through the parent `GLockGuard` this
+    /// lock was acquired with) is kept while the lock is released, and the parent lock type is
+    /// checked again before re-acquiring it. The default timeout of the `GLock` does not apply to
+    /// re-acquiring the lock.
+    ///
+    /// The lock is acquired again even if the `GLock` was closed (see `GLock::close()`) or reset
+    /// (see `GLock::force_reset()`) meanwhile, since the `GLockGuard` remains usable. Moving the
+    /// `GLock` using `GLock::reparent()` or `GLock::adopt_child()` waits until the lock is acquired
+    /// again (and released).
+    ///
+    /// Returns `LockError::ChildLocksHeld` without releasing the lock if child locks or
+    /// `ParentGuard`s still use this `GLockGuard` as their parent lock.
+    pub fn unlocked<R, F: FnOnce() -> R>(&mut self, f: F) -> LockResult<R> {
+        self.lock_instance.suspend()?;
+
+        // If `f` panics, the lock instance is dropped while suspended, which releases nothing.
+        let result = f();
+
+        // Resuming ignores closing and resets, the parent lock is kept, and the `GLock` cannot be
+        // moved to another parent while the lock is suspended, so this never fails.
+        self.lock_instance.resume().expect("failed to re-acquire lock after unlocked()");
+        Ok(result)
+    }
+
+    /// Detaches this `GLockGuard` into a `LockToken`, without releasing the lock. The `LockToken`
+    /// can be sent to another thread and converted back into a `GLockGuard` using
+    /// `GLock::from_token()`.
+    pub fn into_token(self) -> LockToken<I> {
+        // Dropping the guard while the token shares the lock detaches it from the current thread.
+        LockToken { lock_instance: self.lock_instance.clone() }
+    }
+}
+
+impl<'lck, U, I: LockId> GLockGuard<'lck, Vec<GLock<U, I>>, I> {
+
+    /// Acquires `Shared` locks on all the child `GLock`s in the protected `Vec`, using this
+    /// `GLockGuard` as their parent lock, which gives a consistent snapshot of all of them. The
+    /// locks are acquired in a consistent global order (see `GLock::global_seq()`), which is the
+    /// order of their ids for automatically assigned ids, while the returned `GLockGuard`s are in
+    /// the same order as the `GLock`s in the `Vec`.
+    ///
+    /// If any of the locks fails to be acquired, all locks acquired so far are released. If this
+    /// `GLockGuard` does not support `Shared` child locks, it is upgraded as for
+    /// `lock_using_parent()`.
+    #[must_use = "the lock is released as soon as the guard is dropped"]
+    pub fn lock_all_children_shared(&self) -> LockResult<Vec<GLockGuard<'_, U, I>>> {
+        let children: &Vec<GLock<U, I>> = self;
+
+        let mut order: Vec<usize> = (0..children.len()).collect();
+        order.sort_by_key(|i| children[*i].kernel.order_key());
+
+        let mut guards: Vec<Option<GLockGuard<'_, U, I>>> = children.iter().map(|_| None).collect();
+
+        for i in order {
+            guards[i] = Some(children[i].lock_using_parent(LockType::Shared, self)?);
+        }
+
+        Ok(guards.into_iter().map(|g| g.unwrap()).collect())
+    }
+}
+
+impl<'lck, T: 'lck, I: LockId> GLockGuard<'lck, Pin<Box<T>>, I> {
+
+    /// Returns a pinned reference to the protected data. See `GLock::new_root_pinned()`.
+    pub fn as_pin(&self) -> Pin<&T> {
+        (**self).as_ref()
+    }
+}
+
+impl<'lck, T: 'lck, I: LockId> Drop for GLockGuard<'lck, T, I> {
+
+    // The lock is only released once every `Arc` of its instance is dropped. If it is still shared
+    // (e.g. by a `LockToken` or by a child lock held in another thread), it is no longer held on
+    // behalf of the current thread.
+    fn drop(&mut self) {
+        if Arc::strong_count(&self.lock_instance) > 1 { self.lock_instance.detach(); }
+    }
+}
+
+impl<'lck, T: 'lck, I: LockId> Deref for GLockGuard<'lck, T, I> {
+    type Target = T;
+
+    fn deref(&self) -> &<Self as Deref>::Target {
+        unsafe { &*self.lock.data_ptr() }
+    }
+}
+
+/// A `GLockGuard` represents an acquired `Exclusive` lock instance. It can be used to read as well
+/// as mutate  the protected data. The lock is released by dropping the `GLockGuardMut` object.
+#[must_use = "the lock is released as soon as the guard is dropped"]
+#[derive(Debug)]
+pub struct GLockGuardMut<'lck, T: 'lck, I: LockId = Id> {
+    lock_guard: GLockGuard<'lck, T, I>,
+}
+
+impl<'lck, T: 'lck, I: LockId> GLockGuardMut<'lck, T, I> {
+
+    fn new(lock_guard: GLockGuard<'lck, T, I>) -> GLockGuardMut<'lck, T, I> {
+        debug_assert!(matches!(lock_guard.lock_type(), Ok(LockType::Exclusive)), "GLockGuardMut requires an Exclusive lock");
+        GLockGuardMut { lock_guard }
+    }
+
+    /// Splits the protected data into two mutable references, using the specified functions to
+    /// project a pointer to the data into pointers to two of its parts (e.g. fields). This allows
+    /// handing each part to a different helper while holding a single `Exclusive` lock.
+    ///
+    /// When the parts are fields of a `struct`, prefer the safe `split_fields_mut!` macro.
+    ///
+    /// # Safety
+    ///
+    /// The pointers returned by `fa` and `fb` must point to valid, non-overlapping parts of the
+    /// protected data.
+    pub unsafe fn split2<A, B, FA, FB>(&mut self, fa: FA, fb: FB) -> (&mut A, &mut B)
+        where FA: FnOnce(*mut T) -> *mut A, FB: FnOnce(*mut T) -> *mut B {
+
+        let data = self.lock_guard.lock.data_ptr();
+        (&mut *fa(data), &mut *fb(data))
+    }
+
+    /// Returns the automatic upgrade of the parent lock performed while acquiring this lock, if
+    /// any. See `GLockGuard::parent_upgrade()`.
+    pub fn parent_upgrade(&self) -> Option<ParentUpgrade> {
+        self.lock_guard.parent_upgrade()
+    }
+
+    /// Returns the time at which the lock held by this `GLockGuardMut` was acquired. See
+    /// `GLockGuard::acquired_at()`.
+    #[cfg(feature = "timestamps")]
+    pub fn acquired_at(&self) -> Instant {
+        self.lock_guard.acquired_at()
+    }
+
+    /// Returns a `ParentGuard` sharing the lock held on the parent `GLock` on behalf of this
+    /// `GLockGuardMut`. See `GLockGuard::take_parent_guard()`.
+    pub fn take_parent_guard(&self) -> Option<ParentGuard<I>> {
+        self.lock_guard.take_parent_guard()
+    }
+
+    /// Downgrades this `GLockGuardMut` to the specified type, without releasing the lock, and
+    /// returns a read-only `GLockGuard` of that type. Every lock type is reachable downward from
+    /// `Exclusive`, so this allows shedding privileges progressively, e.g. downgrading to
+    /// `SharedIntentionExclusive` keeps other writers out of the protected data and still allows
+    /// `Exclusive` child locks, while letting other threads read the children that are not locked.
+    ///
+    /// If child locks are still held using this `GLockGuardMut` as their parent lock, the requested
+    /// type must support their types (see `LockType::supports_children()`).
+    ///
+    /// In case of failure (i.e. a `LockError::InvalidDowngrade` error), it will return a tuple
+    /// containing the error as well as the original `GLockGuardMut`.
+    #[must_use = "the lock is released as soon as the guard is dropped"]
+    pub fn downgrade_to(self, to_type: LockType) -> Result<GLockGuard<'lck, T, I>, (LockError, GLockGuardMut<'lck, T, I>)> {
+        match self.lock_guard.lock_instance.downgrade(to_type) {
+            Ok(_)   => { Ok(self.lock_guard) },
+            Err(e)  => { Err((e, self)) },
+        }
+    }
+
+    /// Releases the lock held by this `GLockGuardMut`, calls the specified function, then acquires
+    /// an `Exclusive` lock again before returning. See `GLockGuard::unlocked()`.
+    pub fn unlocked<R, F: FnOnce() -> R>(&mut self, f: F) -> LockResult<R> {
+        self.lock_guard.unlocked(f)
+    }
+
+    /// Detaches this `GLockGuardMut` into a `LockToken`, without releasing the lock. See
+    /// `GLockGuard::into_token()`.
+    pub fn into_token(self) -> LockToken<I> {
+        self.lock_guard.into_token()
+    }
+
+    /// Calls the specified function with a mutable slice of the protected data, for data types that
+    /// can be viewed as a slice (e.g. `Vec<U>`, arrays or boxed slices), and returns its result.
+    /// The slice can then be split into disjoint mutable subslices using the usual slice methods
+    /// (e.g. `split_at_mut()` or `chunks_mut()`), which can be processed independently (e.g. in
+    /// parallel) while holding a single `Exclusive` lock.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use glock::GLock;
+    ///
+    /// let lock = GLock::new_root(vec![1u32, 2, 3, 4, 5]).unwrap();
+    /// let mut guard = lock.lock_exclusive().unwrap();
+    ///
+    /// let sums: Vec<u32> = guard.with_slice(|data| {
+    ///     data.chunks_mut(2)
+    ///         .map(|chunk| {
+    ///             chunk.iter_mut().for_each(|x| *x *= 10);
+    ///             chunk.iter().sum()
+    ///         })
+    ///         .collect()
+    /// });
+    ///
+    /// assert_eq!(sums, vec![30, 70, 50]);
+    /// assert_eq!(*guard, vec![10, 20, 30, 40, 50]);
+    /// ```
+    pub fn with_slice<U, R, F: FnOnce(&mut [U]) -> R>(&mut self, f: F) -> R where T: AsMut<[U]> {
+        f(self.deref_mut().as_mut())
+    }
+
+    /// Returns a read-only reference to the protected data. This is equivalent to dereferencing
+    /// the `GLockGuardMut`, but reads better when passing the data to helpers that only need read
+    /// access.
+    pub fn as_read(&self) -> &T {
+        self.lock_guard.deref()
+    }
+
+    /// Returns a read-only `GLockGuardRef` borrowed from this `GLockGuardMut`. The returned guard
+    /// does not touch the lock kernel (i.e. it does not acquire any additional lock), since the
+    /// `Exclusive` lock is already held. The protected data cannot be mutated through this
+    /// `GLockGuardMut` until the returned `GLockGuardRef` is dropped.
+    pub fn reborrow_shared(&self) -> GLockGuardRef<'_, T, I> {
+        GLockGuardRef { lock_guard: &self.lock_guard }
+    }
+}
+
+impl<'lck, T: 'lck, I: LockId> GLockGuardMut<'lck, Pin<Box<T>>, I> {
+
+    /// Returns a pinned reference to the protected data. See `GLock::new_root_pinned()`.
+    pub fn as_pin(&self) -> Pin<&T> {
+        (**self).as_ref()
+    }
+
+    /// Returns a pinned mutable reference to the protected data. See `GLock::new_root_pinned()`.
+    pub fn as_pin_mut(&mut self) -> Pin<&mut T> {
+        (**self).as_mut()
+    }
+}
+
+impl<'lck, T: 'lck, I: LockId> Deref for GLockGuardMut<'lck, T, I> {
+    type Target = T;
+    fn deref(&self) -> &<Self as Deref>::Target { self.lock_guard.deref() }
+}
+
+impl<'lck, T: 'lck, I: LockId> DerefMut for GLockGuardMut<'lck, T, I> {
+    fn deref_mut(&mut self) -> &mut <Self as Deref>::Target {
+        unsafe { &mut *self.lock_guard.lock.data_ptr() }
+    }
+}
+
+/// The guard returned by `GLock::lock_exclusive_or_timeout_shared()`, holding either the
+/// `Exclusive` lock or the `Shared` lock it fell back to. Both variants can be used to read the
+/// protected data, but only `Exclusive` allows mutating it.
+#[must_use = "the lock is released as soon as the guard is dropped"]
+#[derive(Debug)]
+pub enum ExclusiveOrShared<'lck, T: 'lck, I: LockId = Id> {
+    /// The `Exclusive` lock was acquired before the timeout expired.
+    Exclusive(GLockGuardMut<'lck, T, I>),
+
+    /// The timeout expired, and a `Shared` lock was acquired instead.
+    Shared(GLockGuard<'lck, T, I>),
+}
+
+impl<'lck, T: 'lck, I: LockId> ExclusiveOrShared<'lck, T, I> {
+
+    /// Returns `true` if the `Exclusive` lock was acquired.
+    pub fn is_exclusive(&self) -> bool {
+        matches!(self, ExclusiveOrShared::Exclusive(_))
+    }
+}
+
+impl<'lck, T: 'lck, I: LockId> Deref for ExclusiveOrShared<'lck, T, I> {
+    type Target = T;
+
+    fn deref(&self) -> &<Self as Deref>::Target {
+        match self {
+            ExclusiveOrShared::Exclusive(lock_guard)    => lock_guard.deref(),
+            ExclusiveOrShared::Shared(lock_guard)       => lock_guard.deref(),
+        }
+    }
+}
+
+/// A `GLockGuardRef` is a read-only view borrowed from a `GLockGuardMut` using `reborrow_shared()`.
+/// It does not hold a lock of its own; it is only valid as long as the `GLockGuardMut` it was
+/// borrowed from.
+#[derive(Debug)]
+pub struct GLockGuardRef<'grd, T: 'grd, I: LockId = Id> {
+    lock_guard: &'grd GLockGuard<'grd, T, I>,
+}
+
+impl<'grd, T: 'grd, I: LockId> GLockGuardRef<'grd, T, I> {
+
+    /// Returns the type of the lock currently held by the originating `GLockGuardMut`.
+    pub fn lock_type(&self) -> LockResult<LockType> {
+        self.lock_guard.lock_type()
+    }
+}
+
+impl<'grd, T: 'grd, I: LockId> Deref for GLockGuardRef<'grd, T, I> {
+    type Target = T;
+    fn deref(&self) -> &<Self as Deref>::Target { self.lock_guard.deref() }
+}
+
+/// A guard over a lock held on a parent `GLock`, which does not give access to its protected data.
+/// It is created using `GLockGuard::take_parent_guard()`, and can be passed to the
+/// `*_using_parent` methods of any child of that `GLock`. The lock is released once this
+/// `ParentGuard` and all the `GLockGuard`s sharing it are dropped.
+#[derive(Debug, Clone)]
+pub struct ParentGuard<I: LockId = Id> {
+    lock_instance: Arc<LockInstance<I>>,
+    _not_send: NotSend,
+}
+
+impl<I: LockId> ParentGuard<I> {
+
+    /// Returns the type of the lock currently held.
+    pub fn lock_type(&self) -> LockResult<LockType> {
+        Ok(self.lock_instance.lock_type())
+    }
+}
+
+/// Implemented by the guards that can be passed as parent guards to the `*_using_parent` methods
+/// of a child `GLock`, i.e. `GLockGuard`, `GLockGuardMut` and `ParentGuard`.
+pub trait AsParentGuard<I: LockId = Id> {
+
+    #[doc(hidden)]
+    fn parent_instance(&self) -> Arc<LockInstance<I>>;
+}
+
+impl<'lck, T: 'lck, I: LockId> AsParentGuard<I> for GLockGuard<'lck, T, I> {
+    fn parent_instance(&self) -> Arc<LockInstance<I>> { self.lock_instance.clone() }
+}
+
+impl<'lck, T: 'lck, I: LockId> AsParentGuard<I> for GLockGuardMut<'lck, T, I> {
+    fn parent_instance(&self) -> Arc<LockInstance<I>> { self.lock_guard.parent_instance() }
+}
+
+impl<I: LockId> AsParentGuard<I> for ParentGuard<I> {
+    fn parent_instance(&self) -> Arc<LockInstance<I>> { self.lock_instance.clone() }
+}
+
+/// A `LockToken` represents a held lock that is detached from its `GLock`, created using
+/// `GLockGuard::into_token()`. Unlike a `GLockGuard`, it does not borrow the `GLock`, so it can be
+/// sent to another thread, where it can be converted back into a `GLockGuard` using
+/// `GLock::from_token()`. The lock is held while the `LockToken` is in transit, and released if
+/// the `LockToken` is dropped.
+#[derive(Debug)]
+pub struct LockToken<I: LockId = Id> {
+    lock_instance: Arc<LockInstance<I>>,
+}
+
+impl<I: LockId> LockToken<I> {
+
+    /// Returns the type of the lock held by this `LockToken`.
+    pub fn lock_type(&self) -> LockResult<LockType> {
+        Ok(self.lock_instance.lock_type())
+    }
+}
+
+/// A `Transaction` acquires locks on several `GLock`s, possibly protecting data of different types,
+/// as a single unit. Locks are acquired in the order in which they are requested, and if any of
+/// them fails to be acquired, all the locks acquired so far by the `Transaction` are released in
+/// reverse order, so a failed `Transaction` never leaves a partial set of locks held.
+///
+/// Once all locks are acquired, `commit()` hands them over as `LockToken`s, which can be converted
+/// back into `GLockGuard`s using `GLock::from_token()`. Dropping the `Transaction` (or calling
+/// `abort()`) instead releases all of its locks in reverse order.
+///
+/// Unlike `GLock::transaction()`, which runs a function over the children of a single `GLock`, a
+/// `Transaction` can span unrelated `GLock`s, including root `GLock`s.
+#[must_use = "the locks are released as soon as the transaction is dropped"]
+#[derive(Debug)]
+pub struct Transaction<I: LockId = Id> {
+    tokens: Vec<LockToken<I>>,
+}
+
+impl<I: LockId> Transaction<I> {
+
+    /// Creates a new empty `Transaction`.
+    pub fn new() -> Transaction<I> {
+        Transaction { tokens: Vec::new() }
+    }
+
+    /// Acquires a lock of the specified type on the specified `GLock`, blocking until it is ready.
+    /// See `GLock::lock()`. If it fails, all the locks held by the `Transaction` are released.
+    pub fn acquire<T>(&mut self, lock: &GLock<T, I>, lock_type: LockType) -> LockResult<()> {
+        let result = lock.lock(lock_type);
+        self.record(result)
+    }
+
+    /// Attempts to acquire a lock of the specified type on the specified `GLock`, without blocking.
+    /// See `GLock::try_lock()`. If it fails (e.g. with a `LockError::LockBusy` error), all the
+    /// locks held by the `Transaction` are released.
+    pub fn try_acquire<T>(&mut self, lock: &GLock<T, I>, lock_type: LockType) -> LockResult<()> {
+        let result = lock.try_lock(lock_type);
+        self.record(result)
+    }
+
+    /// Returns the number of locks held by the `Transaction`.
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    /// Returns `true` if the `Transaction` does not hold any lock.
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    /// Consumes the `Transaction`, handing over its locks as `LockToken`s, in the order in which
+    /// they were acquired.
+    pub fn commit(mut self) -> Vec<LockToken<I>> {
+        mem::take(&mut self.tokens)
+    }
+
+    /// Consumes the `Transaction`, releasing all of its locks in reverse order. This is the same
+    /// as dropping it.
+    pub fn abort(self) {}
+
+    fn record<T>(&mut self, result: LockResult<GLockGuard<'_, T, I>>) -> LockResult<()> {
+        match result {
+            Ok(lock_guard) => {
+                self.tokens.push(lock_guard.into_token());
+                Ok(())
+            },
+
+            Err(e) => {
+                self.release();
+                Err(e)
+            },
+        }
+    }
+
+    fn release(&mut self) {
+        while let Some(token) = self.tokens.pop() { drop(token); }
+    }
+}
+
+impl<I: LockId> Default for Transaction<I> {
+    fn default() -> Transaction<I> { Transaction::new() }
+}
+
+impl<I: LockId> Drop for Transaction<I> {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+
+/// A read-only view of a `GLock` that projects its protected data, created using
+/// `GLock::project()`.
+pub struct GLockProjection<'lck, T: 'lck, U: ?Sized, F, I: LockId = Id> {
+    lock: &'lck GLock<T, I>,
+    project: F,
+    _marker: PhantomData<fn() -> *const U>,
+}
+
+impl<'lck, T: 'lck, U: ?Sized, F: Fn(&T) -> &U, I: LockId> GLockProjection<'lck, T, U, F, I> {
+
+    /// Acquires a lock of the specified type on the underlying `GLock`. See `GLock::lock()`.
+    #[must_use = "the lock is released as soon as the guard is dropped"]
+    pub fn lock(&self, lock_type: LockType) -> LockResult<GLockProjectionGuard<'lck, T, U, I>> {
+        self.lock.lock(lock_type).map(|lg| self.guard(lg))
+    }
+
+    /// Attempts to acquire a lock of the specified type on the underlying `GLock`. See
+    /// `GLock::try_lock()`.
+    #[must_use = "the lock is released as soon as the guard is dropped"]
+    pub fn try_lock(&self, lock_type: LockType) -> LockResult<GLockProjectionGuard<'lck, T, U, I>> {
+        self.lock.try_lock(lock_type).map(|lg| self.guard(lg))
+    }
+
+    fn guard(&self, lock_guard: GLockGuard<'lck, T, I>) -> GLockProjectionGuard<'lck, T, U, I> {
+        let data = (self.project)(unsafe { &*self.lock.data_ptr() }) as *const U;
+        GLockProjectionGuard { _lock_guard: lock_guard, data: unsafe { &*data } }
+    }
+}
+
+/// A view of a `GLock` that projects its protected data and allows mutating it, created using
+/// `GLock::project_mut()`.
+pub struct GLockProjectionMut<'lck, T: 'lck, U: ?Sized, F, I: LockId = Id> {
+    lock: &'lck GLock<T, I>,
+    project: F,
+    _marker: PhantomData<fn() -> *const U>,
+}
+
+impl<'lck, T: 'lck, U: ?Sized, F: Fn(&mut T) -> &mut U, I: LockId> GLockProjectionMut<'lck, T, U, F, I> {
+
+    /// Acquires an `Exclusive` lock on the underlying `GLock`. See `GLock::lock_exclusive()`.
+    #[must_use = "the lock is released as soon as the guard is dropped"]
+    pub fn lock_exclusive(&self) -> LockResult<GLockProjectionGuardMut<'lck, T, U, I>> {
+        self.lock.lock_exclusive().map(|lg| self.guard(lg))
+    }
+
+    /// Attempts to acquire an `Exclusive` lock on the underlying `GLock`. See
+    /// `GLock::try_lock_exclusive()`.
+    #[must_use = "the lock is released as soon as the guard is dropped"]
+    pub fn try_lock_exclusive(&self) -> LockResult<GLockProjectionGuardMut<'lck, T, U, I>> {
+        self.lock.try_lock_exclusive().map(|lg| self.guard(lg))
+    }
+
+    fn guard(&self, lock_guard: GLockGuardMut<'lck, T, I>) -> GLockProjectionGuardMut<'lck, T, U, I> {
+        let data = (self.project)(unsafe { &mut *self.lock.data_ptr() }) as *mut U;
+        GLockProjectionGuardMut { _lock_guard: lock_guard, data: unsafe { &mut *data } }
+    }
+}
+
+/// A guard returned by a `GLockProjection`, which gives read access to the projected data. The
+/// lock is released by dropping the `GLockProjectionGuard` object.
+#[must_use = "the lock is released as soon as the guard is dropped"]
+pub struct GLockProjectionGuard<'lck, T: 'lck, U: ?Sized + 'lck, I: LockId = Id> {
+    _lock_guard: GLockGuard<'lck, T, I>,
+    data: &'lck U,
+}
+
+impl<'lck, T: 'lck, U: ?Sized + 'lck, I: LockId> Deref for GLockProjectionGuard<'lck, T, U, I> {
+    type Target = U;
+    fn deref(&self) -> &<Self as Deref>::Target { self.data }
+}
+
+/// A guard returned by a `GLockProjectionMut`, which gives read and write access to the projected
+/// data. The lock is released by dropping the `GLockProjectionGuardMut` object.
+#[must_use = "the lock is released as soon as the guard is dropped"]
+pub struct GLockProjectionGuardMut<'lck, T: 'lck, U: ?Sized + 'lck, I: LockId = Id> {
+    _lock_guard: GLockGuardMut<'lck, T, I>,
+    data: &'lck mut U,
+}
+
+impl<'lck, T: 'lck, U: ?Sized + 'lck, I: LockId> Deref for GLockProjectionGuardMut<'lck, T, U, I> {
+    type Target = U;
+    fn deref(&self) -> &<Self as Deref>::Target { self.data }
+}
+
+impl<'lck, T: 'lck, U: ?Sized + 'lck, I: LockId> DerefMut for GLockProjectionGuardMut<'lck, T, U, I> {
+    fn deref_mut(&mut self) -> &mut <Self as Deref>::Target { self.data }
+}
+
+
+// These tests use `std` threads, so they cannot run under `loom`.
+#[cfg(all(test, not(loom)))]
 mod test {
     use super::*;
 
     #[test]
-    fn non_nested_locks() {
+    fn non_nested_locks() {
+        let p = GLock::new_root(0u32).unwrap();
+
+        let c1 = p.new_child(0u32).unwrap();
+        let c2 = p.new_child(0u32).unwrap();
+
+        for p_type1 in LockType::lock_types() {
+            let p_lg1 = p.try_lock(*p_type1).unwrap();
+
+            for p_type2 in LockType::lock_types() {
+                assert_eq!(p.try_lock(*p_type2).is_ok(), p_type1.compatible_with(*p_type2));
+            }
+
+            for c1_type1 in LockType::lock_types() {
+                let _c1_lg1 = c1.try_lock_using_parent(*c1_type1, &p_lg1).unwrap();
+
+                for c1_type2 in LockType::lock_types() {
+                    assert_eq!(c1.try_lock_using_parent(*c1_type2, &p_lg1).is_ok(), c1_type1.compatible_with(*c1_type2));
+                }
+
+                for c2_type in LockType::lock_types() {
+                    drop(c2.try_lock_using_parent(*c2_type, &p_lg1).unwrap());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn nested_locks() {
+
+        struct Parent {
+            child1: GLock<u32>,
+            child2: GLock<u32>,
+        }
+
+        let parent_lock = {
+            let parent_lb = GLock::<Parent>::new_root_builder();
+
+            let parent = Parent {
+                child1: parent_lb.new_child(0u32).unwrap(),
+                child2: parent_lb.new_child(0u32).unwrap(),
+            };
+
+            parent_lb.build(parent).unwrap()
+        };
+
+        for p_type1 in LockType::lock_types() {
+            let p_lg1 = parent_lock.try_lock(*p_type1).unwrap();
+
+            for p_type2 in LockType::lock_types() {
+                assert_eq!(parent_lock.try_lock(*p_type2).is_ok(), p_type1.compatible_with(*p_type2));
+            }
+
+            for c1_type1 in LockType::lock_types() {
+                let _c1_lg1 = p_lg1.child1.try_lock_using_parent(*c1_type1, &p_lg1).unwrap();
+
+                for c1_type2 in LockType::lock_types() {
+                    assert_eq!(p_lg1.child1.try_lock_using_parent(*c1_type2, &p_lg1).is_ok(), c1_type1.compatible_with(*c1_type2));
+                }
+
+                for c2_type in LockType::lock_types() {
+                    drop(p_lg1.child2.try_lock_using_parent(*c2_type, &p_lg1).unwrap());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn upgrade_to_exclusive() {
+
+        let p = GLock::new_root(0u32).unwrap();
+
+        let c = p.new_child(0u32).unwrap();
+
+        let c_g = c.lock(LockType::Shared).unwrap();
+
+        assert_eq!(p.try_lock(LockType::Shared).is_ok(), true);
+
+        let mut c_g_mut = c_g.upgrade_to_exclusive().unwrap();
+        *c_g_mut = 10;
+
+        assert_eq!(p.try_lock(LockType::Shared).is_ok(), false);
+    }
+
+    #[test]
+    fn reborrow_shared() {
+
+        fn read_helper(data: &u32) -> u32 { *data }
+
+        let p = GLock::new_root(0u32).unwrap();
+
+        let mut p_g_mut = p.lock_exclusive().unwrap();
+        *p_g_mut = 10;
+
+        assert_eq!(read_helper(p_g_mut.as_read()), 10);
+
+        {
+            let p_g_ref = p_g_mut.reborrow_shared();
+            assert_eq!(*p_g_ref, 10);
+            assert_eq!(p_g_ref.lock_type().unwrap(), LockType::Exclusive);
+        }
+
+        *p_g_mut = 20;
+        assert_eq!(p.try_lock(LockType::Shared).is_ok(), false);
+
+        drop(p_g_mut);
+        assert_eq!(p.try_lock(LockType::Shared).is_ok(), true);
+    }
+
+    #[test]
+    fn poison_policy() {
+        use std::panic::{ catch_unwind, AssertUnwindSafe };
+
+        let best_effort = GLock::new_root(0u32).unwrap();
+
+        let fail_stop = GLock::<u32>::new_root_builder()
+            .poison_policy(PoisonPolicy::FailStop)
+            .build(0u32)
+            .unwrap();
+
+        for lock in [&best_effort, &fail_stop].iter() {
+            let result = catch_unwind(AssertUnwindSafe(|| {
+                let _g = lock.lock_exclusive().unwrap();
+                panic!("panicking while holding a lock");
+            }));
+
+            assert_eq!(result.is_err(), true);
+        }
+
+        assert_eq!(best_effort.try_lock(LockType::Shared).is_ok(), true);
+        assert_eq!(fail_stop.try_lock(LockType::Shared).err(), Some(LockError::Poisoned));
+    }
+
+    #[test]
+    fn lock_all_exclusive_roots() {
+        use std::thread;
+
+        let a = Arc::new(GLock::new_root(0u32).unwrap());
+        let b = Arc::new(GLock::new_root(0u32).unwrap());
+
+        // Miri is orders of magnitude slower, and a few rounds are enough to check the accesses.
+        let rounds = if cfg!(miri) { 10 } else { 1000 };
+
+        let threads: Vec<_> = (0..2).map(|t| {
+            let a = a.clone();
+            let b = b.clone();
+
+            thread::spawn(move || {
+                for _ in 0..rounds {
+                    let mut guards = if t == 0 {
+                        lock_all_exclusive(&[&a, &b]).unwrap()
+                    } else {
+                        lock_all_exclusive(&[&b, &a]).unwrap()
+                    };
+
+                    for g in guards.iter_mut() { **g += 1; }
+                }
+            })
+        }).collect();
+
+        for t in threads { t.join().unwrap(); }
+
+        assert_eq!(*a.lock(LockType::Shared).unwrap(), 2 * rounds);
+        assert_eq!(*b.lock(LockType::Shared).unwrap(), 2 * rounds);
+
+        assert_eq!(lock_all_exclusive(&[&a, &b, &a]).err(), Some(LockError::DuplicateLock));
+    }
+
+    #[test]
+    fn depth() {
+        let p = GLock::new_root(0u32).unwrap();
+        let c = p.new_child(0u32).unwrap();
+        let gc = c.new_child(0u32).unwrap();
+
+        assert_eq!(p.depth(), 0);
+        assert_eq!(c.depth(), 1);
+        assert_eq!(gc.depth(), 2);
+    }
+
+    #[test]
+    fn failed_try_upgrade_keeps_lock_type() {
+        let p = GLock::new_root(0u32).unwrap();
+        let c = p.new_child(0u32).unwrap();
+
+        let c_g1 = c.lock(LockType::Shared).unwrap();
+        let _c_g2 = c.lock(LockType::Shared).unwrap();
+
+        assert_eq!(c_g1.try_upgrade(LockType::Exclusive), Err(LockError::LockBusy));
+        assert_eq!(c_g1.lock_type().unwrap(), LockType::Shared);
+
+        // The implicit parent lock upgraded for `Exclusive` is relaxed back to `IntentionShared`.
+        let mut p_counts = LOCK_EMPTY_COUNTS;
+        p_counts[LockType::IntentionShared.index()] = 2;
+        assert_eq!(p.lock_counts(), Ok(p_counts));
+
+        assert_eq!(c_g1.try_upgrade(LockType::IntentionShared), Err(LockError::UpgradeToLowerType { original: LockType::Shared, requested: LockType::IntentionShared }));
+        assert_eq!(c_g1.lock_type().unwrap(), LockType::Shared);
+
+        let _p_g = p.lock(LockType::IntentionShared).unwrap();
+        assert_eq!(c.try_lock(LockType::Shared).is_ok(), true);
+    }
+
+    #[test]
+    fn waiters() {
+        use std::thread;
+        use std::time::Duration;
+
+        let p = Arc::new(GLock::new_root(0u32).unwrap());
+
+        assert_eq!(p.waiters().unwrap(), 0);
+
+        let p_g = p.lock_exclusive().unwrap();
+
+        let waiter = {
+            let p = p.clone();
+            thread::spawn(move || { p.lock(LockType::Shared).map(|_| ()) })
+        };
+
+        while p.waiters().unwrap() == 0 { thread::sleep(Duration::from_millis(1)); }
+        assert_eq!(p.waiters().unwrap(), 1);
+
+        drop(p_g);
+        waiter.join().unwrap().unwrap();
+
+        assert_eq!(p.waiters().unwrap(), 0);
+    }
+
+    #[test]
+    fn default() {
+
+        #[derive(Default)]
+        struct Counters {
+            a: GLock<u32>,
+            b: GLock<u32>,
+        }
+
+        let counters = Counters::default();
+
+        *counters.a.lock_exclusive().unwrap() += 1;
+
+        assert_eq!(*counters.a.lock(LockType::Shared).unwrap(), 1);
+        assert_eq!(*counters.b.lock(LockType::Shared).unwrap(), 0);
+        assert_eq!(counters.b.depth(), 0);
+    }
+
+    #[test]
+    fn lock_id() {
+        let p = GLock::new_root(0u32).unwrap();
+        let c1 = p.new_child(0u32).unwrap();
+        let c2 = p.new_child(0u32).unwrap();
+
+        let p_g = p.lock(LockType::IntentionShared).unwrap();
+
+        assert_eq!(p_g.lock_id(), None);
+        assert_eq!(c1.lock_using_parent(LockType::Shared, &p_g).unwrap().lock_id(), Some(0));
+        assert_eq!(c2.lock_using_parent(LockType::Shared, &p_g).unwrap().lock_id(), Some(1));
+    }
+
+    #[test]
+    fn lock_token() {
+        use std::thread;
+        use std::sync::mpsc::channel;
+
+        let p = Arc::new(GLock::new_root(0u32).unwrap());
+        let other = GLock::new_root(0u32).unwrap();
+
+        let (sender, receiver) = channel();
+
+        let token = p.lock_exclusive().unwrap().into_token();
+        assert_eq!(token.lock_type().unwrap(), LockType::Exclusive);
+
+        let (_, token) = other.from_token(token).err().unwrap();
+        assert_eq!(p.try_lock(LockType::Shared).is_ok(), false);
+
+        let receiver_thread = {
+            let p = p.clone();
+
+            thread::spawn(move || {
+                let token = receiver.recv().unwrap();
+                let mut p_g_mut = p.from_token(token).unwrap().upgrade_to_exclusive().unwrap();
+                *p_g_mut = 10;
+            })
+        };
+
+        sender.send(token).unwrap();
+        receiver_thread.join().unwrap();
+
+        assert_eq!(*p.try_lock(LockType::Shared).unwrap(), 10);
+    }
+
+    #[test]
+    fn intended_lock_type() {
+        let p = GLock::new_root(0u32).unwrap();
+
+        let c = p.new_child_builder().unwrap()
+            .intended_lock_type(LockType::Exclusive)
+            .build(0u32)
+            .unwrap();
+
+        {
+            let p_g = p.lock(LockType::IntentionShared).unwrap();
+            assert_eq!(c.try_lock_using_parent(LockType::Shared, &p_g).err(), Some(LockError::UnsupportedChildLockType { parent: LockType::IntentionShared, child: LockType::Exclusive }));
+        }
+
+        let p_g = p.lock(LockType::IntentionExclusive).unwrap();
+        assert_eq!(c.try_lock_exclusive_using_parent(&p_g).is_ok(), true);
+    }
+
+    #[test]
+    fn project() {
+
+        struct Point {
+            x: u32,
+            y: u32,
+        }
+
+        let p = GLock::new_root(Point { x: 1, y: 2 }).unwrap();
+
+        let x = p.project(|point| &point.x);
+        let y_mut = p.project_mut(|point| &mut point.y);
+
+        {
+            let x_g = x.lock(LockType::Shared).unwrap();
+            assert_eq!(*x_g, 1);
+            assert_eq!(y_mut.try_lock_exclusive().is_ok(), false);
+        }
+
+        {
+            let mut y_g = y_mut.lock_exclusive().unwrap();
+            *y_g = 20;
+            assert_eq!(x.try_lock(LockType::Shared).is_ok(), false);
+        }
+
+        let p_g = p.lock(LockType::Shared).unwrap();
+        assert_eq!(p_g.x, 1);
+        assert_eq!(p_g.y, 20);
+    }
+
+    #[test]
+    fn try_lock_never_blocks_on_ancestors() {
+        let root = GLock::new_root(0u32).unwrap();
+        let c = root.new_child(0u32).unwrap();
+        let gc = c.new_child(0u32).unwrap();
+        let ggc = gc.new_child(0u32).unwrap();
+
+        for held_type in LockType::lock_types() {
+            let _root_g = root.lock(*held_type).unwrap();
+
+            for lock_type in LockType::lock_types() {
+                let should_succeed = held_type.compatible_with(lock_type.implicit_parent_type());
+                let result = ggc.try_lock(*lock_type);
+
+                assert_eq!(result.is_ok(), should_succeed);
+                if !should_succeed { assert_eq!(result.err(), Some(LockError::LockBusy)); }
+            }
+        }
+    }
+
+    #[test]
+    fn split() {
+        use std::ptr;
+
+        struct Pair {
+            a: String,
+            b: Vec<u32>,
+        }
+
+        fn append(s: &mut String) { s.push('!'); }
+        fn push(v: &mut Vec<u32>) { v.push(1); }
+
+        let p = GLock::new_root(Pair { a: String::new(), b: Vec::new() }).unwrap();
+        let mut p_g = p.lock_exclusive().unwrap();
+
+        {
+            let (a, b) = unsafe { p_g.split2(|p| ptr::addr_of_mut!((*p).a), |p| ptr::addr_of_mut!((*p).b)) };
+            append(a);
+            push(b);
+        }
+
+        {
+            let (a, b) = split_fields_mut!(p_g, a, b);
+            append(a);
+            push(b);
+        }
+
+        assert_eq!(p_g.a, "!!");
+        assert_eq!(p_g.b, vec![1, 1]);
+    }
+
+    #[test]
+    fn timeout() {
+        use std::time::Instant;
+
+        let p = GLock::new_root(0u32).unwrap();
+        let c = p.new_child(0u32).unwrap();
+
+        let c_default = p.new_child_builder().unwrap()
+            .default_timeout(Duration::from_millis(10))
+            .build(0u32)
+            .unwrap();
+
+        let p_g = p.lock(LockType::Shared).unwrap();
+
+        let start = Instant::now();
+        assert_eq!(c.lock_timeout(LockType::Exclusive, Duration::from_millis(10)).err(), Some(LockError::Timeout));
+        assert_eq!(c_default.lock_exclusive().err(), Some(LockError::Timeout));
+        assert_eq!(start.elapsed() >= Duration::from_millis(20), true);
+
+        assert_eq!(c.lock_timeout(LockType::Shared, Duration::from_millis(10)).is_ok(), true);
+        assert_eq!(c_default.lock(LockType::Shared).is_ok(), true);
+
+        // The parent lock preventing the upgrade is held by the current thread.
+        let c_g = c.lock(LockType::Shared).unwrap();
+        assert_eq!(c_g.upgrade_timeout(LockType::Exclusive, Duration::from_millis(10)), Err(LockError::WouldSelfDeadlock));
+        assert_eq!(c_g.lock_type().unwrap(), LockType::Shared);
+
+        drop(p_g);
+        assert_eq!(c_g.upgrade_timeout(LockType::Exclusive, Duration::from_millis(10)), Ok(()));
+    }
+
+    #[test]
+    fn lock_shared() {
+        let p = GLock::new_root(0u32).unwrap();
+        let c = p.new_child(0u32).unwrap();
+
+        let p_g = p.lock_shared().unwrap();
+        assert_eq!(p_g.lock_type().unwrap(), LockType::Shared);
+        assert_eq!(p.try_lock_shared().is_ok(), true);
+        assert_eq!(p.try_lock_exclusive().is_ok(), false);
+
+        let c_g = c.lock_shared_using_parent(&p_g).unwrap();
+        assert_eq!(c_g.lock_type().unwrap(), LockType::Shared);
+        assert_eq!(c.try_lock_shared_using_parent(&p_g).is_ok(), true);
+        assert_eq!(c.try_lock_shared().is_ok(), true);
+    }
+
+    #[test]
+    fn upgrade_would_self_deadlock() {
+        use std::thread;
+
+        let p = Arc::new(GLock::new_root(0u32).unwrap());
+
+        let p_g1 = p.lock_shared().unwrap();
+        let p_g2 = p.lock_shared().unwrap();
+
+        let (e, p_g1) = p_g1.upgrade_to_exclusive().err().unwrap();
+        assert_eq!(e, LockError::WouldSelfDeadlock);
+        assert_eq!(p_g1.lock_type().unwrap(), LockType::Shared);
+
+        drop(p_g2);
+
+        let other = {
+            let p = p.clone();
+            thread::spawn(move || { p.lock_shared().unwrap().into_token() })
+        };
+
+        let token = other.join().unwrap();
+        assert_eq!(p_g1.upgrade_timeout(LockType::Exclusive, Duration::from_millis(10)), Err(LockError::Timeout));
+
+        let _p_g3 = p.from_token(token).unwrap();
+        assert_eq!(p_g1.upgrade(LockType::Exclusive), Err(LockError::WouldSelfDeadlock));
+    }
+
+    #[test]
+    fn require_explicit_parent() {
+        let p = GLock::new_root(0u32).unwrap();
+
+        let c = p.new_child_builder().unwrap()
+            .require_explicit_parent()
+            .build(0u32)
+            .unwrap();
+
+        assert_eq!(c.lock(LockType::Shared).err(), Some(LockError::ParentNotHeld));
+        assert_eq!(c.try_lock_exclusive().err(), Some(LockError::ParentNotHeld));
+        assert_eq!(p.try_lock_exclusive().is_ok(), true);
+
+        let p_g = p.lock(LockType::IntentionExclusive).unwrap();
+        assert_eq!(c.lock_exclusive_using_parent(&p_g).is_ok(), true);
+    }
+
+    #[test]
+    fn same_lock() {
+        let p = GLock::new_root(0u32).unwrap();
+        let c = p.new_child(String::new()).unwrap();
+
+        let p_g1 = p.lock_shared().unwrap();
+        let p_g2 = p.lock_shared().unwrap();
+        let c_g = c.lock_shared_using_parent(&p_g1).unwrap();
+
+        assert_eq!(p_g1.same_lock(&p_g2), true);
+        assert_eq!(p_g1.same_lock(&c_g), false);
+        assert_eq!(c_g.same_lock(&c_g), true);
+    }
+
+    #[test]
+    fn try_into_inner() {
+        let p = GLock::new_root(String::from("parent")).unwrap();
+        let c = p.new_child(1u32).unwrap();
+
+        let p = GLock::try_into_inner(p).err().unwrap();
+        drop(c);
+
+        let token = p.lock_exclusive().unwrap().into_token();
+        let p = GLock::try_into_inner(p).err().unwrap();
+        drop(token);
+
+        assert_eq!(GLock::try_into_inner(p).ok(), Some(String::from("parent")));
+    }
+
+    #[test]
+    fn custom_ids() {
+        #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+        struct NodeId(u32);
+
+        let p = GLock::<u32, NodeId>::new_root_with_id_type(0).unwrap();
+        let c1 = p.new_child_with_id(NodeId(7), 0u32).unwrap();
+
+        assert_eq!(p.new_child_with_id(NodeId(7), 0u32).err(), Some(LockError::DuplicateId));
+
+        let p_g = p.lock_shared().unwrap();
+        assert_eq!(p_g.lock_id(), None);
+        assert_eq!(c1.lock_shared_using_parent(&p_g).unwrap().lock_id(), Some(NodeId(7)));
+        drop(p_g);
+
+        drop(c1);
+        let c2 = p.new_child_with_id(NodeId(7), 0u32).unwrap();
+        assert_eq!(c2.lock_shared().unwrap().lock_id(), Some(NodeId(7)));
+    }
+
+    #[test]
+    fn auto_ids_skip_used_ids() {
+        let p = GLock::new_root(0u32).unwrap();
+
+        let c1 = p.new_child_with_id(0, 0u32).unwrap();
+        let c2 = p.new_child(0u32).unwrap();
+
+        assert_eq!(c1.lock_shared().unwrap().lock_id(), Some(0));
+        assert_eq!(c2.lock_shared().unwrap().lock_id(), Some(1));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "would deadlock on locks held by the current thread")]
+    fn self_deadlock_panics_in_debug_builds() {
+        let p = GLock::new_root(0u32).unwrap();
+        let c = p.new_child(0u32).unwrap();
+
+        let _p_g = p.lock_shared().unwrap();
+        let _c_g = c.lock_exclusive();
+    }
+
+    #[test]
+    fn blocking_on_lock_moved_to_another_thread() {
+        let l = Arc::new(GLock::new_root(0u32).unwrap());
+        let token = l.lock_shared().unwrap().into_token();
+
+        let reader = {
+            let l = l.clone();
+
+            std::thread::spawn(move || {
+                let lg = l.from_token(token).ok().unwrap();
+                std::thread::sleep(Duration::from_millis(20));
+                drop(lg);
+            })
+        };
+
+        // The wait ends once the other thread releases the lock, so it must not be reported as a
+        // self-deadlock, even in debug builds.
+        assert_eq!(l.lock_exclusive().map(|lg| *lg), Ok(0));
+        reader.join().unwrap();
+    }
+
+    #[test]
+    fn get_set() {
+        let counter = GLock::new_root(0i64).unwrap();
+
+        assert_eq!(counter.get(), Ok(0));
+
+        counter.set(-5).unwrap();
+        assert_eq!(counter.get(), Ok(-5));
+
+        assert_eq!(counter.with_exclusive(|v| { *v += 10; *v }), Ok(5));
+        assert_eq!(counter.with_shared(|v| *v * 2), Ok(10));
+
+        let _lg = counter.lock_shared().unwrap();
+        assert_eq!(counter.get(), Ok(5));
+    }
+
+    #[test]
+    fn policy_inheritance() {
+        let timeout = Duration::from_millis(10);
+
+        let root_builder = GLockBuilder::new_root_builder()
+            .poison_policy(PoisonPolicy::FailStop)
+            .default_timeout(timeout);
+
+        let c = root_builder.new_child(0u32).unwrap();
+        let r = root_builder.build(0u32).unwrap();
+
+        let gc1 = c.new_child(0u32).unwrap();
+        let gc2 = c.new_child_builder().unwrap()
+            .no_default_timeout()
+            .require_explicit_parent()
+            .build(0u32)
+            .unwrap();
+        let ggc = gc2.new_child(0u32).unwrap();
+
+        for kernel in [&r.kernel, &c.kernel, &gc1.kernel] {
+            let options = kernel.options();
+            assert_eq!(options.poison_policy, PoisonPolicy::FailStop);
+            assert_eq!(options.default_timeout, Some(timeout));
+        }
+
+        for kernel in [&gc2.kernel, &ggc.kernel] {
+            let options = kernel.options();
+            assert_eq!(options.poison_policy, PoisonPolicy::FailStop);
+            assert_eq!(options.default_timeout, None);
+        }
+
+        assert_eq!(ggc.kernel.options().require_explicit_parent, false);
+
+        let _gc1_lg = gc1.lock_exclusive().unwrap();
+        assert_eq!(gc1.lock_exclusive().err(), Some(LockError::Timeout));
+    }
+
+    #[test]
+    fn upgrade_to_exclusive_timeout() {
+        use std::thread;
+
+        let p = Arc::new(GLock::new_root(5u32).unwrap());
+
+        let token = {
+            let p = p.clone();
+            thread::spawn(move || { p.lock_shared().unwrap().into_token() }).join().unwrap()
+        };
+
+        let p_g = p.lock_shared().unwrap();
+
+        let (e, p_g) = p_g.upgrade_to_exclusive_timeout(Duration::from_millis(10)).err().unwrap();
+        assert_eq!(e, LockError::Timeout);
+        assert_eq!(p_g.lock_type().unwrap(), LockType::Shared);
+        assert_eq!(*p_g, 5);
+
+        drop(token);
+
+        let mut p_g_mut = p_g.upgrade_to_exclusive_timeout(Duration::from_millis(10)).ok().unwrap();
+        *p_g_mut = 6;
+        drop(p_g_mut);
+
+        assert_eq!(p.get(), Ok(6));
+    }
+
+    #[test]
+    fn lock_exclusive_timeout() {
+        use std::thread;
+
+        let p = Arc::new(GLock::new_root(5u32).unwrap());
+        let c = p.new_child(1u32).unwrap();
+
+        let token = {
+            let p = p.clone();
+            thread::spawn(move || { p.lock(LockType::IntentionShared).unwrap().into_token() }).join().unwrap()
+        };
+
+        let p_g = p.lock_shared().unwrap();
+        assert_eq!(p.lock_exclusive_timeout(Duration::from_millis(10)).err(), Some(LockError::Timeout));
+        drop(p_g);
+
+        let p_g = p.lock(LockType::IntentionExclusive).unwrap();
+        let c_g = c.lock_shared_using_parent(&p_g).unwrap();
+        assert_eq!(c.lock_exclusive_using_parent_timeout(&p_g, Duration::from_millis(10)).err(), Some(LockError::Timeout));
+        drop(c_g);
+
+        let mut c_g_mut = c.lock_exclusive_using_parent_timeout(&p_g, Duration::from_millis(10)).unwrap();
+        *c_g_mut = 2;
+        drop(c_g_mut);
+        drop(p_g);
+
+        assert_eq!(p.lock_exclusive_timeout(Duration::from_millis(10)).err(), Some(LockError::Timeout));
+        drop(token);
+
+        let mut p_g_mut = p.lock_exclusive_timeout(Duration::from_millis(10)).unwrap();
+        *p_g_mut = 6;
+        drop(p_g_mut);
+
+        assert_eq!(p.get(), Ok(6));
+        assert_eq!(c.get(), Ok(2));
+    }
+
+    #[test]
+    fn debug_while_locked() {
+        let p = GLock::new_root(0u32).unwrap();
+        let c = p.new_child(7u32).unwrap();
+
+        let c_lg = c.lock_exclusive().unwrap();
+
+        assert_eq!(format!("{:?}", c).contains("data: <locked>"), true);
+        assert_eq!(format!("{:?}", c_lg).is_empty(), false);
+
+        drop(c_lg);
+        assert_eq!(format!("{:?}", c).contains("data: 7"), true);
+    }
+
+    #[test]
+    fn debug_has_no_side_effects() {
+        use std::sync::atomic::{ AtomicUsize, Ordering };
+
+        let admitted = Arc::new(AtomicUsize::new(0));
+
+        let p = GLock::new_root(0u32).unwrap();
+
+        let c = {
+            let admitted = admitted.clone();
+
+            p.new_child_builder().unwrap()
+                .require_explicit_parent()
+                .admit(move |_, _| { admitted.fetch_add(1, Ordering::SeqCst); true })
+                .build(7u32)
+                .unwrap()
+        };
+
+        // Neither the admission hook nor the missing parent lock are involved.
+        assert_eq!(format!("{:?}", c).contains("data: 7"), true);
+        assert_eq!(admitted.load(Ordering::SeqCst), 0);
+
+        // Locks of other types do not prevent reading the data.
+        let p_lg = p.lock_shared().unwrap();
+        let c_lg = c.lock_shared_using_parent(&p_lg).unwrap();
+        assert_eq!(format!("{:?}", c).contains("data: 7"), true);
+        assert_eq!(c.lock_counts().unwrap()[LockType::Shared.index()], 1);
+        drop(c_lg);
+        drop(p_lg);
+
+        assert_eq!(p.lock_counts(), Ok(LOCK_EMPTY_COUNTS));
+    }
+
+    #[test]
+    fn upgrade_if() {
+        use std::thread;
+        use std::sync::mpsc::channel;
+
+        let p = Arc::new(GLock::new_root(0u32).unwrap());
+
+        let (e, p_g) = p.lock_shared().unwrap().upgrade_if(|v| *v > 0).err().unwrap();
+        assert_eq!(e, None);
+        assert_eq!(p_g.lock_type().unwrap(), LockType::Shared);
+
+        let mut p_g = p_g.upgrade_if(|v| *v == 0).ok().unwrap();
+        *p_g = 1;
+        drop(p_g);
+
+        let (sender, receiver) = channel();
+
+        let reader = {
+            let p = p.clone();
+
+            thread::spawn(move || {
+                let p_g = p.lock_shared().unwrap();
+                sender.send(()).unwrap();
+                thread::sleep(Duration::from_millis(10));
+                *p_g
+            })
+        };
+
+        receiver.recv().unwrap();
+
+        let mut p_g = p.lock_shared().unwrap().upgrade_if(|v| *v == 1).ok().unwrap();
+        *p_g += 1;
+        drop(p_g);
+
+        assert_eq!(reader.join().unwrap(), 1);
+        assert_eq!(p.get(), Ok(2));
+    }
+
+    #[test]
+    fn lock_with_priority() {
+        use std::thread;
+        use std::sync::Mutex;
+
+        let p = Arc::new(GLock::new_root(0u32).unwrap());
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        let p_g = p.lock_exclusive().unwrap();
+
+        let spawn_waiter = |priority: u8| {
+            let waiters = p.waiters().unwrap();
+
+            let handle = {
+                let p = p.clone();
+                let order = order.clone();
+
+                thread::spawn(move || {
+                    let _p_g = p.lock_exclusive_with_priority(priority).unwrap();
+                    order.lock().unwrap().push(priority);
+                })
+            };
+
+            while p.waiters().unwrap() == waiters { thread::sleep(Duration::from_millis(1)); }
+            handle
+        };
+
+        let low = spawn_waiter(1);
+        let high = spawn_waiter(5);
+
+        assert_eq!(p.try_lock(LockType::IntentionShared).is_ok(), false);
+
+        drop(p_g);
+        low.join().unwrap();
+        high.join().unwrap();
+
+        assert_eq!(*order.lock().unwrap(), vec![5, 1]);
+        assert_eq!(p.try_lock(LockType::IntentionShared).is_ok(), true);
+    }
+
+    #[test]
+    fn priority_waiter_lets_holders_lock_children() {
+        use std::thread;
+
+        let p = Arc::new(GLock::new_root(0u32).unwrap());
+        let c = p.new_child(0u32).unwrap();
+        let p_g = p.lock_shared().unwrap();
+
+        let writer = {
+            let p = p.clone();
+
+            thread::spawn(move || {
+                *p.lock_exclusive_with_priority(5).unwrap() = 1;
+            })
+        };
+
+        while p.waiters().unwrap() == 0 { thread::sleep(Duration::from_millis(1)); }
+
+        // The implicit `IntentionShared` lock on the parent, and another `Shared` lock on it, do
+        // not wait behind the writer, which waits for the shared lock held by this thread.
+        assert_eq!(*c.lock_shared().unwrap(), 0);
+        assert_eq!(*p.lock_shared().unwrap(), 0);
+
+        // Threads that do not hold the parent still wait behind it.
+        let other = {
+            let p = p.clone();
+            thread::spawn(move || p.try_lock_shared().err()).join().unwrap()
+        };
+
+        assert_eq!(other, Some(LockError::LockBusy));
+
+        drop(p_g);
+        writer.join().unwrap();
+        assert_eq!(p.get().unwrap(), 1);
+    }
+
+    #[test]
+    fn pending_upgrade_blocks_new_readers() {
+        use std::thread;
+
+        let p = Arc::new(GLock::new_root(0u32).unwrap());
+
+        let token = {
+            let p = p.clone();
+            thread::spawn(move || { p.lock_shared().unwrap().into_token() }).join().unwrap()
+        };
+
+        let upgrader = {
+            let p = p.clone();
+
+            thread::spawn(move || {
+                let mut p_g = p.lock_shared().unwrap().upgrade_to_exclusive().ok().unwrap();
+                *p_g = 1;
+            })
+        };
+
+        while p.waiters().unwrap() == 0 { thread::sleep(Duration::from_millis(1)); }
+
+        assert_eq!(p.try_lock_shared().err(), Some(LockError::LockBusy));
+
+        let readers: Vec<_> = (0..4).map(|_| {
+            let p = p.clone();
+            thread::spawn(move || p.get().unwrap())
+        }).collect();
+
+        drop(token);
+        upgrader.join().unwrap();
+
+        for reader in readers {
+            assert_eq!(reader.join().unwrap(), 1);
+        }
+    }
+
+    #[test]
+    fn pending_upgrade_lets_co_readers_lock_children() {
+        use std::thread;
+
+        let p = Arc::new(GLock::new_root(0u32).unwrap());
+        let c = p.new_child(0u32).unwrap();
+        let p_g = p.lock_shared().unwrap();
+
+        let upgrader = {
+            let p = p.clone();
+
+            thread::spawn(move || {
+                let mut p_g = p.lock_shared().unwrap().upgrade_to_exclusive().ok().unwrap();
+                *p_g = 1;
+            })
+        };
+
+        while p.waiters().unwrap() == 0 { thread::sleep(Duration::from_millis(1)); }
+
+        // The implicit `IntentionShared` lock on the parent does not wait behind the upgrade,
+        // which waits for the shared lock held by this thread.
+        assert_eq!(*c.lock_shared().unwrap(), 0);
+
+        // Threads that do not hold the parent still wait behind it.
+        let other = {
+            let p = p.clone();
+            thread::spawn(move || p.try_lock_shared().err()).join().unwrap()
+        };
+
+        assert_eq!(other, Some(LockError::LockBusy));
+
+        drop(p_g);
+        upgrader.join().unwrap();
+        assert_eq!(p.get().unwrap(), 1);
+    }
+
+    #[test]
+    fn count_contribution() {
+        let p = GLock::new_root(0u32).unwrap();
+        let c1 = p.new_child(0u32).unwrap();
+        let c2 = p.new_child(0u32).unwrap();
+
+        let is = LockType::IntentionShared.index();
+        let ix = LockType::IntentionExclusive.index();
+        let s = LockType::Shared.index();
+        let x = LockType::Exclusive.index();
+
+        let p_g = p.lock(LockType::IntentionShared).unwrap();
+        let c1_g = c1.lock_shared_using_parent(&p_g).unwrap();
+        let c2_g = c2.lock_shared().unwrap();
+
+        assert_eq!(p.lock_counts().unwrap()[is], 2);
+        assert_eq!(p_g.count_contribution().unwrap()[is], 1);
+        assert_eq!(c1_g.count_contribution().unwrap()[s], 1);
+
+        c1_g.upgrade(LockType::Exclusive).unwrap();
+
+        assert_eq!(p.lock_counts().unwrap()[is], 1);
+        assert_eq!(p.lock_counts().unwrap()[ix], 1);
+        assert_eq!(p_g.count_contribution().unwrap()[ix], 1);
+        assert_eq!(c1.lock_counts().unwrap()[x], 1);
+        assert_eq!(c1_g.count_contribution().unwrap(), c1.lock_counts().unwrap());
+
+        drop(c2_g);
+        drop(c1_g);
+
+        assert_eq!(p.lock_counts().unwrap(), p_g.count_contribution().unwrap());
+        assert_eq!(c1.lock_counts().unwrap(), LOCK_EMPTY_COUNTS);
+    }
+
+    #[test]
+    fn take_parent_guard() {
+        let p = GLock::new_root(0u32).unwrap();
+        let c1 = p.new_child(0u32).unwrap();
+        let c2 = p.new_child(0u32).unwrap();
+
+        assert_eq!(p.lock_shared().unwrap().take_parent_guard().is_none(), true);
+
+        let c1_g = c1.lock_exclusive().unwrap();
+        let p_g = c1_g.take_parent_guard().unwrap();
+
+        assert_eq!(p_g.lock_type(), Ok(LockType::IntentionExclusive));
+        assert_eq!(p.lock_counts().unwrap()[LockType::IntentionExclusive.index()], 1);
+
+        let c2_g = c2.lock_exclusive_using_parent(&p_g).unwrap();
+        assert_eq!(p.lock_counts().unwrap()[LockType::IntentionExclusive.index()], 1);
+
+        drop(c1_g);
+        drop(c2_g);
+        assert_eq!(p.try_lock_exclusive().is_ok(), false);
+
+        let _c1_g = c1.lock_shared_using_parent(&p_g).unwrap();
+        drop(p_g);
+        assert_eq!(p.try_lock_exclusive().is_ok(), false);
+    }
+
+    #[test]
+    fn transaction() {
+        struct Parent {
+            child1: GLock<u32>,
+            child2: GLock<u32>,
+        }
+
+        let p = {
+            let p_lb = GLock::<Parent>::new_root_builder();
+
+            let parent = Parent {
+                child1: p_lb.new_child(1u32).unwrap(),
+                child2: p_lb.new_child(2u32).unwrap(),
+            };
+
+            p_lb.build(parent).unwrap()
+        };
+
+        let sum = p.transaction(|p_g| {
+            let mut c1_g = p_g.child1.lock_exclusive_using_parent(p_g)?;
+            let c2_g = p_g.child2.lock_shared_using_parent(p_g)?;
+
+            assert_eq!(p.try_lock_exclusive().is_ok(), false);
+            assert_eq!(p.try_lock(LockType::IntentionExclusive).is_ok(), true);
+
+            *c1_g += *c2_g;
+            Ok(*c1_g)
+        });
+
+        assert_eq!(sum, Ok(3));
+        assert_eq!(p.try_lock_exclusive().is_ok(), true);
+
+        let busy: LockResult<()> = p.transaction(|p_g| {
+            let _c1_g = p_g.child1.lock_shared_using_parent(p_g)?;
+            p_g.child1.try_lock_exclusive_using_parent(p_g).map(|_| ())
+        });
+
+        assert_eq!(busy, Err(LockError::LockBusy));
+        assert_eq!(p.try_lock_exclusive().is_ok(), true);
+    }
+
+    #[test]
+    fn reparent() {
+        let p1 = GLock::new_root(0u32).unwrap();
+        let p2 = p1.new_child(0u32).unwrap();
+        let c = p1.new_child_with_id(7, 0u32).unwrap();
+        let gc = c.new_child(0u32).unwrap();
+
+        assert_eq!(gc.depth(), 2);
+        assert_eq!(p1.reparent(&c, &p2), Ok(()));
+        assert_eq!(gc.depth(), 3);
+        assert_eq!(p1.reparent(&c, &p2), Err(LockError::InvalidParentLock));
+
+        let p1_g = p1.lock(LockType::IntentionExclusive).unwrap();
+        assert_eq!(c.lock_exclusive_using_parent(&p1_g).err(), Some(LockError::InvalidParentLock));
+        drop(p1_g);
+
+        let p2_g = p2.lock(LockType::IntentionExclusive).unwrap();
+        let mut c_g = c.lock_exclusive_using_parent(&p2_g).unwrap();
+        *c_g = 1;
+        drop(p2_g);
+
+        assert_eq!(p2.try_lock_exclusive().is_ok(), false);
+        drop(c_g);
+
+        let gc_g = gc.lock_exclusive().unwrap();
+        assert_eq!(p2.try_lock_exclusive().is_ok(), false);
+        assert_eq!(p1.try_lock(LockType::IntentionExclusive).is_ok(), true);
+        drop(gc_g);
+
+        assert_eq!(p2.try_lock_exclusive().is_ok(), true);
+        assert_eq!(p2.reparent(&c, &p1), Ok(()));
+        assert_eq!(gc.depth(), 2);
+    }
+
+    #[test]
+    fn reparent_invalid() {
+        let p = GLock::new_root(0u32).unwrap();
+        let c1 = p.new_child_with_id(1, 0u32).unwrap();
+        let c2 = p.new_child_with_id(2, 0u32).unwrap();
+        let gc = c1.new_child_with_id(2, 0u32).unwrap();
+
+        assert_eq!(p.reparent(&c1, &c1), Err(LockError::InvalidReparent));
+        assert_eq!(p.reparent(&c1, &gc), Err(LockError::InvalidReparent));
+        assert_eq!(p.reparent(&c2, &c1), Err(LockError::DuplicateId));
+        assert_eq!(c1.reparent(&c2, &p), Err(LockError::InvalidParentLock));
+
+        assert_eq!(c2.depth(), 1);
+        assert_eq!(c1.try_lock_exclusive().is_ok(), true);
+        assert_eq!(c2.try_lock_exclusive().is_ok(), true);
+    }
+
+    #[test]
+    fn snapshot() {
+        let l = GLock::new_root(vec![String::from("a"), String::from("b")]).unwrap();
+        let mut snapshot = l.snapshot().unwrap();
+
+        assert_eq!(l.try_lock_exclusive().is_ok(), true);
+
+        l.with_exclusive(|v| v.push(String::from("c"))).unwrap();
+        snapshot.push(String::from("d"));
+
+        assert_eq!(snapshot, vec!["a", "b", "d"]);
+        assert_eq!(l.snapshot().unwrap(), vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn parent_upgrade() {
+        let p = GLock::new_root(0u32).unwrap();
+        let c1 = p.new_child(0u32).unwrap();
+        let c2 = p.new_child(0u32).unwrap();
+
+        let c1_g = c1.lock_exclusive().unwrap();
+        assert_eq!(c1_g.parent_upgrade(), None);
+        drop(c1_g);
+
+        let p_g = p.lock_shared().unwrap();
+        let c1_g = c1.lock_shared_using_parent(&p_g).unwrap();
+        assert_eq!(c1_g.parent_upgrade(), None);
+
+        let c2_g = c2.lock_exclusive_using_parent(&p_g).unwrap();
+        assert_eq!(c2_g.parent_upgrade(), Some(ParentUpgrade { from: LockType::Shared, to: LockType::SharedIntentionExclusive }));
+        assert_eq!(p_g.lock_type(), Ok(LockType::SharedIntentionExclusive));
+
+        drop(c1_g);
+        let c1_g = c1.lock_exclusive_using_parent(&p_g).unwrap();
+        assert_eq!(c1_g.parent_upgrade(), None);
+    }
+
+    #[test]
+    fn downgrade_to() {
+        let p = Arc::new(GLock::new_root(0u32).unwrap());
+        let c1 = Arc::new(p.new_child(1u32).unwrap());
+        let c2 = Arc::new(p.new_child(2u32).unwrap());
+
+        let mut p_g = p.lock_exclusive().unwrap();
+        *p_g = 1;
+
+        let p_g = p_g.downgrade_to(LockType::SharedIntentionExclusive).unwrap();
+        assert_eq!(p_g.lock_type(), Ok(LockType::SharedIntentionExclusive));
+        assert_eq!(*p_g, 1);
+
+        let mut c1_g = c1.lock_exclusive_using_parent(&p_g).unwrap();
+        *c1_g = 10;
+
+        {
+            let (p, c1, c2) = (p.clone(), c1.clone(), c2.clone());
+
+            std::thread::spawn(move || {
+                assert_eq!(p.try_lock(LockType::IntentionShared).is_ok(), true);
+                assert_eq!(p.try_lock(LockType::IntentionExclusive).is_ok(), false);
+                assert_eq!(p.try_lock_shared().is_ok(), false);
+                assert_eq!(c2.try_lock_shared().map(|g| *g), Ok(2));
+                assert_eq!(c1.try_lock_shared().is_ok(), false);
+            }).join().unwrap();
+        }
+
+        drop(c1_g);
+        drop(p_g);
+        assert_eq!(p.lock_counts().unwrap(), LOCK_EMPTY_COUNTS);
+    }
+
+    #[test]
+    fn downgrade_to_invalid() {
+        let p = GLock::new_root(0u32).unwrap();
+        let c = p.new_child(0u32).unwrap();
+
+        let p_g = p.lock_exclusive().unwrap();
+        let c_g = c.lock_exclusive_using_parent(&p_g).unwrap();
+
+        let (err, p_g) = p_g.downgrade_to(LockType::Shared).err().unwrap();
+        assert_eq!(err, LockError::InvalidDowngrade { original: LockType::Exclusive, requested: LockType::Shared });
+        assert_eq!(p.try_lock(LockType::IntentionShared).is_ok(), false);
+
+        drop(c_g);
+
+        let p_g = p_g.downgrade_to(LockType::Shared).unwrap();
+        assert_eq!(p_g.lock_type(), Ok(LockType::Shared));
+        assert_eq!(c.try_lock_shared().is_ok(), true);
+        assert_eq!(c.try_lock_exclusive().is_ok(), false);
+    }
+
+    #[test]
+    fn with_child() {
+        let p = GLock::new_root(0u32).unwrap();
+        let _c = p.new_child(0u32).unwrap();
+
+        let r = p.with_child(String::from("a"), |c| {
+            assert_eq!(p.kernel.children_len(), 2);
+
+            let mut c_g = c.lock_exclusive().unwrap();
+            c_g.push('b');
+
+            assert_eq!(p.try_lock_shared().is_ok(), false);
+            c_g.clone()
+        });
+
+        assert_eq!(r, Ok(String::from("ab")));
+        assert_eq!(p.kernel.children_len(), 1);
+        assert_eq!(p.try_lock_exclusive().is_ok(), true);
+    }
+
+    #[test]
+    fn relax_parent_lock() {
+        let p = Arc::new(GLock::new_root(0u32).unwrap());
+        let c1 = p.new_child(0u32).unwrap();
+        let c2 = p.new_child(0u32).unwrap();
+        let gc = c2.new_child(0u32).unwrap();
+
+        let try_lock_shared = |p: &Arc<GLock<u32>>| {
+            let p = p.clone();
+            std::thread::spawn(move || p.try_lock_shared().is_ok()).join().unwrap()
+        };
+
+        let p_g = p.lock(LockType::IntentionShared).unwrap();
+        let c1_g = c1.lock_shared_using_parent(&p_g).unwrap();
+        let c2_g = c2.lock_exclusive_using_parent(&p_g).unwrap();
+
+        assert_eq!(p_g.lock_type(), Ok(LockType::IntentionExclusive));
+        assert_eq!(try_lock_shared(&p), false);
+
+        drop(c2_g);
+        assert_eq!(p_g.lock_type(), Ok(LockType::IntentionShared));
+        assert_eq!(try_lock_shared(&p), true);
+
+        let gc_g = gc.lock_exclusive().unwrap();
+        assert_eq!(p_g.lock_type(), Ok(LockType::IntentionShared));
+        assert_eq!(try_lock_shared(&p), false);
+
+        let gc_g = gc_g.downgrade_to(LockType::Shared).unwrap();
+        assert_eq!(try_lock_shared(&p), true);
+        drop(gc_g);
+
+        let c1_g = c1_g.upgrade_to_exclusive().map_err(|(e, _)| e).unwrap();
+        assert_eq!(p_g.lock_type(), Ok(LockType::IntentionExclusive));
+        assert_eq!(try_lock_shared(&p), false);
+
+        let c1_g = c1_g.downgrade_to(LockType::Shared).unwrap();
+        assert_eq!(p_g.lock_type(), Ok(LockType::IntentionShared));
+
+        drop(c1_g);
+        p_g.upgrade(LockType::IntentionExclusive).unwrap();
+
+        let c2_g = c2.lock_exclusive_using_parent(&p_g).unwrap();
+        assert_eq!(c2_g.parent_upgrade(), None);
+        drop(c2_g);
+
+        assert_eq!(p_g.lock_type(), Ok(LockType::IntentionExclusive));
+        drop(p_g);
+        assert_eq!(p.lock_counts().unwrap(), LOCK_EMPTY_COUNTS);
+    }
+
+    #[test]
+    fn wait_idle() {
+        let p = Arc::new(GLock::new_root(0u32).unwrap());
+        let c = Arc::new(p.new_child(0u32).unwrap());
+        let (tx, rx) = std::sync::mpsc::channel();
+
+        let holder = {
+            let c = c.clone();
+
+            std::thread::spawn(move || {
+                let mut c_g = c.lock_exclusive().unwrap();
+                tx.send(()).unwrap();
+
+                std::thread::sleep(Duration::from_millis(50));
+                *c_g = 1;
+            })
+        };
+
+        rx.recv().unwrap();
+        assert_eq!(p.wait_idle_timeout(Duration::from_millis(1)), Err(LockError::Timeout));
+        assert_eq!(p.wait_idle(), Ok(()));
+        assert_eq!(c.get(), Ok(1));
+
+        holder.join().unwrap();
+
+        let _p_g = p.lock_shared().unwrap();
+        assert_eq!(p.wait_idle(), Err(LockError::WouldSelfDeadlock));
+    }
+
+    #[test]
+    fn failed_child_lock_releases_parent_lock() {
+        let p = Arc::new(GLock::new_root(0u32).unwrap());
+        let c = Arc::new(p.new_child(0u32).unwrap());
+
+        let c_token = {
+            let c = c.clone();
+            std::thread::spawn(move || c.lock_exclusive().unwrap().into_token()).join().unwrap()
+        };
+
+        let mut counts = LOCK_EMPTY_COUNTS;
+        counts[LockType::IntentionExclusive.index()] = 1;
+
+        assert_eq!(c.try_lock_shared().err(), Some(LockError::LockBusy));
+        assert_eq!(p.lock_counts(), Ok(counts));
+
+        assert_eq!(c.lock_timeout(LockType::Shared, Duration::from_millis(1)).err(), Some(LockError::Timeout));
+        assert_eq!(p.lock_counts(), Ok(counts));
+
+        let p_g = p.lock(LockType::IntentionShared).unwrap();
+        counts[LockType::IntentionShared.index()] = 1;
+
+        assert_eq!(c.try_lock_exclusive_using_parent(&p_g).err(), Some(LockError::LockBusy));
+        assert_eq!(p_g.lock_type(), Ok(LockType::IntentionShared));
+        assert_eq!(p.lock_counts(), Ok(counts));
+
+        drop(p_g);
+        drop(c_token);
+        assert_eq!(p.lock_counts(), Ok(LOCK_EMPTY_COUNTS));
+    }
+
+    #[test]
+    fn lock_or_init() {
+        use std::sync::atomic::{ AtomicUsize, Ordering };
+
+        let l = Arc::new(GLock::new_root(None).unwrap());
+        let inits = Arc::new(AtomicUsize::new(0));
+
+        let threads: Vec<_> = (0..2).map(|i| {
+            let l = l.clone();
+            let inits = inits.clone();
+
+            std::thread::spawn(move || {
+                let mut lg = l.lock_or_init(|| {
+                    inits.fetch_add(1, Ordering::SeqCst);
+                    std::thread::sleep(Duration::from_millis(10));
+                    vec![i]
+                }).unwrap();
+
+                lg.push(i + 10);
+            })
+        }).collect();
+
+        for t in threads { t.join().unwrap(); }
+
+        assert_eq!(inits.load(Ordering::SeqCst), 1);
+
+        let v = l.snapshot().unwrap().unwrap();
+        assert_eq!(v.len(), 3);
+        assert_eq!(v[1..].contains(&10) && v[1..].contains(&11), true);
+        assert_eq!(l.try_lock_exclusive().is_ok(), true);
+    }
+
+    #[test]
+    fn global_seq() {
+        let p1 = GLock::new_root(0u32).unwrap();
+        let p2 = GLock::new_root(0u32).unwrap();
+        let c1 = p1.new_child(0u32).unwrap();
+        let c2 = p2.new_child(0u32).unwrap();
+
+        assert_eq!(c1.kernel.id(), c2.kernel.id());
+
+        let mut seqs: Vec<u64> = [&p1, &p2, &c1, &c2].iter().map(|l| l.global_seq()).collect();
+        seqs.sort();
+        seqs.dedup();
+        assert_eq!(seqs.len(), 4);
+        assert_eq!(p1.global_seq() < c1.global_seq(), true);
+    }
+
+    #[test]
+    fn unlocked() {
+        let p = Arc::new(GLock::new_root(0u32).unwrap());
+        let c = Arc::new(p.new_child(0u32).unwrap());
+
+        let mut c_g = c.lock_exclusive().unwrap();
+        *c_g = 1;
+
+        let r = c_g.unlocked(|| {
+            let c = c.clone();
+            std::thread::spawn(move || c.set(2)).join().unwrap().unwrap();
+            assert_eq!(p.try_lock_exclusive().is_ok(), false);
+            3
+        });
+
+        assert_eq!(r, Ok(3));
+        assert_eq!(*c_g, 2);
+        assert_eq!(c.try_lock_shared().is_ok(), false);
+
+        let gc = c.new_child(0u32).unwrap();
+        let gc_g = gc.lock_shared_using_parent(&c_g).unwrap();
+        assert_eq!(c_g.unlocked(|| ()), Err(LockError::ChildLocksHeld));
+        assert_eq!(*gc_g, 0);
+        drop(gc_g);
+
+        drop(c_g);
+        assert_eq!(p.lock_counts(), Ok(LOCK_EMPTY_COUNTS));
+        assert_eq!(c.lock_counts(), Ok(LOCK_EMPTY_COUNTS));
+    }
+
+    #[test]
+    fn unlocked_panic() {
+        let l = Arc::new(GLock::new_root(0u32).unwrap());
+
+        let result = {
+            let l = l.clone();
+
+            std::thread::spawn(move || {
+                let mut lg = l.lock_shared().unwrap();
+                let _ = lg.unlocked(|| panic!("unlocked"));
+            }).join()
+        };
+
+        assert_eq!(result.is_err(), true);
+        assert_eq!(l.lock_counts(), Ok(LOCK_EMPTY_COUNTS));
+    }
+
+    #[test]
+    fn unlocked_closed_during_window() {
+        let l = GLock::new_root(0u32).unwrap();
+        let mut lg = l.lock_exclusive().unwrap();
+
+        let r = lg.unlocked(|| {
+            assert_eq!(l.close(), Ok(()));
+            assert_eq!(l.lock_counts(), Ok(LOCK_EMPTY_COUNTS));
+            1
+        });
+
+        assert_eq!(r, Ok(1));
+        *lg = 2;
+        assert_eq!(l.lock_counts().unwrap()[LockType::Exclusive.index()], 1);
+
+        drop(lg);
+        assert_eq!(l.lock_counts(), Ok(LOCK_EMPTY_COUNTS));
+        assert_eq!(l.try_lock_shared().err(), Some(LockError::LockReleased));
+    }
+
+    #[test]
+    fn unlocked_reset_during_window() {
+        let l = GLock::new_root(0u32).unwrap();
+        let mut lg = l.lock_exclusive().unwrap();
+
+        assert_eq!(lg.unlocked(|| unsafe { l.force_reset() }), Ok(Ok(())));
+        *lg = 1;
+        assert_eq!(l.lock_counts().unwrap()[LockType::Exclusive.index()], 1);
+
+        drop(lg);
+        assert_eq!(l.lock_counts(), Ok(LOCK_EMPTY_COUNTS));
+        assert_eq!(l.get(), Ok(1));
+    }
+
+    #[test]
+    fn unlocked_reparent_during_window() {
+        let p1 = Arc::new(GLock::new_root(0u32).unwrap());
+        let p2 = Arc::new(p1.new_child(0u32).unwrap());
+        let c = Arc::new(p1.new_child(0u32).unwrap());
+
+        let mut c_g = c.lock_exclusive().unwrap();
+
+        let mover = c_g.unlocked(|| {
+            // The current thread would wait for itself.
+            assert_eq!(p1.reparent(&c, &p2), Err(LockError::WouldSelfDeadlock));
+
+            let mover = {
+                let (p1, p2, c) = (p1.clone(), p2.clone(), c.clone());
+                std::thread::spawn(move || p1.reparent(&c, &p2))
+            };
+
+            // The move waits for the lock to be acquired again under the old parent.
+            std::thread::sleep(Duration::from_millis(20));
+            assert_eq!(c.depth(), 1);
+            mover
+        }).unwrap();
+
+        *c_g = 1;
+        assert_eq!(c.lock_counts().unwrap()[LockType::Exclusive.index()], 1);
+
+        drop(c_g);
+        assert_eq!(mover.join().unwrap(), Ok(()));
+        assert_eq!(c.depth(), 2);
+        assert_eq!(c.get(), Ok(1));
+        assert_eq!(p1.lock_counts(), Ok(LOCK_EMPTY_COUNTS));
+    }
+
+    #[test]
+    fn get_with_type() {
+        fn read_at_least_shared(lg: &GLockGuard<u32>) -> LockResult<u32> {
+            let (data, lock_type) = lg.get_with_type()?;
+            assert_eq!(LockType::Shared.upgradable_to(lock_type), true);
+            Ok(*data)
+        }
+
+        let l = GLock::new_root(5u32).unwrap();
+
+        let lg = l.lock(LockType::SharedIntentionExclusive).unwrap();
+        assert_eq!(lg.get_with_type(), Ok((&5, LockType::SharedIntentionExclusive)));
+        assert_eq!(read_at_least_shared(&lg), Ok(5));
+    }
+
+    #[test]
+    fn conflicting_upgrades() {
+        use std::sync::Barrier;
+
+        let l = Arc::new(GLock::new_root(0u32).unwrap());
+        let barrier = Arc::new(Barrier::new(2));
+
+        for _ in 0..50 {
+            let threads: Vec<_> = (0..2).map(|_| {
+                let l = l.clone();
+                let barrier = barrier.clone();
+
+                std::thread::spawn(move || {
+                    let lg = l.lock_shared().unwrap();
+                    barrier.wait();
+
+                    match lg.upgrade_to_exclusive() {
+                        Ok(mut lg)          => { *lg += 1; None },
+                        Err((err, _lg))     => Some(err),
+                    }
+                })
+            }).collect();
+
+            let errors: Vec<_> = threads.into_iter().filter_map(|t| t.join().unwrap()).collect();
+            assert_eq!(errors, vec![LockError::ConflictingUpgrade]);
+        }
+
+        assert_eq!(l.get(), Ok(50));
+        assert_eq!(l.lock_counts(), Ok(LOCK_EMPTY_COUNTS));
+    }
+
+    #[test]
+    fn lock_released() {
+        let p = GLock::new_root(0u32).unwrap();
+        let c1 = p.new_child(0u32).unwrap();
+        let c2 = p.new_child(0u32).unwrap();
+
+        let c1_g = c1.lock_exclusive().unwrap();
+        let p_g = c1_g.take_parent_guard().unwrap();
+        drop(p);
+
+        assert_eq!(c2.lock_shared().err(), Some(LockError::LockReleased));
+        assert_eq!(c2.try_lock_exclusive().err(), Some(LockError::LockReleased));
+
+        let c2_g = c2.lock_shared_using_parent(&p_g).unwrap();
+        drop(c2_g);
+        drop(p_g);
+        drop(c1_g);
+
+        assert_eq!(c1.lock_shared().err(), Some(LockError::LockReleased));
+    }
+
+    #[test]
+    fn with_slice() {
+        let l = GLock::new_root(vec![0u32; 6]).unwrap();
+        let mut lg = l.lock_exclusive().unwrap();
+
+        lg.with_slice(|data| {
+            let (left, right) = data.split_at_mut(3);
+            left.iter_mut().for_each(|x| *x = 1);
+            right.iter_mut().for_each(|x| *x = 2);
+        });
+
+        assert_eq!(*lg, vec![1, 1, 1, 2, 2, 2]);
+
+        let a = GLock::new_root([1u8, 2, 3]).unwrap();
+        let len = a.lock_exclusive().unwrap().with_slice(|data: &mut [u8]| { data.reverse(); data.len() });
+
+        assert_eq!(len, 3);
+        assert_eq!(a.get(), Ok([3, 2, 1]));
+    }
+
+    #[test]
+    fn shared_lock() {
+        let p = GLock::new_root(0u32).unwrap().into_shared();
+        let c = p.new_child(0u32).unwrap().into_shared();
+        let c2 = c.clone_handle();
+
+        assert_eq!(c.handle_count(), 2);
+        assert_eq!(c.same_lock(&c2), true);
+
+        let threads: Vec<_> = (0..4).map(|_| {
+            let c = c.clone_handle();
+            std::thread::spawn(move || { *c.lock_exclusive().unwrap() += 1; })
+        }).collect();
+
+        for t in threads { t.join().unwrap(); }
+
+        assert_eq!(c.get(), Ok(4));
+        assert_eq!(c.handle_count(), 2);
+
+        let p_g = p.lock(LockType::IntentionExclusive).unwrap();
+        *c2.lock_exclusive_using_parent(&p_g).unwrap() += 1;
+        drop(p_g);
+
+        let c = SharedGLock::try_unwrap(c).unwrap_err();
+        drop(c2);
+
+        let c = SharedGLock::try_unwrap(c).unwrap();
+        assert_eq!(c.into_inner(), 5);
+    }
+
+    #[test]
+    fn copy_out() {
+        let l = GLock::new_root(1u32).unwrap();
+
+        let value = match l.lock_shared().unwrap().upgrade_if(|v| *v == 1) {
+            Ok(mut lg)          => { *lg += 1; lg.downgrade_to(LockType::Shared).map_err(|(err, _)| err).unwrap().copy_out() },
+            Err((_, lg))        => lg.copy_out(),
+        };
+
+        assert_eq!(value, 2);
+        assert_eq!(l.lock_counts().unwrap(), LOCK_EMPTY_COUNTS);
+    }
+
+    #[test]
+    fn lock_if() {
+        let p = GLock::new_root(0u32).unwrap();
+        let c = p.new_child(0u32).unwrap();
+        let below_cap = |counts: &[usize; LOCK_TYPE_COUNT]| counts[LockType::Shared.index()] < 2;
+
+        let g1 = c.lock_if(LockType::Shared, below_cap).unwrap().unwrap();
+        let g2 = c.lock_if(LockType::Shared, below_cap).unwrap().unwrap();
+        assert_eq!(c.lock_if(LockType::Shared, below_cap).unwrap().is_none(), true);
+        assert_eq!(p.lock_counts().unwrap()[LockType::IntentionShared.index()], 2);
+
+        drop(g1);
+        let g3 = c.lock_if(LockType::Shared, below_cap).unwrap().unwrap();
+        assert_eq!(c.lock_counts().unwrap()[LockType::Shared.index()], 2);
+
+        drop(g2);
+        drop(g3);
+
+        let l = Arc::new(GLock::new_root(0u32).unwrap());
+        let lg = l.lock_exclusive().unwrap();
+
+        let t = {
+            let l = l.clone();
+            std::thread::spawn(move || { l.lock_if(LockType::Shared, |counts| counts[LockType::Shared.index()] == 0).unwrap().map(|lg| *lg) })
+        };
+
+        while l.waiters().unwrap() == 0 { std::thread::yield_now(); }
+        drop(lg);
+
+        assert_eq!(t.join().unwrap(), Some(0));
+        assert_eq!(p.lock_counts().unwrap(), LOCK_EMPTY_COUNTS);
+        assert_eq!(l.lock_counts().unwrap(), LOCK_EMPTY_COUNTS);
+    }
+
+    #[test]
+    fn lock_nonblocking_parent() {
+        let p = Arc::new(GLock::new_root(0u32).unwrap());
+        let c = Arc::new(p.new_child(0u32).unwrap());
+
+        let p_lg = p.lock_shared().unwrap();
+        assert_eq!(c.lock_exclusive_nonblocking_parent().err(), Some(LockError::LockBusy));
+        assert_eq!(c.lock_nonblocking_parent(LockType::Shared).is_ok(), true);
+        drop(p_lg);
+
+        let c_lg = c.lock_exclusive().unwrap();
+
+        let t = {
+            let c = c.clone();
+            std::thread::spawn(move || { c.lock_exclusive_nonblocking_parent().map(|mut lg| { *lg += 1; }) })
+        };
+
+        while c.waiters().unwrap() == 0 { std::thread::yield_now(); }
+        drop(c_lg);
+
+        assert_eq!(t.join().unwrap(), Ok(()));
+        assert_eq!(c.get(), Ok(1));
+        assert_eq!(p.lock_counts().unwrap(), LOCK_EMPTY_COUNTS);
+    }
+
+    #[test]
+    fn parent_chain_types() {
+        let p = GLock::new_root(0u32).unwrap();
+        let c = p.new_child_with_id(1, 0u32).unwrap();
+        let gc = c.new_child_with_id(2, 0u32).unwrap();
+
+        assert_eq!(p.parent_chain_types(LockType::Exclusive), vec![]);
+        assert_eq!(c.parent_chain_types(LockType::Shared), vec![(None, LockType::IntentionShared)]);
+        assert_eq!(gc.parent_chain_types(LockType::Exclusive), vec![(None, LockType::IntentionExclusive), (Some(1), LockType::IntentionExclusive)]);
+        assert_eq!(gc.parent_chain_types(LockType::Shared), vec![(None, LockType::IntentionShared), (Some(1), LockType::IntentionShared)]);
+        assert_eq!(gc.parent_chain_types(LockType::SharedIntentionExclusive).len(), gc.depth());
+
+        let gc_lg = gc.lock(LockType::SharedIntentionExclusive).unwrap();
+        let p_counts = p.lock_counts().unwrap();
+        let c_counts = c.lock_counts().unwrap();
+
+        for (id, lock_type) in gc.parent_chain_types(LockType::SharedIntentionExclusive) {
+            let counts = if id.is_none() { p_counts } else { c_counts };
+            assert_eq!(counts[lock_type.index()], 1);
+        }
+
+        drop(gc_lg);
+    }
+
+    #[test]
+    fn swap_children() {
+        let p = GLock::new_root(0u32).unwrap();
+        let a = p.new_child(String::from("a")).unwrap();
+        let b = p.new_child(String::from("b")).unwrap();
+        let c = p.new_child(String::new()).unwrap();
+
+        let p_lg = p.lock(LockType::IntentionExclusive).unwrap();
+        p_lg.swap_children(&a, &b).unwrap();
+        p_lg.swap_children(&c, &b).unwrap();
+        assert_eq!(p_lg.swap_children(&a, &a), Err(LockError::DuplicateLock));
+
+        assert_eq!(a.lock_counts().unwrap(), LOCK_EMPTY_COUNTS);
+        assert_eq!(b.lock_counts().unwrap(), LOCK_EMPTY_COUNTS);
+        drop(p_lg);
+
+        assert_eq!(a.snapshot(), Ok(String::from("b")));
+        assert_eq!(b.snapshot(), Ok(String::new()));
+        assert_eq!(c.snapshot(), Ok(String::from("a")));
+        assert_eq!(p.lock_counts().unwrap(), LOCK_EMPTY_COUNTS);
+    }
+
+    #[test]
+    fn upgraded_mut() {
+        let l = GLock::new_root(0u32).unwrap();
+
+        let mut lg = l.lock_shared().unwrap();
+        assert_eq!(lg.upgraded_mut(), None);
+
+        lg.upgrade(LockType::Exclusive).unwrap();
+        *lg.upgraded_mut().unwrap() += 1;
+        assert_eq!(*lg, 1);
+        drop(lg);
+
+        let mut lg = l.lock(LockType::SharedIntentionExclusive).unwrap();
+        assert_eq!(lg.upgraded_mut(), None);
+        drop(lg);
+
+        let mut lg = l.lock(LockType::Exclusive).unwrap();
+        *lg.upgraded_mut().unwrap() += 1;
+        drop(lg);
+
+        assert_eq!(l.get(), Ok(2));
+    }
+
+    #[test]
+    fn child_map() {
+        let p = GLock::new_root(0u32).unwrap();
+        let mut map = p.new_child_map::<String, u32>();
+
+        *map.child_entry(String::from("a"), || 1).unwrap().lock_exclusive().unwrap() += 1;
+        map.child_entry(String::from("b"), || 10).unwrap();
+        assert_eq!(map.child_entry(String::from("a"), || 100).unwrap().get(), Ok(2));
+        assert_eq!(map.len(), 2);
+
+        let mut values: Vec<(String, u32)> = map.iter().map(|(k, l)| (k.clone(), l.get().unwrap())).collect();
+        values.sort();
+        assert_eq!(values, vec![(String::from("a"), 2), (String::from("b"), 10)]);
+
+        let p_lg = p.lock(LockType::IntentionExclusive).unwrap();
+        assert_eq!(p.try_lock_exclusive().err(), Some(LockError::LockBusy));
+        *map.get("b").unwrap().lock_exclusive_using_parent(&p_lg).unwrap() += 1;
+        drop(p_lg);
+
+        let b = map.remove("b").unwrap();
+        assert_eq!(map.contains_key("b"), false);
+        assert_eq!(b.get(), Ok(11));
+        assert_eq!(p.kernel.children_len(), 2);
+
+        drop(b);
+        assert_eq!(p.kernel.children_len(), 1);
+        assert_eq!(map.keys().collect::<Vec<_>>(), vec!["a"]);
+    }
+
+    #[test]
+    fn nested_child_map() {
+        let pb = GLockBuilder::new_root_builder().poison_policy(PoisonPolicy::FailStop);
+        let map: GLockMap<u32, u32> = pb.new_child_map();
+        let p = pb.build(map).unwrap();
+
+        {
+            let mut p_lg = p.lock_exclusive().unwrap();
+            for k in 0..3 { p_lg.child_entry(k, || k).unwrap(); }
+        }
+
+        let p_lg = p.lock(LockType::IntentionShared).unwrap();
+        let sum: u32 = p_lg.iter().map(|(_, c)| *c.lock_shared_using_parent(&p_lg).unwrap()).sum();
+        assert_eq!(sum, 3);
+        assert_eq!(p_lg.get(&1).unwrap().kernel.options().poison_policy, PoisonPolicy::FailStop);
+    }
+
+    #[test]
+    fn exclusive_guard_excludes_other_guards() {
+        let l = GLock::new_root(0u32).unwrap();
+
+        for lock_type in LockType::lock_types().iter() {
+            let lg = l.lock(*lock_type).unwrap();
+            assert_eq!(l.try_lock_exclusive().err(), Some(LockError::LockBusy));
+            assert_eq!(l.lock_timeout(LockType::Exclusive, Duration::from_millis(1)).err(), Some(LockError::Timeout));
+            drop(lg);
+        }
+
+        let mut lg = l.lock_exclusive().unwrap();
+
+        for lock_type in LockType::lock_types().iter() {
+            assert_eq!(l.try_lock(*lock_type).err(), Some(LockError::LockBusy));
+        }
+
+        *lg += 1;
+
+        let token = lg.into_token();
+        let lg = l.from_token(token).unwrap();
+        assert_eq!(*lg, 1);
+    }
+
+    #[test]
+    fn in_place_upgrade_excludes_other_guards() {
+        let l = GLock::new_root(0u32).unwrap();
+
+        let mut lg1 = l.lock_shared().unwrap();
+        let lg2 = l.lock(LockType::IntentionShared).unwrap();
+
+        assert_eq!(lg1.try_upgrade(LockType::Exclusive), Err(LockError::LockBusy));
+        assert_eq!(lg1.upgrade(LockType::Exclusive), Err(LockError::WouldSelfDeadlock));
+        assert_eq!(lg1.upgraded_mut(), None);
+        assert_eq!(*lg2, 0);
+
+        drop(lg2);
+        lg1.upgrade(LockType::Exclusive).unwrap();
+        *lg1.upgraded_mut().unwrap() += 1;
+
+        assert_eq!(l.try_lock(LockType::IntentionShared).err(), Some(LockError::LockBusy));
+
+        let value = lg1.unlocked(|| { *l.lock_exclusive().unwrap() += 1; }).map(|_| *lg1);
+        assert_eq!(value, Ok(2));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn assume_locked() {
+        let l = Arc::new(GLock::new_root(5u32).unwrap());
+
+        assert_eq!(unsafe { l.assume_locked(LockType::IntentionShared) }.err(), Some(LockError::LockNotHeld));
+
+        let lg = l.lock_shared().unwrap();
+        assert_eq!(unsafe { l.assume_locked(LockType::Shared) }, Ok(&5));
+        assert_eq!(unsafe { l.assume_locked(LockType::IntentionShared) }, Ok(&5));
+        assert_eq!(unsafe { l.assume_locked(LockType::Exclusive) }.err(), Some(LockError::LockNotHeld));
+        assert_eq!(unsafe { l.assume_locked(LockType::IntentionExclusive) }.err(), Some(LockError::LockNotHeld));
+        assert_eq!(l.lock_counts().unwrap()[LockType::Shared.index()], 1);
+
+        let t = {
+            let l = l.clone();
+            std::thread::spawn(move || { unsafe { l.assume_locked(LockType::IntentionShared) }.copied() })
+        };
+
+        assert_eq!(t.join().unwrap(), Err(LockError::LockNotHeld));
+        drop(lg);
+    }
+
+    #[test]
+    fn adopt_child() {
+        let p = GLock::new_root(0u32).unwrap();
+        let c = Arc::new(GLock::new_root(0u32).unwrap());
+        let gc = c.new_child(0u32).unwrap();
+
+        let c_lg = c.lock_exclusive().unwrap();
+
+        let t = {
+            let c = c.clone();
+            std::thread::spawn(move || { c.lock_shared().map(|lg| *lg) })
+        };
+
+        while c.waiters().unwrap() == 0 { std::thread::yield_now(); }
+
+        let adopter = {
+            let c = c.clone();
+            let p_kernel = p.kernel.clone();
+            std::thread::spawn(move || { p_kernel.adopt(&c.kernel) })
+        };
+
+        while c.waiters().unwrap() < 2 { std::thread::yield_now(); }
+        drop(c_lg);
+
+        assert_eq!(adopter.join().unwrap(), Ok(()));
+        assert_eq!(t.join().unwrap(), Ok(0));
+
+        assert_eq!(c.depth(), 1);
+        assert_eq!(gc.parent_chain_types(LockType::Exclusive), vec![(None, LockType::IntentionExclusive), (None, LockType::IntentionExclusive)]);
+
+        let gc_lg = gc.lock_exclusive().unwrap();
+        assert_eq!(p.lock_counts().unwrap()[LockType::IntentionExclusive.index()], 1);
+        assert_eq!(p.try_lock_exclusive().err(), Some(LockError::LockBusy));
+        drop(gc_lg);
+
+        let c_lg = c.lock_shared().unwrap();
+        assert_eq!(c_lg.lock_id(), None);
+        assert_eq!(p.lock_counts().unwrap()[LockType::IntentionShared.index()], 1);
+        drop(c_lg);
+
+        assert_eq!(p.adopt_child(&c), Err(LockError::HasParent));
+        assert_eq!(p.adopt_child(&gc), Err(LockError::HasParent));
+        assert_eq!(gc.adopt_child(&p), Err(LockError::InvalidReparent));
+        assert_eq!(p.adopt_child(&p), Err(LockError::InvalidReparent));
+        assert_eq!(p.lock_counts().unwrap(), LOCK_EMPTY_COUNTS);
+        assert_eq!(c.lock_counts().unwrap(), LOCK_EMPTY_COUNTS);
+    }
+
+    #[test]
+    fn supports_child() {
         let p = GLock::new_root(0u32).unwrap();
+        let c = p.new_child(0u32).unwrap();
 
-        let c1 = p.new_child(0u32).unwrap();
-        let c2 = p.new_child(0u32).unwrap();
+        let p_lg = p.lock(LockType::IntentionShared).unwrap();
+        assert_eq!(p_lg.supports_child(LockType::IntentionShared), true);
+        assert_eq!(p_lg.supports_child(LockType::Shared), true);
+        assert_eq!(p_lg.supports_child(LockType::Exclusive), false);
 
-        for p_type1 in LockType::lock_types() {
-            let p_lg1 = p.try_lock(*p_type1).unwrap();
+        drop(c.lock_using_parent(LockType::Shared, &p_lg).unwrap());
+        assert_eq!(p_lg.lock_type().unwrap(), LockType::IntentionShared);
 
-            for p_type2 in LockType::lock_types() {
-                assert_eq!(p.try_lock(*p_type2).is_ok(), p_type1.compatible_with(*p_type2));
+        let c_lg = c.lock_exclusive_using_parent(&p_lg).unwrap();
+        assert_eq!(p_lg.lock_type().unwrap(), LockType::IntentionExclusive);
+        assert_eq!(p_lg.supports_child(LockType::Exclusive), true);
+        drop(c_lg);
+        drop(p_lg);
+
+        for parent_type in LockType::lock_types().iter() {
+            let p_lg = p.try_lock(*parent_type).unwrap();
+
+            for child_type in LockType::lock_types().iter() {
+                assert_eq!(p_lg.supports_child(*child_type), parent_type.supports_children(*child_type));
             }
+        }
+    }
 
-            for c1_type1 in LockType::lock_types() {
-                let _c1_lg1 = c1.try_lock_using_parent(*c1_type1, &p_lg1).unwrap();
+    #[test]
+    fn close() {
+        let p = Arc::new(GLock::new_root(0u32).unwrap());
+        let c = Arc::new(p.new_child(0u32).unwrap());
 
-                for c1_type2 in LockType::lock_types() {
-                    assert_eq!(c1.try_lock_using_parent(*c1_type2, &p_lg1).is_ok(), c1_type1.compatible_with(*c1_type2));
-                }
+        let mut c_lg = c.lock_exclusive().unwrap();
 
-                for c2_type in LockType::lock_types() {
-                    c2.try_lock_using_parent(*c2_type, &p_lg1).unwrap();
+        assert_eq!(p.close(), Err(LockError::WouldSelfDeadlock));
+        assert_eq!(c.try_lock_shared().err(), Some(LockError::LockBusy));
+
+        let waiter = {
+            let c = c.clone();
+            std::thread::spawn(move || { c.lock_shared().map(|lg| *lg) })
+        };
+
+        while c.waiters().unwrap() == 0 { std::thread::yield_now(); }
+
+        let closer = {
+            let p = p.clone();
+            std::thread::spawn(move || { p.close_timeout(Duration::from_millis(1)) })
+        };
+
+        assert_eq!(closer.join().unwrap(), Err(LockError::Timeout));
+        assert_eq!(waiter.join().unwrap(), Err(LockError::LockReleased));
+        assert_eq!(p.try_lock_shared().err(), Some(LockError::LockReleased));
+        assert_eq!(c.try_lock_shared().err(), Some(LockError::LockReleased));
+
+        let closer = {
+            let p = p.clone();
+            std::thread::spawn(move || { p.close() })
+        };
+
+        *c_lg = 7;
+        drop(c_lg);
+
+        assert_eq!(closer.join().unwrap(), Ok(()));
+        assert_eq!(p.lock_counts().unwrap(), LOCK_EMPTY_COUNTS);
+        assert_eq!(c.lock_counts().unwrap(), LOCK_EMPTY_COUNTS);
+        assert_eq!(c.lock_shared().err(), Some(LockError::LockReleased));
+        assert_eq!(p.close(), Ok(()));
+    }
+
+    #[test]
+    #[cfg(feature = "timestamps")]
+    fn acquired_at() {
+        use std::time::Instant;
+
+        let p = GLock::new_root(0u32).unwrap();
+        let c = p.new_child(0u32).unwrap();
+
+        let before = Instant::now();
+        let c_lg = c.lock_shared().unwrap();
+        let acquired_at = c_lg.acquired_at();
+        assert_eq!(before <= acquired_at && acquired_at <= Instant::now(), true);
+
+        std::thread::sleep(Duration::from_millis(1));
+        let p_lg = p.lock(LockType::IntentionShared).unwrap();
+        assert_eq!(p_lg.acquired_at() > acquired_at, true);
+
+        let c_lg = c_lg.upgrade_to_exclusive().map_err(|(e, _)| e).unwrap();
+        assert_eq!(c_lg.acquired_at(), acquired_at);
+    }
+
+    #[test]
+    fn reader_writer_count() {
+        let p = GLock::new_root(0u32).unwrap();
+        let c = p.new_child(0u32).unwrap();
+
+        assert_eq!((p.reader_count(), p.writer_count()), (Ok(0), Ok(0)));
+
+        let p_lg1 = p.lock_shared().unwrap();
+        let p_lg2 = p.lock_shared().unwrap();
+        let c_lg = c.lock_shared().unwrap();
+        assert_eq!((p.reader_count(), p.writer_count()), (Ok(2), Ok(0)));
+        assert_eq!((c.reader_count(), c.writer_count()), (Ok(1), Ok(0)));
+        drop(c_lg);
+        drop(p_lg1);
+        drop(p_lg2);
+
+        let p_lg1 = p.lock(LockType::SharedIntentionExclusive).unwrap();
+        let p_lg2 = p.lock(LockType::IntentionShared).unwrap();
+        assert_eq!((p.reader_count(), p.writer_count()), (Ok(1), Ok(0)));
+
+        let c_lg = c.lock_exclusive_using_parent(&p_lg1).unwrap();
+        assert_eq!((p.reader_count(), p.writer_count()), (Ok(1), Ok(0)));
+        assert_eq!((c.reader_count(), c.writer_count()), (Ok(0), Ok(1)));
+        drop(c_lg);
+        drop(p_lg1);
+        drop(p_lg2);
+
+        let c_lg = c.lock_exclusive().unwrap();
+        assert_eq!((p.reader_count(), p.writer_count()), (Ok(0), Ok(0)));
+        drop(c_lg);
+
+        let p_lg = p.lock_exclusive().unwrap();
+        assert_eq!((p.reader_count(), p.writer_count()), (Ok(0), Ok(1)));
+        drop(p_lg);
+
+        assert_eq!((p.reader_count(), p.writer_count()), (Ok(0), Ok(0)));
+    }
+
+    #[test]
+    fn lock_preferred() {
+        let p = Arc::new(GLock::new_root(0u32).unwrap());
+        let c = p.new_child(0u32).unwrap();
+
+        let c_lg = c.lock_preferred(LockType::Exclusive, LockType::Shared).unwrap();
+        assert_eq!(c_lg.lock_type().unwrap(), LockType::Exclusive);
+        drop(c_lg);
+
+        let c_lg1 = c.lock_shared().unwrap();
+        let c_lg2 = c.lock_preferred(LockType::Exclusive, LockType::Shared).unwrap();
+        assert_eq!(c_lg2.lock_type().unwrap(), LockType::Shared);
+        drop(c_lg2);
+        drop(c_lg1);
+
+        let p_lg = p.lock_shared().unwrap();
+
+        let t = {
+            let p = p.clone();
+            std::thread::spawn(move || {
+                p.lock_preferred(LockType::Exclusive, LockType::IntentionShared).and_then(|lg| lg.lock_type())
+            })
+        };
+
+        assert_eq!(t.join().unwrap(), Ok(LockType::IntentionShared));
+
+        // The implicit parent lock of the preferred type is busy as well, so it falls back.
+        assert_eq!(c.lock_preferred(LockType::Exclusive, LockType::Shared).and_then(|lg| lg.lock_type()), Ok(LockType::Shared));
+        drop(p_lg);
+
+        assert_eq!(p.lock_counts().unwrap(), LOCK_EMPTY_COUNTS);
+    }
+
+    #[test]
+    fn lock_preferred_invalid_types() {
+        let p = GLock::new_root(0u32).unwrap();
+        let c = p.new_child(0u32).unwrap();
+
+        for lt in LockType::lock_types().iter() {
+            assert_eq!(c.lock_preferred(*lt, *lt).err(), Some(LockError::InvalidLockType));
+        }
+
+        assert_eq!(c.lock_preferred(LockType::Shared, LockType::Exclusive).err(), Some(LockError::InvalidLockType));
+        assert_eq!(c.lock_preferred(LockType::Shared, LockType::IntentionExclusive).err(), Some(LockError::InvalidLockType));
+        assert_eq!(c.lock_preferred(LockType::IntentionExclusive, LockType::Shared).err(), Some(LockError::InvalidLockType));
+        assert_eq!(p.lock_counts().unwrap(), LOCK_EMPTY_COUNTS);
+        assert_eq!(c.lock_counts().unwrap(), LOCK_EMPTY_COUNTS);
+    }
+
+    #[test]
+    fn admit() {
+        use std::sync::Mutex;
+
+        let calls = Arc::new(Mutex::new(Vec::new()));
+
+        let p = GLock::<u32>::new_root_builder()
+            .admit({
+                let calls = calls.clone();
+                move |id, lock_type| {
+                    calls.lock().unwrap().push((id, lock_type));
+                    lock_type != LockType::Exclusive
                 }
+            })
+            .build(0u32)
+            .unwrap();
+
+        let c = p.new_child(0u32).unwrap();
+        let c_id = c.lock_shared().unwrap().lock_id();
+        assert_eq!(mem::take(&mut *calls.lock().unwrap()), vec![(c_id, LockType::Shared), (None, LockType::IntentionShared)]);
+
+        assert_eq!(p.lock_exclusive().err(), Some(LockError::Rejected));
+        assert_eq!(p.try_lock_exclusive().err(), Some(LockError::Rejected));
+        assert_eq!(c.lock(LockType::Exclusive).err(), Some(LockError::Rejected));
+        assert_eq!(mem::take(&mut *calls.lock().unwrap()), vec![(None, LockType::Exclusive), (None, LockType::Exclusive), (c_id, LockType::Exclusive)]);
+
+        let p_lg = p.lock(LockType::IntentionExclusive).unwrap();
+        assert_eq!(c.lock_exclusive_using_parent(&p_lg).err(), Some(LockError::Rejected));
+        assert_eq!(p_lg.upgrade(LockType::Exclusive), Ok(()));
+        drop(p_lg);
+
+        assert_eq!(p.lock_counts().unwrap(), LOCK_EMPTY_COUNTS);
+        assert_eq!(GLock::new_root(0u32).unwrap().lock_exclusive().is_ok(), true);
+    }
+
+    #[test]
+    fn lock_all_children_shared() {
+        let b = GLock::<Vec<GLock<u32>>>::new_root_builder();
+        let children = (0..4).map(|i| b.new_child(i)).collect::<LockResult<Vec<_>>>().unwrap();
+        let p = Arc::new(b.build(children).unwrap());
+
+        let p_lg = p.lock(LockType::IntentionShared).unwrap();
+        let mut c_lg = p_lg[2].lock_exclusive_using_parent(&p_lg).unwrap();
+
+        let t = {
+            let p = p.clone();
+            std::thread::spawn(move || {
+                let p_lg = p.lock(LockType::IntentionShared).unwrap();
+                let c_lgs = p_lg.lock_all_children_shared().unwrap();
+                c_lgs.iter().map(|lg| **lg).collect::<Vec<_>>()
+            })
+        };
+
+        *c_lg = 10;
+        drop(c_lg);
+
+        assert_eq!(t.join().unwrap(), vec![0, 1, 10, 3]);
+
+        let c_lgs = p_lg.lock_all_children_shared().unwrap();
+        assert_eq!(c_lgs.iter().map(|lg| lg.lock_id()).collect::<Vec<_>>(), vec![Some(0), Some(1), Some(2), Some(3)]);
+        assert_eq!(c_lgs.iter().all(|lg| lg.lock_type() == Ok(LockType::Shared)), true);
+        assert_eq!(p_lg[0].try_lock_exclusive_using_parent(&p_lg).err(), Some(LockError::LockBusy));
+        drop(c_lgs);
+
+        assert_eq!(p_lg[0].try_lock_exclusive_using_parent(&p_lg).is_ok(), true);
+        drop(p_lg);
+        assert_eq!(p.lock_counts().unwrap(), LOCK_EMPTY_COUNTS);
+    }
+
+    #[test]
+    fn lock_using_parents() {
+        let r = GLock::new_root(0u32).unwrap();
+        let m = r.new_child(0u32).unwrap();
+        let l = m.new_child(0u32).unwrap();
+
+        let r_lg = r.lock(LockType::IntentionExclusive).unwrap();
+        let m_lg = m.lock_using_parent(LockType::IntentionExclusive, &r_lg).unwrap();
+
+        let mut l_lg = l.lock_using_parents(LockType::Exclusive, &[&r_lg, &m_lg]).unwrap();
+        *l_lg.upgraded_mut().unwrap() = 5;
+        assert_eq!(r.lock_counts().unwrap()[LockType::IntentionExclusive.index()], 1);
+        assert_eq!(m.lock_counts().unwrap()[LockType::IntentionExclusive.index()], 1);
+        drop(l_lg);
+
+        let l_lg = l.lock_using_parents(LockType::Shared, &[&m_lg]).unwrap();
+        assert_eq!(*l_lg, 5);
+        drop(l_lg);
+
+        assert_eq!(l.lock_using_parents(LockType::Shared, &[&m_lg, &r_lg]).err(), Some(LockError::InvalidParentLock));
+        assert_eq!(l.lock_using_parents(LockType::Shared, &[&r_lg]).err(), Some(LockError::InvalidParentLock));
+        assert_eq!(l.lock_using_parents(LockType::Shared, &[&r_lg, &r_lg, &m_lg]).err(), Some(LockError::InvalidParentLock));
+        assert_eq!(l.lock_using_parents(LockType::Shared, &[]).err(), Some(LockError::ParentNotHeld));
+        assert_eq!(r.lock_using_parents(LockType::Shared, &[&m_lg]).err(), Some(LockError::InvalidParentLock));
+
+        drop(m_lg);
+        drop(r_lg);
+        assert_eq!(r.lock_counts().unwrap(), LOCK_EMPTY_COUNTS);
+        assert_eq!(m.lock_counts().unwrap(), LOCK_EMPTY_COUNTS);
+    }
+
+    #[test]
+    fn multi_lock_transaction() {
+        let p = GLock::new_root(0u32).unwrap();
+        let a = p.new_child(1u32).unwrap();
+        let b = p.new_child(String::from("b")).unwrap();
+
+        let mut tx = Transaction::new();
+        assert_eq!(tx.acquire(&a, LockType::Exclusive), Ok(()));
+        assert_eq!(tx.try_acquire(&b, LockType::Shared), Ok(()));
+        assert_eq!(tx.len(), 2);
+        assert_eq!(a.try_lock_shared().err(), Some(LockError::LockBusy));
+
+        let mut tokens = tx.commit().into_iter();
+        let a_lg = a.from_token(tokens.next().unwrap()).map_err(|(e, _)| e).unwrap();
+        let b_lg = b.from_token(tokens.next().unwrap()).map_err(|(e, _)| e).unwrap();
+        assert_eq!(a_lg.lock_type(), Ok(LockType::Exclusive));
+        assert_eq!((*a_lg, b_lg.as_str()), (1, "b"));
+        drop(a_lg);
+        drop(b_lg);
+        assert_eq!(p.lock_counts().unwrap(), LOCK_EMPTY_COUNTS);
+
+        // A failed acquisition releases the locks acquired so far.
+        let b_lg = b.lock_exclusive().unwrap();
+        let mut tx = Transaction::new();
+        assert_eq!(tx.try_acquire(&a, LockType::Exclusive), Ok(()));
+        assert_eq!(tx.try_acquire(&b, LockType::Shared), Err(LockError::LockBusy));
+        assert_eq!(tx.is_empty(), true);
+        assert_eq!(a.try_lock_exclusive().is_ok(), true);
+        drop(b_lg);
+
+        let mut tx = Transaction::new();
+        assert_eq!(tx.try_acquire(&p, LockType::IntentionShared), Ok(()));
+        assert_eq!(tx.try_acquire(&a, LockType::Shared), Ok(()));
+        tx.abort();
+        assert_eq!(p.lock_counts().unwrap(), LOCK_EMPTY_COUNTS);
+
+        let mut tx = Transaction::new();
+        assert_eq!(tx.try_acquire(&b, LockType::Exclusive), Ok(()));
+        drop(tx);
+        assert_eq!(p.lock_counts().unwrap(), LOCK_EMPTY_COUNTS);
+        assert_eq!(b.lock_counts().unwrap(), LOCK_EMPTY_COUNTS);
+    }
+
+    #[test]
+    fn build_tree() {
+        let spec = LockSpec::new("root", vec![
+            LockSpec::new("a", vec![LockSpec::leaf("a1"), LockSpec::leaf("a2")]),
+            LockSpec::leaf("b"),
+        ]);
+
+        let tree = GLockBuilder::new_root_builder().poison_policy(PoisonPolicy::FailStop).build_tree(spec).unwrap();
+
+        let r_lg = tree.lock(LockType::IntentionExclusive).unwrap();
+        assert_eq!(r_lg.data, "root");
+        assert_eq!(r_lg.children.iter().map(|c| c.lock_using_parent(LockType::Shared, &r_lg).unwrap().data).collect::<Vec<_>>(), vec!["a", "b"]);
+
+        let a = &r_lg.children[0];
+        let a_lg = a.lock_using_parent(LockType::IntentionExclusive, &r_lg).unwrap();
+        let mut a2_lg = a_lg.children[1].lock_exclusive_using_parent(&a_lg).unwrap();
+        a2_lg.data = "a2'";
+
+        assert_eq!(a_lg.children[1].depth(), 2);
+        assert_eq!(a_lg.children[1].kernel.options().poison_policy, PoisonPolicy::FailStop);
+        assert_eq!(a_lg.children[0].kernel.id(), Some(0));
+        assert_eq!(a_lg.children[1].try_lock_shared_using_parent(&a_lg).err(), Some(LockError::LockBusy));
+        assert_eq!(tree.lock_counts().unwrap()[LockType::IntentionExclusive.index()], 1);
+        assert_eq!(r_lg.children[1].lock_using_parent(LockType::Shared, &r_lg).unwrap().children.is_empty(), true);
+
+        drop(a2_lg);
+        assert_eq!(a_lg.children[1].lock_shared_using_parent(&a_lg).unwrap().data, "a2'");
+    }
+
+    #[test]
+    fn abandoned_wait_releases_parent_lock() {
+        let p = Arc::new(GLock::new_root(0u32).unwrap());
+        let c = Arc::new(p.new_child(0u32).unwrap());
+
+        let c_lg = c.lock_exclusive().unwrap();
+
+        // The implicit parent lock is acquired before waiting for the child lock, and must be
+        // released when the wait is given up.
+        let t = {
+            let c = c.clone();
+            std::thread::spawn(move || { c.lock_timeout(LockType::Shared, Duration::from_millis(10)).err() })
+        };
+
+        assert_eq!(t.join().unwrap(), Some(LockError::Timeout));
+        assert_eq!(c.waiters().unwrap(), 0);
+        assert_eq!(p.lock_counts().unwrap()[LockType::IntentionShared.index()], 0);
+        assert_eq!(p.lock_counts().unwrap()[LockType::IntentionExclusive.index()], 1);
+        assert_eq!(p.verify_invariants(), Ok(()));
+        assert_eq!(c.verify_invariants(), Ok(()));
+
+        drop(c_lg);
+        assert_eq!(p.try_lock_exclusive().is_ok(), true);
+    }
+
+    #[test]
+    fn holds_at_least() {
+        let p = GLock::new_root(0u32).unwrap();
+
+        for lock_type in LockType::lock_types().iter() {
+            let p_lg = p.try_lock(*lock_type).unwrap();
+
+            for at_least in LockType::lock_types().iter() {
+                assert_eq!(p_lg.holds_at_least(*at_least), Ok(at_least.upgradable_to(*lock_type)));
             }
         }
+
+        let p_lg = p.lock(LockType::IntentionExclusive).unwrap();
+        assert_eq!(p_lg.holds_at_least(LockType::IntentionShared), Ok(true));
+        assert_eq!(p_lg.holds_at_least(LockType::Shared), Ok(false));
+
+        p_lg.upgrade(LockType::SharedIntentionExclusive).unwrap();
+        assert_eq!(p_lg.holds_at_least(LockType::Shared), Ok(true));
+        assert_eq!(p_lg.holds_at_least(LockType::IntentionExclusive), Ok(true));
+        assert_eq!(p_lg.holds_at_least(LockType::Exclusive), Ok(false));
     }
 
     #[test]
-    fn nested_locks() {
+    fn stats() {
+        let p = Arc::new(GLock::new_root(0u32).unwrap());
+        assert_eq!(p.stats(), Ok(LockStats::default()));
 
-        struct Parent {
-            child1: GLock<u32>,
-            child2: GLock<u32>,
+        // Readers pass a waiting writer.
+        let p_lg1 = p.lock_shared().unwrap();
+
+        let writer = {
+            let p = p.clone();
+            std::thread::spawn(move || { p.lock_exclusive().map(|_| ()) })
         };
 
-        let parent_lock = {
-            let parent_lb = GLock::<Parent>::new_root_builder();
+        while p.waiters().unwrap() == 0 { std::thread::yield_now(); }
 
-            let parent = Parent {
-                child1: parent_lb.new_child(0u32).unwrap(),
-                child2: parent_lb.new_child(0u32).unwrap(),
-            };
+        let p_lg2 = p.lock_shared().unwrap();
+        let p_lg3 = p.try_lock_shared().unwrap();
+        assert_eq!(p.stats().unwrap().starvation_events, 2);
 
-            parent_lb.build(parent).unwrap()
+        std::thread::sleep(Duration::from_millis(10));
+        drop((p_lg1, p_lg2, p_lg3));
+        assert_eq!(writer.join().unwrap(), Ok(()));
+        assert_eq!(p.stats().unwrap().max_wait >= Duration::from_millis(10), true);
+
+        // A high-priority writer is not passed.
+        let p_lg1 = p.lock_shared().unwrap();
+
+        let writer = {
+            let p = p.clone();
+            std::thread::spawn(move || { p.lock_exclusive_with_priority(1).map(|_| ()) })
         };
 
-        for p_type1 in LockType::lock_types() {
-            let p_lg1 = parent_lock.try_lock(*p_type1).unwrap();
+        while p.waiters().unwrap() == 0 { std::thread::yield_now(); }
 
-            for p_type2 in LockType::lock_types() {
-                assert_eq!(parent_lock.try_lock(*p_type2).is_ok(), p_type1.compatible_with(*p_type2));
-            }
+        let other = {
+            let p = p.clone();
+            std::thread::spawn(move || p.try_lock_shared().err()).join().unwrap()
+        };
 
-            for c1_type1 in LockType::lock_types() {
-                let _c1_lg1 = p_lg1.child1.try_lock_using_parent(*c1_type1, &p_lg1).unwrap();
+        assert_eq!(other, Some(LockError::LockBusy));
+        drop(p_lg1);
+        assert_eq!(writer.join().unwrap(), Ok(()));
+        assert_eq!(p.stats().unwrap().starvation_events, 2);
 
-                for c1_type2 in LockType::lock_types() {
-                    assert_eq!(p_lg1.child1.try_lock_using_parent(*c1_type2, &p_lg1).is_ok(), c1_type1.compatible_with(*c1_type2));
-                }
+        // Compatible waiters are not starved.
+        let p_lg1 = p.lock(LockType::IntentionExclusive).unwrap();
+        let reader = {
+            let p = p.clone();
+            std::thread::spawn(move || { p.lock_shared().map(|_| ()) })
+        };
 
-                for c2_type in LockType::lock_types() {
-                    p_lg1.child2.try_lock_using_parent(*c2_type, &p_lg1).unwrap();
-                }
+        while p.waiters().unwrap() == 0 { std::thread::yield_now(); }
+
+        let p_lg2 = p.lock(LockType::IntentionShared).unwrap();
+        assert_eq!(p.stats().unwrap().starvation_events, 2);
+        drop((p_lg1, p_lg2));
+        assert_eq!(reader.join().unwrap(), Ok(()));
+    }
+
+    #[test]
+    fn pinned() {
+        use std::marker::PhantomPinned;
+        use std::ptr;
+
+        struct SelfRef {
+            value: u32,
+            value_ptr: *const u32,
+            _pinned: PhantomPinned,
+        }
+
+        impl SelfRef {
+            fn init(self: Pin<&mut Self>) {
+                let this = unsafe { self.get_unchecked_mut() };
+                this.value_ptr = &this.value;
+            }
+
+            // The pointer is only compared, never dereferenced, so it can outlive the reference it
+            // was created from.
+            fn is_intact(self: Pin<&Self>) -> bool {
+                ptr::eq(&self.value, self.value_ptr)
+            }
+
+            fn set(self: Pin<&mut Self>, value: u32) {
+                unsafe { self.get_unchecked_mut().value = value; }
             }
         }
+
+        let p = GLock::new_root_pinned(SelfRef { value: 1, value_ptr: ptr::null(), _pinned: PhantomPinned }).unwrap();
+        let c = p.new_child_pinned(0u32).unwrap();
+
+        p.lock_exclusive().unwrap().as_pin_mut().init();
+        assert_eq!(p.lock_shared().unwrap().as_pin().is_intact(), true);
+
+        // Moving the `GLock` does not move the pinned data.
+        let p = Box::new(p);
+
+        let mut p_lg = p.lock_exclusive().unwrap();
+        assert_eq!(p_lg.as_pin().is_intact(), true);
+        p_lg.as_pin_mut().set(5);
+        drop(p_lg);
+
+        let p_lg = p.lock_shared().unwrap();
+        assert_eq!((p_lg.as_pin().is_intact(), p_lg.value), (true, 5));
+        drop(p_lg);
+
+        *c.lock_exclusive().unwrap().as_pin_mut() = 3;
+        assert_eq!(*c.lock_shared().unwrap().as_pin(), 3);
     }
 
     #[test]
-    fn upgrade_to_exclusive() {
+    fn try_acquire_additional() {
+        let p = GLock::new_root(0u32).unwrap();
+        let c = Arc::new(p.new_child(0u32).unwrap());
+
+        let c_lg = c.lock(LockType::SharedIntentionExclusive).unwrap();
+        let c_lg2 = c_lg.try_acquire_additional(LockType::IntentionShared).unwrap();
+        assert_eq!(c_lg2.lock_type(), Ok(LockType::IntentionShared));
+        assert_eq!(p.lock_counts().unwrap()[LockType::IntentionExclusive.index()], 1);
+        assert_eq!(p.lock_counts().unwrap()[LockType::IntentionShared.index()], 0);
+
+        for lock_type in [LockType::IntentionExclusive, LockType::Shared, LockType::SharedIntentionExclusive, LockType::Exclusive].iter() {
+            assert_eq!(c_lg.try_acquire_additional(*lock_type).err(), Some(LockError::WouldSelfDeadlock));
+        }
+
+        // The additional lock keeps the shared parent lock alive, relaxed to the type it needs.
+        drop(c_lg);
+        assert_eq!(p.lock_counts().unwrap()[LockType::IntentionShared.index()], 1);
+        assert_eq!(p.lock_counts().unwrap()[LockType::IntentionExclusive.index()], 0);
+        assert_eq!(c.lock_counts().unwrap()[LockType::IntentionShared.index()], 1);
+        drop(c_lg2);
+        assert_eq!(p.lock_counts().unwrap(), LOCK_EMPTY_COUNTS);
+
+        let token = {
+            let c = c.clone();
+            std::thread::spawn(move || { c.lock(LockType::IntentionExclusive).unwrap().into_token() }).join().unwrap()
+        };
+
+        let c_lg = c.lock(LockType::IntentionShared).unwrap();
+        assert_eq!(c_lg.try_acquire_additional(LockType::Shared).err(), Some(LockError::LockBusy));
+        assert_eq!(c_lg.try_acquire_additional(LockType::IntentionExclusive).is_ok(), true);
+        drop(token);
+        assert_eq!(c_lg.try_acquire_additional(LockType::Shared).is_ok(), true);
+
+        // The shared implicit parent lock is escalated for the additional lock, then relaxed.
+        let c_lg2 = c_lg.try_acquire_additional(LockType::IntentionExclusive).unwrap();
+        assert_eq!(p.lock_counts().unwrap()[LockType::IntentionExclusive.index()], 1);
+        assert_eq!(p.lock_counts().unwrap()[LockType::IntentionShared.index()], 0);
+        drop(c_lg2);
+        assert_eq!(p.lock_counts().unwrap()[LockType::IntentionExclusive.index()], 0);
+        assert_eq!(p.lock_counts().unwrap()[LockType::IntentionShared.index()], 1);
+        drop(c_lg);
+
+        let p_lg = p.lock_shared().unwrap();
+        assert_eq!(p_lg.try_acquire_additional(LockType::Shared).map(|lg| *lg), Ok(0));
+    }
 
+    #[test]
+    fn force_reset() {
         let p = GLock::new_root(0u32).unwrap();
+        let c = Arc::new(p.new_child(0u32).unwrap());
 
-        let c = p.new_child(0u32).unwrap();
+        // A lock whose owner is gone (e.g. a forgotten guard) leaves the `GLock` stuck.
+        let stale = c.lock_exclusive().unwrap().into_token();
+        assert_eq!(c.try_lock_shared().err(), Some(LockError::LockBusy));
 
-        let c_g = c.lock(LockType::Shared).unwrap();
+        let waiter = {
+            let c = c.clone();
+            std::thread::spawn(move || c.lock_shared().map(|lg| *lg))
+        };
 
-        assert_eq!(p.try_lock(LockType::Shared).is_ok(), true);
+        while c.waiters().unwrap() == 0 { std::thread::sleep(Duration::from_millis(1)); }
 
-        let mut c_g_mut = c_g.upgrade_to_exclusive().unwrap();
-        *c_g_mut = 10;
+        unsafe { c.force_reset().unwrap(); }
 
-        assert_eq!(p.try_lock(LockType::Shared).is_ok(), false);
+        assert_eq!(waiter.join().unwrap(), Ok(0));
+
+        // The stale lock does not release the locks acquired after the reset.
+        let c_lg = c.try_lock_exclusive().unwrap();
+        drop(stale);
+        assert_eq!(c.lock_counts().unwrap()[LockType::Exclusive.index()], 1);
+        assert_eq!(c.verify_invariants(), Ok(()));
+        drop(c_lg);
+
+        assert_eq!(c.lock_counts().unwrap(), LOCK_EMPTY_COUNTS);
+        assert_eq!(p.lock_counts().unwrap(), LOCK_EMPTY_COUNTS);
+    }
+
+    #[test]
+    fn lock_exclusive_or_timeout_shared() {
+        let l = Arc::new(GLock::new_root(0u32).unwrap());
+
+        match l.lock_exclusive_or_timeout_shared(Duration::from_millis(10)).unwrap() {
+            ExclusiveOrShared::Exclusive(mut lg) => *lg = 1,
+            ExclusiveOrShared::Shared(_) => panic!("expected an exclusive lock"),
+        }
+
+        // A reader holding its lock past the timeout forces the shared fallback.
+        let (locked_tx, locked_rx) = std::sync::mpsc::channel();
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+
+        let reader = {
+            let l = l.clone();
+
+            std::thread::spawn(move || {
+                let _lg = l.lock_shared().unwrap();
+                locked_tx.send(()).unwrap();
+                release_rx.recv().unwrap();
+            })
+        };
+
+        locked_rx.recv().unwrap();
+
+        let lg = l.lock_exclusive_or_timeout_shared(Duration::from_millis(10)).unwrap();
+        assert_eq!(lg.is_exclusive(), false);
+        assert_eq!(*lg, 1);
+        assert_eq!(l.reader_count(), Ok(2));
+
+        release_tx.send(()).unwrap();
+        reader.join().unwrap();
+        drop(lg);
+
+        assert_eq!(l.lock_exclusive_or_timeout_shared(Duration::from_millis(10)).map(|lg| lg.is_exclusive()), Ok(true));
+    }
+
+    #[test]
+    fn lock_exclusive_if_version() {
+        let l = Arc::new(GLock::new_root(0u32).unwrap());
+
+        let version = {
+            let lg = l.lock_shared().unwrap();
+            assert_eq!(*lg, 0);
+            l.write_version().unwrap()
+        };
+
+        // Releasing non-exclusive locks does not change the version.
+        drop(l.lock(LockType::IntentionExclusive).unwrap());
+        assert_eq!(l.write_version(), Ok(version));
+
+        {
+            let mut lg = l.lock_exclusive_if_version(version).unwrap().unwrap();
+            *lg += 1;
+        }
+
+        let version = l.write_version().unwrap();
+
+        // A concurrent write bumps the version.
+        {
+            let l = l.clone();
+            std::thread::spawn(move || { *l.lock_exclusive().unwrap() += 10; }).join().unwrap();
+        }
+
+        assert_eq!(l.lock_exclusive_if_version(version).unwrap().is_none(), true);
+        assert_eq!(l.write_version(), Ok(version + 1));
+        assert_eq!(l.lock_counts().unwrap(), LOCK_EMPTY_COUNTS);
+
+        // Downgrading an `Exclusive` lock counts as a write as well.
+        let version = l.write_version().unwrap();
+        let lg = l.lock_exclusive().unwrap().downgrade_to(LockType::Shared).ok().unwrap();
+        assert_eq!(*lg, 11);
+        drop(lg);
+
+        assert_eq!(l.write_version(), Ok(version + 1));
+        assert_eq!(l.lock_exclusive_if_version(version + 1).unwrap().map(|lg| *lg), Some(11));
+    }
+
+    #[test]
+    fn upgrade_with_lock_moved_to_another_thread() {
+        let l = Arc::new(GLock::new_root(0u32).unwrap());
+
+        let lg = l.lock_shared().unwrap();
+        let token = l.lock_shared().unwrap().into_token();
+
+        let reader = {
+            let l = l.clone();
+
+            std::thread::spawn(move || {
+                let lg = l.from_token(token).ok().unwrap();
+                std::thread::sleep(Duration::from_millis(20));
+                drop(lg);
+            })
+        };
+
+        // The moved lock is no longer attributed to the current thread, so the upgrade waits for
+        // it to be released instead of failing with `WouldSelfDeadlock`.
+        assert_eq!(lg.upgrade(LockType::Exclusive), Ok(()));
+        assert_eq!(l.writer_count(), Ok(1));
+        reader.join().unwrap();
+    }
+
+    #[test]
+    fn wait_idle_with_lock_moved_to_another_thread() {
+        let l = Arc::new(GLock::new_root(0u32).unwrap());
+        let token = l.lock_shared().unwrap().into_token();
+
+        let (token_tx, token_rx) = std::sync::mpsc::channel();
+
+        let holder = {
+            let l = l.clone();
+
+            std::thread::spawn(move || {
+                let lg = l.from_token(token).ok().unwrap();
+                token_tx.send(lg.into_token()).unwrap();
+            })
+        };
+
+        // The lock received back from the other thread is held by the current thread once redeemed.
+        let lg = l.from_token(token_rx.recv().unwrap()).ok().unwrap();
+        holder.join().unwrap();
+        assert_eq!(l.wait_idle().err(), Some(LockError::WouldSelfDeadlock));
+        assert_eq!(l.close_timeout(Duration::from_millis(1)).err(), Some(LockError::WouldSelfDeadlock));
+
+        // The lock moved to another thread is waited for instead.
+        let token = lg.into_token();
+
+        let holder = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            drop(token);
+        });
+
+        assert_eq!(l.wait_idle(), Ok(()));
+        holder.join().unwrap();
     }
 }
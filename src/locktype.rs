@@ -104,6 +104,14 @@ const LOCK_TYPES: [LockType; LOCK_TYPE_COUNT] = [
     LockType::Exclusive,
 ];
 
+const LOCK_TYPES_DESC: [LockType; LOCK_TYPE_COUNT] = [
+    LockType::Exclusive,
+    LockType::SharedIntentionExclusive,
+    LockType::Shared,
+    LockType::IntentionExclusive,
+    LockType::IntentionShared,
+];
+
 const LOCK_TYPE_IMPLICIT_PARENT_TYPE: [LockType; LOCK_TYPE_COUNT] = [
     LockType::IntentionShared,
     LockType::IntentionExclusive,
@@ -140,8 +148,19 @@ impl LockType {
 
     pub fn lock_types() -> &'static [LockType] { &LOCK_TYPES }
 
+    /// Returns all lock types ordered from the least restrictive (`IntentionShared`) to the most
+    /// restrictive (`Exclusive`), i.e. each lock type is only upgradable to lock types that come
+    /// after it. `IntentionExclusive` and `Shared` are not upgradable to each other, so their
+    /// relative order is arbitrary.
+    pub fn by_restrictiveness_asc() -> &'static [LockType] { &LOCK_TYPES }
+
+    /// Returns all lock types ordered from the most restrictive (`Exclusive`) to the least
+    /// restrictive (`IntentionShared`), i.e. the reverse of `by_restrictiveness_asc()`. This is
+    /// useful for finding the strongest lock type satisfying some condition.
+    pub fn by_restrictiveness_desc() -> &'static [LockType] { &LOCK_TYPES_DESC }
+
     /// Returns the numeric index corresponding to this lock type.
-    pub fn index(self) -> usize {
+    pub const fn index(self) -> usize {
         match self {
             LockType::IntentionShared           => 0,
             LockType::IntentionExclusive        => 1,
@@ -154,29 +173,73 @@ impl LockType {
     /// Returns the implicit parent lock type for this lock type. This means that, before acquiring
     /// this type of lock for a child `GLock`, locks of the implicit parent type must be acquired
     /// for all its ancestor `GLock`s.
-    pub fn implicit_parent_type(self) -> LockType { LOCK_TYPE_IMPLICIT_PARENT_TYPE[self.index()] }
+    pub const fn implicit_parent_type(self) -> LockType { LOCK_TYPE_IMPLICIT_PARENT_TYPE[self.index()] }
+
+    /// Returns `true` if acquiring a lock of this type on a child `GLock` requires an
+    /// `IntentionExclusive` lock on its parent (i.e. on each of its ancestors), `false` if an
+    /// `IntentionShared` lock is enough.
+    pub const fn requires_exclusive_ancestry(self) -> bool { matches!(self.implicit_parent_type(), LockType::IntentionExclusive) }
 
     /// Returns `true` if the lock type is compatible with the specified lock type, `false` otherwise.
-    pub fn compatible_with(self, other_type: LockType) -> bool { LOCK_TYPE_COMPATIBLE_WITH[self.index()][other_type.index()] }
+    pub const fn compatible_with(self, other_type: LockType) -> bool { LOCK_TYPE_COMPATIBLE_WITH[self.index()][other_type.index()] }
 
     /// Returns `true` if the lock type is upgradable to the specified lock type, `false` otherwise.
-    pub fn upgradable_to(self, other_type: LockType) -> bool { LOCK_TYPE_UPGRADABLE_TO[self.index()][other_type.index()] }
+    pub const fn upgradable_to(self, other_type: LockType) -> bool { LOCK_TYPE_UPGRADABLE_TO[self.index()][other_type.index()] }
+
+    /// Returns `true` if the lock type is downgradable to the specified lock type, `false` otherwise.
+    /// A lock type is downgradable to any lock type that is upgradable to it.
+    pub const fn downgradable_to(self, other_type: LockType) -> bool { other_type.upgradable_to(self) }
 
     /// Returns `true` if the lock type can support child locks of the specified type, `false` otherwise.
     /// If `true`, this means that if a lock of this type is acquired for a parent `GLock`, a lock
     /// of the specified type can be acquired for a child `GLock`.
-    pub fn supports_children(self, other_type: LockType) -> bool { LOCK_TYPE_SUPPORTS_CHILDREN[self.index()][other_type.index()] }
+    pub const fn supports_children(self, other_type: LockType) -> bool { LOCK_TYPE_SUPPORTS_CHILDREN[self.index()][other_type.index()] }
 
     /// Returns the least restrictive lock type that this lock type can be upgraded to, that is at
     /// least as restrictive as the specified type.
     pub fn min_upgradable(self, other_type: LockType) -> LockType {
-        for lt in LOCK_TYPES.iter() {
+        for lt in LockType::by_restrictiveness_asc().iter() {
             if self.upgradable_to(*lt) && other_type.upgradable_to(*lt) {
                 return *lt;
             }
         }
 
-        return LockType::Exclusive;
+        LockType::Exclusive
+    }
+
+    /// Returns the least restrictive lock type that grants at least what both lock types grant,
+    /// i.e. the effective type of holding both locks at once. For example, `Shared` combined with
+    /// `IntentionExclusive` is `SharedIntentionExclusive`. `Exclusive` is returned when no lesser
+    /// type suffices. This is symmetric, and combining a lock type with itself returns it as is.
+    pub fn combine(self, other_type: LockType) -> LockType { self.min_upgradable(other_type) }
+
+    /// Returns the parent lock type that supports child locks of all the specified types, i.e. the
+    /// `combine()` of their implicit parent types. Acquiring the parent lock at this type before
+    /// locking each child using a `*_using_parent` variant avoids upgrading the parent lock
+    /// midway. If no child lock types are specified, `IntentionShared` is returned.
+    pub fn parent_type_for(children: &[LockType]) -> LockType {
+        children.iter().fold(LockType::IntentionShared, |acc, lt| acc.combine(lt.implicit_parent_type()))
+    }
+
+    /// Returns the direct upgrade transitions between lock types, i.e. the pairs `(from, to)` of
+    /// distinct types where `from` is upgradable to `to`, and no other type sits strictly between
+    /// them. These are the edges of the upgradability lattice, from which `upgradable_to()` can be
+    /// derived by transitivity. The pairs are ordered by restrictiveness of `from`, then of `to`.
+    pub fn upgrade_edges() -> Vec<(LockType, LockType)> {
+        let types = LockType::by_restrictiveness_asc();
+        let strictly_upgradable = |from: LockType, to: LockType| from != to && from.upgradable_to(to);
+
+        let mut edges = Vec::new();
+
+        for from in types.iter() {
+            for to in types.iter() {
+                if strictly_upgradable(*from, *to) && !types.iter().any(|lt| strictly_upgradable(*from, *lt) && strictly_upgradable(*lt, *to)) {
+                    edges.push((*from, *to));
+                }
+            }
+        }
+
+        edges
     }
 }
 
@@ -192,6 +255,18 @@ impl Display for LockType {
     }
 }
 
+/// Describes an automatic upgrade of a parent lock, performed when a child `GLock` is locked using
+/// a parent `GLockGuard` whose type does not support the requested child lock type (e.g. taking an
+/// `Exclusive` child lock using a `Shared` parent lock). See `GLockGuard::parent_upgrade()`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ParentUpgrade {
+    /// The type of the parent lock before the upgrade.
+    pub from: LockType,
+
+    /// The type of the parent lock after the upgrade.
+    pub to: LockType,
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -205,6 +280,15 @@ mod test {
         assert_eq!(LockType::Exclusive.implicit_parent_type(), LockType::IntentionExclusive);
     }
 
+    #[test]
+    fn requires_exclusive_ancestry() {
+        assert_eq!(LockType::IntentionShared.requires_exclusive_ancestry(), false);
+        assert_eq!(LockType::IntentionExclusive.requires_exclusive_ancestry(), true);
+        assert_eq!(LockType::Shared.requires_exclusive_ancestry(), false);
+        assert_eq!(LockType::SharedIntentionExclusive.requires_exclusive_ancestry(), true);
+        assert_eq!(LockType::Exclusive.requires_exclusive_ancestry(), true);
+    }
+
     #[test]
     fn compatible_with() {
         assert_eq!(LockType::IntentionShared.compatible_with(LockType::IntentionShared), true);
@@ -336,4 +420,100 @@ mod test {
         assert_eq!(LockType::Exclusive.min_upgradable(LockType::SharedIntentionExclusive), LockType::Exclusive);
         assert_eq!(LockType::Exclusive.min_upgradable(LockType::Exclusive), LockType::Exclusive);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn const_fn() {
+        const SHARED_COMPATIBLE: bool = LockType::Shared.compatible_with(LockType::IntentionShared);
+        const SHARED_UPGRADABLE: bool = LockType::Shared.upgradable_to(LockType::Exclusive);
+        const SHARED_PARENT: LockType = LockType::Shared.implicit_parent_type();
+        const SHARED_SUPPORTS_EXCLUSIVE: bool = LockType::Shared.supports_children(LockType::Exclusive);
+        const EXCLUSIVE_INDEX: usize = LockType::Exclusive.index();
+
+        assert_eq!(SHARED_COMPATIBLE, true);
+        assert_eq!(SHARED_UPGRADABLE, true);
+        assert_eq!(SHARED_PARENT, LockType::IntentionShared);
+        assert_eq!(SHARED_SUPPORTS_EXCLUSIVE, false);
+        assert_eq!(EXCLUSIVE_INDEX, 4);
+    }
+
+    #[test]
+    fn downgradable_to() {
+        for t1 in LockType::lock_types().iter() {
+            assert_eq!(LockType::Exclusive.downgradable_to(*t1), true);
+
+            for t2 in LockType::lock_types().iter() {
+                assert_eq!(t1.downgradable_to(*t2), t2.upgradable_to(*t1));
+            }
+        }
+
+        assert_eq!(LockType::SharedIntentionExclusive.downgradable_to(LockType::Shared), true);
+        assert_eq!(LockType::Shared.downgradable_to(LockType::IntentionExclusive), false);
+    }
+
+    #[test]
+    fn by_restrictiveness() {
+        assert_eq!(LockType::by_restrictiveness_desc(), &[
+            LockType::Exclusive,
+            LockType::SharedIntentionExclusive,
+            LockType::Shared,
+            LockType::IntentionExclusive,
+            LockType::IntentionShared,
+        ]);
+
+        let mut asc = LockType::by_restrictiveness_asc().to_vec();
+        asc.reverse();
+        assert_eq!(asc, LockType::by_restrictiveness_desc());
+
+        for (i, t1) in LockType::by_restrictiveness_desc().iter().enumerate() {
+            for t2 in LockType::by_restrictiveness_desc()[i + 1..].iter() {
+                assert_eq!(t1.upgradable_to(*t2), false);
+            }
+        }
+    }
+
+    #[test]
+    fn combine() {
+        assert_eq!(LockType::combine(LockType::Shared, LockType::IntentionExclusive), LockType::SharedIntentionExclusive);
+        assert_eq!(LockType::combine(LockType::IntentionShared, LockType::IntentionExclusive), LockType::IntentionExclusive);
+        assert_eq!(LockType::combine(LockType::IntentionShared, LockType::Shared), LockType::Shared);
+        assert_eq!(LockType::combine(LockType::SharedIntentionExclusive, LockType::IntentionExclusive), LockType::SharedIntentionExclusive);
+        assert_eq!(LockType::combine(LockType::Shared, LockType::Exclusive), LockType::Exclusive);
+
+        for t1 in LockType::lock_types().iter() {
+            assert_eq!(t1.combine(*t1), *t1);
+
+            for t2 in LockType::lock_types().iter() {
+                let combined = t1.combine(*t2);
+
+                assert_eq!(combined, t2.combine(*t1));
+                assert_eq!(t1.upgradable_to(combined) && t2.upgradable_to(combined), true);
+            }
+        }
+    }
+
+    #[test]
+    fn parent_type_for() {
+        assert_eq!(LockType::parent_type_for(&[]), LockType::IntentionShared);
+        assert_eq!(LockType::parent_type_for(&[LockType::Shared, LockType::Shared]), LockType::IntentionShared);
+        assert_eq!(LockType::parent_type_for(&[LockType::Exclusive]), LockType::IntentionExclusive);
+        assert_eq!(LockType::parent_type_for(&[LockType::Shared, LockType::Exclusive, LockType::Shared]), LockType::IntentionExclusive);
+
+        for t1 in LockType::lock_types().iter() {
+            for t2 in LockType::lock_types().iter() {
+                let parent_type = LockType::parent_type_for(&[*t1, *t2]);
+                assert_eq!(parent_type.supports_children(*t1) && parent_type.supports_children(*t2), true);
+            }
+        }
+    }
+
+    #[test]
+    fn upgrade_edges() {
+        assert_eq!(LockType::upgrade_edges(), vec![
+            (LockType::IntentionShared, LockType::IntentionExclusive),
+            (LockType::IntentionShared, LockType::Shared),
+            (LockType::IntentionExclusive, LockType::SharedIntentionExclusive),
+            (LockType::Shared, LockType::SharedIntentionExclusive),
+            (LockType::SharedIntentionExclusive, LockType::Exclusive),
+        ]);
+    }
+}
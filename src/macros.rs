@@ -0,0 +1,38 @@
+/// Splits a `GLockGuardMut` protecting a `struct` into mutable references to some of its fields,
+/// which are guaranteed to be disjoint. Naming the same field twice is rejected by the compiler.
+/// The `GLockGuardMut` cannot be used while the returned references are alive.
+///
+/// # Example
+///
+/// ```
+/// #[macro_use]
+/// extern crate glock;
+///
+/// use glock::GLock;
+///
+/// struct Point {
+///     x: u32,
+///     y: u32,
+/// }
+///
+/// fn main() {
+///     let lock = GLock::new_root(Point { x: 0, y: 0 }).unwrap();
+///     let mut guard = lock.lock_exclusive().unwrap();
+///
+///     {
+///         let (x, y) = split_fields_mut!(guard, x, y);
+///         *x = 10;
+///         *y = 20;
+///     }
+///
+///     assert_eq!(guard.x, 10);
+///     assert_eq!(guard.y, 20);
+/// }
+/// ```
+#[macro_export]
+macro_rules! split_fields_mut {
+    ($guard:expr, $($field:ident),+) => {{
+        let data = &mut *$guard;
+        ($(&mut data.$field),+)
+    }};
+}
@@ -0,0 +1,52 @@
+// Synchronization primitives used by the lock kernel. When built with `--cfg loom`, the ones that
+// take part in the interleavings of threads are replaced with their `loom` equivalents, so that the
+// kernel can be model checked (see the `loom_test` module of the kernel). `Arc` and `Weak` are kept
+// from `std`, since `loom` does not provide `Weak`.
+
+use std::sync::{ PoisonError, TryLockError };
+
+#[cfg(not(loom))]
+pub use std::sync::{ Mutex, MutexGuard, Condvar };
+
+#[cfg(not(loom))]
+pub use std::thread::{ self, ThreadId };
+
+#[cfg(loom)]
+pub use loom::sync::{ Mutex, MutexGuard, Condvar };
+
+#[cfg(loom)]
+pub use loom::thread::{ self, ThreadId };
+
+// The mutex protecting the internal state of the lock kernel and its lock instances. Unlike
+// `Mutex`, it never reports poisoning: the kernel never panics halfway through an update of its
+// state, so the state is still consistent after a thread panicked while holding the mutex (e.g. in
+// a `tracing` subscriber), and locking it cannot fail. Its guards are plain `MutexGuard`s, so that
+// they can still be passed to a `Condvar`.
+#[derive(Debug)]
+pub struct StateMutex<T> {
+    mutex: Mutex<T>,
+}
+
+impl<T> StateMutex<T> {
+
+    pub fn new(value: T) -> StateMutex<T> {
+        StateMutex { mutex: Mutex::new(value) }
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        self.mutex.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Returns `None` if the mutex is held, by any thread.
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        match self.mutex.try_lock() {
+            Ok(guard)                           => Some(guard),
+            Err(TryLockError::Poisoned(err))    => Some(err.into_inner()),
+            Err(TryLockError::WouldBlock)       => None,
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.mutex.get_mut().unwrap_or_else(PoisonError::into_inner)
+    }
+}
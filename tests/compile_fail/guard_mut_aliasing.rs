@@ -0,0 +1,14 @@
+extern crate glock;
+
+use glock::*;
+
+fn main() {
+    let lock = GLock::new_root(0u32).unwrap();
+    let mut guard = lock.lock_exclusive().unwrap();
+
+    let a: &mut u32 = &mut guard;
+    let b: &mut u32 = &mut guard;
+
+    *a += 1;
+    *b += 1;
+}
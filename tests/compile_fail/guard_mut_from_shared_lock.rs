@@ -0,0 +1,11 @@
+extern crate glock;
+
+use glock::*;
+
+fn main() {
+    let lock = GLock::new_root(0u32).unwrap();
+
+    // Only the `lock_exclusive` variants return a `GLockGuardMut`.
+    let guard: GLockGuardMut<u32> = lock.lock_shared().unwrap();
+    drop(guard);
+}
@@ -0,0 +1,11 @@
+extern crate glock;
+
+use glock::*;
+use std::thread;
+
+fn main() {
+    let lock: &'static GLock<u32> = Box::leak(Box::new(GLock::new_root(0u32).unwrap()));
+    let guard = lock.lock_shared().unwrap();
+
+    thread::spawn(move || println!("{}", *guard)).join().unwrap();
+}
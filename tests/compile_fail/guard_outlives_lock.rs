@@ -0,0 +1,12 @@
+extern crate glock;
+
+use glock::*;
+
+fn main() {
+    let guard = {
+        let lock = GLock::new_root(0u32).unwrap();
+        lock.lock_shared().unwrap()
+    };
+
+    println!("{}", *guard);
+}
@@ -0,0 +1,11 @@
+extern crate glock;
+
+use glock::*;
+
+fn main() {
+    let lock = GLock::new_root(0u32).unwrap();
+    let guard = lock.lock_shared().unwrap();
+
+    let data = lock.into_inner();
+    println!("{} {}", data, *guard);
+}
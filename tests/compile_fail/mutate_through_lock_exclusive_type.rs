@@ -0,0 +1,12 @@
+extern crate glock;
+
+use glock::*;
+
+fn main() {
+    let lock = GLock::new_root(0u32).unwrap();
+
+    // `lock()` returns a `GLockGuard` even for `Exclusive` locks, which only allows reading.
+    let guard = lock.lock(LockType::Exclusive).unwrap();
+
+    *guard = 1;
+}
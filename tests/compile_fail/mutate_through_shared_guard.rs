@@ -0,0 +1,10 @@
+extern crate glock;
+
+use glock::*;
+
+fn main() {
+    let lock = GLock::new_root(0u32).unwrap();
+    let guard = lock.lock_shared().unwrap();
+
+    *guard = 1;
+}
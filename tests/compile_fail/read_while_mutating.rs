@@ -0,0 +1,13 @@
+extern crate glock;
+
+use glock::*;
+
+fn main() {
+    let lock = GLock::new_root(0u32).unwrap();
+    let mut guard = lock.lock_exclusive().unwrap();
+
+    let shared = guard.reborrow_shared();
+    *guard += 1;
+
+    println!("{}", *shared);
+}
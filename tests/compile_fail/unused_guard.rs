@@ -0,0 +1,13 @@
+#![deny(unused_must_use)]
+
+extern crate glock;
+
+use glock::*;
+
+fn main() {
+    let lock = GLock::new_root(0u32).unwrap();
+
+    // The guard is dropped right away, releasing the lock before it is ever used.
+    lock.lock_exclusive().unwrap();
+    lock.lock_shared();
+}